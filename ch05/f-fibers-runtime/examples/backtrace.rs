@@ -0,0 +1,40 @@
+use std::backtrace::Backtrace;
+
+use f_fibers_runtime::{yield_thread, Runtime};
+
+fn nested(depth: usize) {
+    if depth == 0 {
+        println!("{}", Backtrace::force_capture());
+    } else {
+        nested(depth - 1);
+    }
+}
+
+#[cfg(not(windows))]
+fn main() {
+    let mut runtime = Runtime::new();
+    runtime.init();
+
+    Runtime::spawn(|| {
+        yield_thread();
+        // The backtrace should end at the fiber's entry point instead of
+        // walking off the top of the fiber's stack
+        nested(3);
+    });
+
+    Runtime::spawn(|| {
+        Runtime::dump();
+        yield_thread();
+        panic!("a panic only ends the fiber it happens in");
+    });
+
+    Runtime::spawn(|| {
+        yield_thread();
+        println!("...and the other fibers keep running.");
+    });
+
+    runtime.run();
+}
+
+#[cfg(windows)]
+fn main() {}
@@ -0,0 +1,13 @@
+//! The code that actually swaps the stacks. By default we use the hand
+//! written x86-64 assembly from the book, but enabling the `ucontext`
+//! feature swaps it for `getcontext`/`makecontext`/`swapcontext` from libc
+//! without changing anything else in the runtime.
+#[cfg(not(feature = "ucontext"))]
+mod asm;
+#[cfg(not(feature = "ucontext"))]
+pub(crate) use asm::{swap, ThreadContext};
+
+#[cfg(feature = "ucontext")]
+mod ucontext;
+#[cfg(feature = "ucontext")]
+pub(crate) use ucontext::{swap, ThreadContext};
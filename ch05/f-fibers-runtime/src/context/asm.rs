@@ -0,0 +1,102 @@
+use std::arch::{asm, naked_asm};
+
+use crate::{call, guard, Thread};
+
+#[derive(Debug, Default)]
+#[repr(C)]
+pub(crate) struct ThreadContext {
+    rsp: u64,
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbx: u64,
+    rbp: u64,
+    thread_ptr: u64,
+}
+
+impl ThreadContext {
+    /// Prepares the stack so that the first switch to this context returns
+    /// into `trampoline`, which calls `call(thread)` and then `guard`.
+    ///
+    /// In the book we wrote `call`, `skip` and `guard` directly to the stack
+    /// instead. That works, but an unwinder has no way of knowing that those
+    /// addresses aren't return addresses from real calls, so backtraces taken
+    /// inside a fiber walk right off the top of its stack.
+    pub(crate) fn prepare(&mut self, stack: &mut [u8], thread: *mut Thread) {
+        unsafe {
+            let s_ptr = stack.as_mut_ptr().add(stack.len());
+            let s_ptr = (s_ptr as usize & !15) as *mut u8;
+            // `ret` pops this, so we enter `trampoline` with `rsp` at
+            // `s_ptr - 16`, which is 16 byte aligned. That's not how a called
+            // function starts (the return address leaves `rsp` 8 bytes off),
+            // but `trampoline` pushes nothing before its own `call`s, and the
+            // ABI wants `rsp` 16 byte aligned at every `call`.
+            std::ptr::write(s_ptr.offset(-24) as *mut u64, trampoline as *const () as u64);
+            self.rsp = s_ptr.offset(-24) as u64;
+        }
+        self.thread_ptr = thread as u64;
+    }
+
+    pub(crate) fn stack_pointer(&self) -> u64 {
+        self.rsp
+    }
+}
+
+/// Saves the current CPU state in `old` and continues running from `new`.
+pub(crate) unsafe fn swap(old: *mut ThreadContext, new: *const ThreadContext) {
+    asm!("call switch", in("rdi") old, in("rsi") new, clobber_abi("C"));
+}
+
+/// The first function that runs on a new fiber's stack. `switch` loads the
+/// pointer to the `Thread` into `rdi` so it's passed on as the argument to
+/// `call`.
+///
+/// `.cfi_undefined rip` tells the unwinder that this frame has no return
+/// address, which is how the outermost frame of a stack is marked (it's the
+/// same thing glibc does in the function that starts every OS thread). A
+/// backtrace captured inside a fiber will end cleanly here.
+#[unsafe(naked)]
+unsafe extern "C" fn trampoline() {
+    naked_asm!(
+        ".cfi_startproc",
+        ".cfi_undefined rip",
+        "call {call}",
+        "call {guard}",
+        // `guard` never returns since a finished fiber is never resumed
+        "ud2",
+        ".cfi_endproc",
+        call = sym call,
+        guard = sym guard,
+    )
+}
+
+#[unsafe(naked)]
+#[no_mangle]
+#[cfg_attr(target_os = "macos", export_name = "\x01switch")]
+unsafe extern "C" fn switch() {
+    // Naked functions get no unwind info unless we write it ourselves. The
+    // default rule (the return address is at `rsp`) holds for every
+    // instruction in `switch`, since we only ever swap one stack that has a
+    // return address on top for another one.
+    naked_asm!(
+        ".cfi_startproc",
+        "mov 0x00[rdi], rsp",
+        "mov 0x08[rdi], r15",
+        "mov 0x10[rdi], r14",
+        "mov 0x18[rdi], r13",
+        "mov 0x20[rdi], r12",
+        "mov 0x28[rdi], rbx",
+        "mov 0x30[rdi], rbp",
+        "mov rsp, 0x00[rsi]",
+        "mov r15, 0x08[rsi]",
+        "mov r14, 0x10[rsi]",
+        "mov r13, 0x18[rsi]",
+        "mov r12, 0x20[rsi]",
+        "mov rbx, 0x28[rsi]",
+        "mov rbp, 0x30[rsi]",
+        "mov rdi, 0x38[rsi]",
+        "ret",
+        ".cfi_endproc",
+    );
+}
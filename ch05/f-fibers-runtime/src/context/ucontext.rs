@@ -0,0 +1,72 @@
+use std::mem;
+
+use crate::{call, guard, Thread};
+
+/// `ucontext_t` stores a pointer into itself (to the saved floating point
+/// state), so we keep it on the heap where it won't move after
+/// `getcontext` has filled it in.
+pub(crate) struct ThreadContext {
+    uc: Box<libc::ucontext_t>,
+}
+
+impl Default for ThreadContext {
+    fn default() -> Self {
+        Self {
+            uc: Box::new(unsafe { mem::zeroed() }),
+        }
+    }
+}
+
+impl ThreadContext {
+    /// Prepares the context so that the first switch to it runs `entry`
+    /// on the fiber's stack.
+    pub(crate) fn prepare(&mut self, stack: &mut [u8], thread: *mut Thread) {
+        let thread = thread as u64;
+        unsafe {
+            if libc::getcontext(&mut *self.uc) != 0 {
+                panic!("getcontext failed: {}", std::io::Error::last_os_error());
+            }
+            self.uc.uc_stack.ss_sp = stack.as_mut_ptr().cast();
+            self.uc.uc_stack.ss_size = stack.len();
+            self.uc.uc_link = std::ptr::null_mut();
+            // The arguments to `makecontext` are `int`s, so we pass the
+            // pointer to the thread in two halves.
+            let entry: extern "C" fn(u32, u32) = entry;
+            libc::makecontext(
+                &mut *self.uc,
+                mem::transmute::<extern "C" fn(u32, u32), extern "C" fn()>(entry),
+                2,
+                (thread >> 32) as u32,
+                thread as u32,
+            );
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn stack_pointer(&self) -> u64 {
+        self.uc.uc_mcontext.gregs[libc::REG_RSP as usize] as u64
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub(crate) fn stack_pointer(&self) -> u64 {
+        self.uc.uc_mcontext.sp
+    }
+}
+
+/// Saves the current CPU state in `old` and continues running from `new`.
+pub(crate) unsafe fn swap(old: *mut ThreadContext, new: *const ThreadContext) {
+    if libc::swapcontext(&mut *(*old).uc, &*(*new).uc) != 0 {
+        panic!("swapcontext failed: {}", std::io::Error::last_os_error());
+    }
+}
+
+/// Does the same job as `trampoline` in the assembly version. `guard` never
+/// returns since a finished fiber is never switched to again.
+///
+/// We don't need to add any unwind info ourselves here. `makecontext` sets
+/// things up so `entry` is called from a function in glibc that already
+/// marks the end of the stack.
+extern "C" fn entry(hi: u32, lo: u32) {
+    call((hi as u64) << 32 | lo as u64);
+    guard();
+}
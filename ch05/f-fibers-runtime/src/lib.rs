@@ -0,0 +1,277 @@
+//! This is an extended version of the runtime in `d-fibers-closure` that
+//! we use to experiment with how fibers behave. It's the same stack swapping
+//! runtime, but instead of scanning every thread looking for one that's
+//! `Ready`, the runtime asks a [`Scheduler`] which fiber should run next.
+//! It can also record every scheduler event in a [`Tracer`].
+use std::{
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+};
+
+use context::ThreadContext;
+
+mod context;
+mod scheduler;
+mod trace;
+
+pub use scheduler::{
+    FifoScheduler, LifoScheduler, Priority, PriorityScheduler, Scheduler, DEFAULT_PRIORITY,
+    PRIORITY_LEVELS,
+};
+pub use trace::{Event, EventKind, Tracer};
+
+const DEFAULT_STACK_SIZE: usize = 1024 * 1024 * 2;
+const MAX_THREADS: usize = 8;
+static mut RUNTIME: usize = 0;
+
+pub struct Runtime {
+    threads: Vec<Thread>,
+    current: usize,
+    scheduler: Box<dyn Scheduler>,
+    tracer: Option<Tracer>,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+enum State {
+    Available,
+    Running,
+    Ready,
+}
+
+struct Thread {
+    id: usize,
+    stack: Vec<u8>,
+    ctx: ThreadContext,
+    state: State,
+    priority: Priority,
+    task: Option<Box<dyn FnOnce()>>,
+}
+
+impl Thread {
+    fn new(id: usize, stack_size: usize) -> Self {
+        Thread {
+            id,
+            stack: vec![0_u8; stack_size],
+            ctx: ThreadContext::default(),
+            state: State::Available,
+            priority: DEFAULT_PRIORITY,
+            task: None,
+        }
+    }
+}
+
+impl Runtime {
+    /// Creates a runtime that runs fibers in FIFO order.
+    pub fn new() -> Self {
+        Self::with_scheduler(FifoScheduler::default())
+    }
+
+    pub fn with_scheduler(scheduler: impl Scheduler + 'static) -> Self {
+        Self::with_config(scheduler, MAX_THREADS, DEFAULT_STACK_SIZE)
+    }
+
+    /// Creates a runtime with room for `max_threads` threads (including the
+    /// base thread) that each get a stack of `stack_size` bytes.
+    pub fn with_config(
+        scheduler: impl Scheduler + 'static,
+        max_threads: usize,
+        stack_size: usize,
+    ) -> Self {
+        let base_thread = Thread {
+            id: 0,
+            stack: vec![0_u8; stack_size],
+            ctx: ThreadContext::default(),
+            state: State::Running,
+            priority: DEFAULT_PRIORITY,
+            task: None,
+        };
+
+        let mut threads = vec![base_thread];
+        let mut available_threads: Vec<Thread> = (1..max_threads)
+            .map(|i| Thread::new(i, stack_size))
+            .collect();
+        threads.append(&mut available_threads);
+
+        Runtime {
+            threads,
+            current: 0,
+            scheduler: Box::new(scheduler),
+            tracer: None,
+        }
+    }
+
+    pub fn init(&self) {
+        unsafe {
+            let r_ptr: *const Runtime = self;
+            RUNTIME = r_ptr as usize;
+        }
+    }
+
+    /// Records every spawn, yield, context switch and exit from now on and
+    /// writes them as a Chrome trace event file to `path` when `run` finishes.
+    pub fn enable_tracing(&mut self, path: impl Into<PathBuf>) {
+        let mut tracer = Tracer::new(path.into());
+        tracer.record(EventKind::Resume, self.current);
+        self.tracer = Some(tracer);
+    }
+
+    /// The events recorded since `enable_tracing`, if it was called.
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.tracer.as_ref()
+    }
+
+    /// Runs until all fibers have finished. Unlike in the book we return
+    /// instead of exiting the process, so we can run the runtime more than
+    /// once in the same program (like we do in the benchmarks).
+    pub fn run(&mut self) {
+        while self.t_yield() {}
+
+        self.trace(EventKind::Suspend, 0);
+        if let Some(tracer) = &self.tracer {
+            if let Err(e) = tracer.save() {
+                eprintln!("Failed to write trace: {e}");
+            }
+        }
+    }
+
+    fn trace(&mut self, kind: EventKind, fiber: usize) {
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.record(kind, fiber);
+        }
+    }
+
+    fn t_return(&mut self) {
+        if self.current != 0 {
+            self.trace(EventKind::Exit, self.current);
+            self.threads[self.current].state = State::Available;
+            self.t_yield();
+        }
+    }
+
+    /// The base thread never enters the ready queue. It only gets the CPU
+    /// back when a fiber finishes and there is nothing else ready to run,
+    /// and when it yields with an empty queue we know all work is done.
+    #[inline(never)]
+    fn t_yield(&mut self) -> bool {
+        let current = self.current;
+        if self.threads[current].state == State::Running {
+            self.trace(EventKind::Yield, current);
+        }
+
+        if current != 0 && self.threads[current].state == State::Running {
+            self.threads[current].state = State::Ready;
+            let priority = self.threads[current].priority;
+            self.scheduler.push(current, priority);
+        }
+
+        let pos = match self.scheduler.pop() {
+            Some(pos) => pos,
+            None if current != 0 => 0,
+            None => return false,
+        };
+
+        if pos == current {
+            self.threads[pos].state = State::Running;
+            return true;
+        }
+
+        if self.threads[current].state == State::Running {
+            self.threads[current].state = State::Ready;
+        }
+
+        self.threads[pos].state = State::Running;
+        self.current = pos;
+        self.trace(EventKind::Suspend, current);
+        self.trace(EventKind::Resume, pos);
+
+        unsafe {
+            let old: *mut ThreadContext = &mut self.threads[current].ctx;
+            let new: *const ThreadContext = &self.threads[pos].ctx;
+            context::swap(old, new);
+        }
+        true
+    }
+
+    /// Prints the id, state and saved stack pointer of every fiber that's
+    /// in use. The running fiber has no saved stack pointer (the one in its
+    /// context is stale until the next switch), so we skip that one.
+    pub fn dump() {
+        let rt = unsafe { &*(RUNTIME as *const Runtime) };
+        println!("{:<4} {:<8} {:>18} {:>12}", "ID", "STATE", "SAVED SP", "STACK USED");
+        for t in rt.threads.iter().filter(|t| t.state != State::Available) {
+            let state = format!("{:?}", t.state);
+            if t.state == State::Running {
+                println!("{:<4} {:<8} {:>18} {:>12}", t.id, state, "-", "-");
+                continue;
+            }
+
+            let sp = t.ctx.stack_pointer();
+            // The base thread runs on the stack the OS gave us, not on `stack`
+            let used = if t.id == 0 {
+                String::from("-")
+            } else {
+                let top = t.stack.as_ptr() as u64 + t.stack.len() as u64;
+                format!("{} B", top - sp)
+            };
+            println!("{:<4} {:<8} {:>#18x} {:>12}", t.id, state, sp, used);
+        }
+    }
+
+    /// Spawns a fiber with `DEFAULT_PRIORITY`.
+    pub fn spawn<F: FnOnce() + 'static>(f: F) {
+        Self::spawn_with_priority(DEFAULT_PRIORITY, f);
+    }
+
+    /// Spawns a fiber with the given priority. The priority is only used by
+    /// schedulers that care about it, like [`PriorityScheduler`].
+    pub fn spawn_with_priority<F: FnOnce() + 'static>(priority: Priority, f: F) {
+        unsafe {
+            let rt_ptr = RUNTIME as *mut Runtime;
+            let rt = &mut *rt_ptr;
+            let available = rt
+                .threads
+                .iter_mut()
+                .find(|t| t.state == State::Available)
+                .expect("no available thread.");
+
+            available.task = Some(Box::new(f));
+            let thread_ptr: *mut Thread = available;
+            available.ctx.prepare(&mut available.stack, thread_ptr);
+            available.state = State::Ready;
+            available.priority = priority;
+            let id = available.id;
+            rt.scheduler.push(id, priority);
+            rt.trace(EventKind::Spawn, id);
+        }
+    }
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+extern "C" fn call(thread: u64) {
+    let thread = unsafe { &mut *(thread as *mut Thread) };
+    if let Some(f) = thread.task.take() {
+        // A panic can't unwind past the start of the fiber's stack, so we
+        // stop it here. The panic hook has already printed the message and
+        // the fiber finishes just as if it returned.
+        let _ = panic::catch_unwind(AssertUnwindSafe(f));
+    }
+}
+
+extern "C" fn guard() {
+    unsafe {
+        let rt_ptr = RUNTIME as *mut Runtime;
+        (*rt_ptr).t_return();
+    };
+}
+
+pub fn yield_thread() {
+    unsafe {
+        let rt_ptr = RUNTIME as *mut Runtime;
+        (*rt_ptr).t_yield();
+    };
+}
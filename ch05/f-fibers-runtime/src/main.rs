@@ -0,0 +1,45 @@
+use std::env;
+
+use f_fibers_runtime::{
+    yield_thread, FifoScheduler, LifoScheduler, PriorityScheduler, Runtime, DEFAULT_PRIORITY,
+};
+
+fn work(name: &'static str, count: usize) {
+    println!("{name} STARTING");
+    for i in 0..count {
+        println!("{name} counter: {i}");
+        yield_thread();
+    }
+    println!("{name} FINISHED");
+}
+
+#[cfg(not(windows))]
+fn main() {
+    let policy = env::args().nth(1).unwrap_or_else(|| String::from("fifo"));
+    let mut runtime = match policy.as_str() {
+        "fifo" => Runtime::with_scheduler(FifoScheduler::default()),
+        "lifo" => Runtime::with_scheduler(LifoScheduler::default()),
+        "priority" => Runtime::with_scheduler(PriorityScheduler::default()),
+        other => {
+            println!("Unknown scheduler `{other}`. Use one of: fifo, lifo, priority.");
+            return;
+        }
+    };
+    if let Some(path) = env::args().nth(2) {
+        runtime.enable_tracing(path);
+    }
+    runtime.init();
+
+    Runtime::spawn_with_priority(DEFAULT_PRIORITY - 1, || work("LOW", 3));
+    Runtime::spawn(|| work("NORMAL", 3));
+    Runtime::spawn_with_priority(DEFAULT_PRIORITY + 1, || {
+        work("HIGH", 2);
+        // Fibers spawned from a fiber are scheduled like any other
+        Runtime::spawn_with_priority(DEFAULT_PRIORITY + 2, || work("NESTED", 2));
+    });
+
+    runtime.run();
+}
+
+#[cfg(windows)]
+fn main() {}
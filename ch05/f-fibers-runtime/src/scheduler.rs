@@ -0,0 +1,89 @@
+use std::collections::VecDeque;
+
+/// The priority of a fiber. Higher values run first when using the
+/// [`PriorityScheduler`]. Values above `PRIORITY_LEVELS - 1` are treated
+/// as the highest priority.
+pub type Priority = u8;
+
+pub const PRIORITY_LEVELS: usize = 8;
+pub const DEFAULT_PRIORITY: Priority = 3;
+
+/// A scheduler decides in which order fibers that are ready to run get
+/// the CPU. The runtime pushes a fiber every time it's spawned or yields,
+/// and pops the next one every time the current fiber yields or finishes.
+///
+/// Both operations should be O(1) since they're called on every context
+/// switch.
+pub trait Scheduler {
+    fn push(&mut self, id: usize, priority: Priority);
+    fn pop(&mut self) -> Option<usize>;
+}
+
+/// Runs fibers in the order they became ready. This gives the same
+/// round-robin behavior as the original `t_yield` in `c-fibers`.
+#[derive(Default)]
+pub struct FifoScheduler {
+    queue: VecDeque<usize>,
+}
+
+impl Scheduler for FifoScheduler {
+    fn push(&mut self, id: usize, _priority: Priority) {
+        self.queue.push_back(id);
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        self.queue.pop_front()
+    }
+}
+
+/// Runs the fiber that became ready most recently first. A fiber that
+/// yields is picked again right away unless another fiber was spawned in
+/// the meantime, so this one is mostly interesting to see how badly it
+/// can starve the others.
+#[derive(Default)]
+pub struct LifoScheduler {
+    stack: Vec<usize>,
+}
+
+impl Scheduler for LifoScheduler {
+    fn push(&mut self, id: usize, _priority: Priority) {
+        self.stack.push(id);
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        self.stack.pop()
+    }
+}
+
+/// Always runs a fiber with the highest priority that's ready. Fibers with
+/// the same priority are run round-robin.
+///
+/// We keep one FIFO queue per priority level and a bitmap where bit `n`
+/// is set if the queue for priority `n` is non-empty, so finding the
+/// highest non-empty queue is a single `leading_zeros` instruction.
+#[derive(Default)]
+pub struct PriorityScheduler {
+    queues: [VecDeque<usize>; PRIORITY_LEVELS],
+    non_empty: u8,
+}
+
+impl Scheduler for PriorityScheduler {
+    fn push(&mut self, id: usize, priority: Priority) {
+        let level = (priority as usize).min(PRIORITY_LEVELS - 1);
+        self.queues[level].push_back(id);
+        self.non_empty |= 1 << level;
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        if self.non_empty == 0 {
+            return None;
+        }
+
+        let level = u8::BITS as usize - 1 - self.non_empty.leading_zeros() as usize;
+        let id = self.queues[level].pop_front();
+        if self.queues[level].is_empty() {
+            self.non_empty &= !(1 << level);
+        }
+        id
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+/// The scheduler events we record. `Resume` and `Suspend` always come in
+/// pairs around the time a fiber holds the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Spawn,
+    Yield,
+    Suspend,
+    Resume,
+    Exit,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    pub kind: EventKind,
+    pub fiber: usize,
+    pub at: Duration,
+}
+
+/// Records timestamped scheduler events for every fiber and writes them
+/// in the Chrome trace event format. Open the file in `chrome://tracing`
+/// or <https://ui.perfetto.dev> to see a timeline with one track per fiber.
+pub struct Tracer {
+    start: Instant,
+    events: Vec<Event>,
+    path: PathBuf,
+}
+
+impl Tracer {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Self {
+            start: Instant::now(),
+            events: vec![],
+            path,
+        }
+    }
+
+    pub(crate) fn record(&mut self, kind: EventKind, fiber: usize) {
+        self.events.push(Event {
+            kind,
+            fiber,
+            at: self.start.elapsed(),
+        });
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Writes the trace to the path given to `Runtime::enable_tracing`.
+    pub(crate) fn save(&self) -> io::Result<()> {
+        let file = fs::File::create(&self.path)?;
+        self.write_chrome_trace(io::BufWriter::new(file))
+    }
+
+    pub fn write_chrome_trace(&self, mut w: impl Write) -> io::Result<()> {
+        let mut fibers: Vec<usize> = self.events.iter().map(|e| e.fiber).collect();
+        fibers.sort_unstable();
+        fibers.dedup();
+
+        let mut entries = vec![];
+        for fiber in fibers {
+            let name = if fiber == 0 {
+                String::from("base thread")
+            } else {
+                format!("fiber {fiber}")
+            };
+            entries.push(format!(
+                r#"{{"name":"thread_name","ph":"M","pid":1,"tid":{fiber},"args":{{"name":"{name}"}}}}"#
+            ));
+        }
+
+        for event in &self.events {
+            let ts = event.at.as_nanos() as f64 / 1000.0;
+            let fiber = event.fiber;
+            let mut entry = String::new();
+            // `B`/`E` draws a slice for the time the fiber held the CPU while
+            // the rest are drawn as instant events on the fiber's track.
+            let (name, ph) = match event.kind {
+                EventKind::Resume => ("running", "B"),
+                EventKind::Suspend => ("running", "E"),
+                EventKind::Spawn => ("spawn", "i"),
+                EventKind::Yield => ("yield", "i"),
+                EventKind::Exit => ("exit", "i"),
+            };
+            write!(
+                entry,
+                r#"{{"name":"{name}","cat":"scheduler","ph":"{ph}","ts":{ts:.3},"pid":1,"tid":{fiber}"#
+            )
+            .unwrap();
+            if ph == "i" {
+                entry.push_str(r#","s":"t""#);
+            }
+            entry.push('}');
+            entries.push(entry);
+        }
+
+        writeln!(w, "{{\"traceEvents\":[")?;
+        writeln!(w, "{}", entries.join(",\n"))?;
+        writeln!(w, "]}}")?;
+        w.flush()
+    }
+}
@@ -0,0 +1,35 @@
+use f_fibers_runtime::{FifoScheduler, LifoScheduler, PriorityScheduler, Scheduler};
+
+fn drain(scheduler: &mut impl Scheduler) -> Vec<usize> {
+    std::iter::from_fn(|| scheduler.pop()).collect()
+}
+
+#[test]
+fn fifo_runs_in_arrival_order() {
+    let mut s = FifoScheduler::default();
+    for id in 1..4 {
+        s.push(id, 0);
+    }
+    assert_eq!(drain(&mut s), vec![1, 2, 3]);
+}
+
+#[test]
+fn lifo_runs_latest_first() {
+    let mut s = LifoScheduler::default();
+    for id in 1..4 {
+        s.push(id, 0);
+    }
+    assert_eq!(drain(&mut s), vec![3, 2, 1]);
+}
+
+#[test]
+fn priority_runs_highest_first_and_round_robin_within_a_level() {
+    let mut s = PriorityScheduler::default();
+    s.push(1, 1);
+    s.push(2, 5);
+    s.push(3, 1);
+    s.push(4, 5);
+    // Out of range priorities are clamped to the highest level
+    s.push(5, 200);
+    assert_eq!(drain(&mut s), vec![5, 2, 4, 1, 3]);
+}
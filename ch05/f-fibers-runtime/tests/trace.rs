@@ -0,0 +1,62 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use f_fibers_runtime::{yield_thread, EventKind, Runtime};
+use serde_json::Value;
+
+#[cfg(not(windows))]
+#[test]
+fn writes_a_chrome_trace_with_a_running_slice_per_switch() {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("trace.json");
+    let mut runtime = Runtime::new();
+    runtime.enable_tracing(&path);
+    runtime.init();
+    for count in 1..4 {
+        Runtime::spawn(move || {
+            for _ in 0..count {
+                yield_thread();
+            }
+        });
+    }
+    runtime.run();
+
+    let events = runtime.tracer().unwrap().events();
+    let count = |kind| events.iter().filter(|e| e.kind == kind).count();
+    assert_eq!(count(EventKind::Spawn), 3);
+    assert_eq!(count(EventKind::Exit), 3);
+    assert_eq!(count(EventKind::Resume), count(EventKind::Suspend));
+
+    let mut written = vec![];
+    runtime.tracer().unwrap().write_chrome_trace(&mut written).unwrap();
+    let saved = fs::read(&path).unwrap();
+    assert_eq!(saved, written, "`run` saved something else");
+
+    let trace: Value = serde_json::from_slice(&saved).unwrap();
+    let entries = trace["traceEvents"].as_array().unwrap();
+    let mut names = HashMap::new();
+    let mut running = HashMap::new();
+    let mut last_ts = 0.0;
+    for entry in entries {
+        let tid = entry["tid"].as_u64().unwrap();
+        assert_eq!(entry["pid"], 1);
+        match entry["ph"].as_str().unwrap() {
+            "M" => {
+                names.insert(tid, entry["args"]["name"].as_str().unwrap().to_string());
+                continue;
+            }
+            // A fiber can't start running again before it stopped, and
+            // stops only once for every time it started
+            "B" => assert!(!running.insert(tid, true).unwrap_or(false), "{entry}"),
+            "E" => assert!(running.insert(tid, false).unwrap_or(false), "{entry}"),
+            "i" => assert_eq!(entry["s"], "t"),
+            ph => panic!("unexpected phase `{ph}`"),
+        }
+        let ts = entry["ts"].as_f64().unwrap();
+        assert!(ts >= last_ts, "{entry} is out of order");
+        last_ts = ts;
+    }
+    assert!(running.values().all(|running| !running), "{running:?}");
+    assert_eq!(names[&0], "base thread");
+    for fiber in 1..4 {
+        assert_eq!(names[&fiber], format!("fiber {fiber}"));
+    }
+}
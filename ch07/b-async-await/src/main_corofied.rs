@@ -26,6 +26,7 @@ fn main() {
     println!("\nELAPSED TIME: {}", start.elapsed().as_secs_f32());
 }
 
+
 // =================================
 // We rewrite this:
 // =================================
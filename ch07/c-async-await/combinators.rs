@@ -0,0 +1,63 @@
+use std::time::Instant;
+
+mod http;
+mod future;
+
+use future::*;
+use crate::http::Http;
+
+/// Resolves to the message the server sends back, `HelloWorld{i}`
+coroutine fn request(i: usize) -> String {
+    let path = format!("/{}/HelloWorld{i}", i * 1000);
+    let message: String = Http::get(&path).map(|txt| txt.lines().last().unwrap_or_default().to_string()).wait;
+    message
+}
+
+coroutine fn async_main() {
+    println!("Program starting");
+
+    // Both requests run at once, so this takes 2 seconds, not 3
+    let both: (String, String) = join(request(2), request(1)).wait;
+    println!("join: {} {}", both.0, both.1);
+
+    // The first one to finish wins, and we drop the other one
+    let first: String = select(request(2), request(1))
+        .map(|either| match either {
+            Either::Left((txt, _)) => format!("left {txt}"),
+            Either::Right((txt, _)) => format!("right {txt}"),
+        })
+        .wait;
+    println!("select: {first}");
+
+    // The second request starts when the first one is done
+    let chained: String = request(1).then(|a| request(1).map(move |b| format!("{a} {b}"))).wait;
+    println!("then: {chained}");
+
+    // The outputs come back in the order the futures were in
+    let all: Vec<String> = join_all(vec![request(3), request(1), request(2)]).wait;
+    println!("join_all: {}", all.join(" "));
+
+    let n: usize = ready(5).wait;
+    let never: String = select(pending::<String>(), request(1))
+        .map(|either| match either {
+            Either::Left((txt, _)) => txt,
+            Either::Right((txt, _)) => txt,
+        })
+        .wait;
+    println!("ready: {n}, pending lost to {never}");
+}
+
+
+fn main() {
+    let start = Instant::now();
+    let mut future = async_main();
+
+    loop {
+        match future.poll() {
+            PollState::NotReady => (),
+            PollState::Ready(_) => break,
+        }
+    }
+
+    println!("\nELAPSED TIME: {}", start.elapsed().as_secs_f32());
+}
@@ -20,7 +20,7 @@ coroutine fn async_main() {
         futures.push(request(i));
     }
 
-    future::join_all(futures).wait;
+    let _: Vec<String> = future::join_all(futures).wait;
 }
 
 
@@ -1,3 +1,8 @@
+// Not every program uses every combinator
+#![allow(dead_code)]
+
+use std::marker::PhantomData;
+
 pub trait Future {
     type Output;
     fn poll(&mut self) -> PollState<Self::Output>;
@@ -8,44 +13,218 @@ pub enum PollState<T> {
     NotReady,
 }
 
-pub fn join_all<F: Future>(futures: Vec<F>) -> JoinAll<F> {
-    let futures = futures.into_iter().map(|f| (false, f)).collect();
-    JoinAll {
-        futures,
-        finished_count: 0,
+/// A future that resolves to `value` the first time it's polled
+pub fn ready<T>(value: T) -> Ready<T> {
+    Ready(Some(value))
+}
+
+pub struct Ready<T>(Option<T>);
+
+impl<T> Future for Ready<T> {
+    type Output = T;
+
+    fn poll(&mut self) -> PollState<Self::Output> {
+        PollState::Ready(self.0.take().expect("`Ready` polled after it resolved"))
     }
 }
 
-pub struct JoinAll<F: Future> {
-    futures: Vec<(bool, F)>,   // bool 记录该 future 是否已完成
-    finished_count: usize,
+/// A future that never resolves
+pub fn pending<T>() -> Pending<T> {
+    Pending(PhantomData)
 }
 
-impl<F: Future> Future for JoinAll<F> {
-    type Output = String;
+pub struct Pending<T>(PhantomData<T>);
+
+impl<T> Future for Pending<T> {
+    type Output = T;
 
     fn poll(&mut self) -> PollState<Self::Output> {
-        // 把 JoinAll 对象中的 futures 拆包并遍历
-        for (finished, fut) in self.futures.iter_mut() {
-            if *finished {
-                continue;
+        PollState::NotReady
+    }
+}
+
+/// A boxed future, like the ones in a `Vec` of different coroutines, is a
+/// future too
+impl<F: Future + ?Sized> Future for Box<F> {
+    type Output = F::Output;
+
+    fn poll(&mut self) -> PollState<Self::Output> {
+        (**self).poll()
+    }
+}
+
+/// The combinators every future gets, like `request(i).map(|txt| txt.len())`
+pub trait FutureExt: Future + Sized {
+    /// Resolves to what `f` makes of the output
+    fn map<U, F: FnOnce(Self::Output) -> U>(self, f: F) -> Map<Self, F> {
+        Map { fut: self, f: Some(f) }
+    }
+
+    /// Resolves to what the future `f` makes of the output resolves to
+    fn then<B: Future, F: FnOnce(Self::Output) -> B>(self, f: F) -> Then<Self, B, F> {
+        Then(ThenState::First(self, Some(f)))
+    }
+}
+
+impl<F: Future> FutureExt for F {}
+
+pub struct Map<Fut, F> {
+    fut: Fut,
+    f: Option<F>,
+}
+
+impl<U, Fut: Future, F: FnOnce(Fut::Output) -> U> Future for Map<Fut, F> {
+    type Output = U;
+
+    fn poll(&mut self) -> PollState<Self::Output> {
+        match self.fut.poll() {
+            PollState::Ready(value) => {
+                let f = self.f.take().expect("`Map` polled after it resolved");
+                PollState::Ready(f(value))
             }
+            PollState::NotReady => PollState::NotReady,
+        }
+    }
+}
+
+pub struct Then<A, B, F>(ThenState<A, B, F>);
 
+enum ThenState<A, B, F> {
+    First(A, Option<F>),
+    Second(B),
+}
+
+impl<A: Future, B: Future, F: FnOnce(A::Output) -> B> Future for Then<A, B, F> {
+    type Output = B::Output;
+
+    fn poll(&mut self) -> PollState<Self::Output> {
+        loop {
+            match &mut self.0 {
+                ThenState::First(a, f) => match a.poll() {
+                    PollState::Ready(value) => {
+                        let f = f.take().expect("`Then` polled after it resolved");
+                        // The second future starts right away
+                        self.0 = ThenState::Second(f(value));
+                    }
+                    PollState::NotReady => return PollState::NotReady,
+                },
+                ThenState::Second(b) => return b.poll(),
+            }
+        }
+    }
+}
+
+/// A future we poll until it resolves, and keep the output of until the
+/// others are done too
+enum MaybeDone<F: Future> {
+    Pending(F),
+    Done(F::Output),
+    Taken,
+}
+
+impl<F: Future> MaybeDone<F> {
+    /// Polls the future if it's still pending, and returns if it's done
+    fn poll(&mut self) -> bool {
+        if let MaybeDone::Pending(fut) = self {
             match fut.poll() {
-                PollState::Ready(_) => {
-                    // 如果 Ready 则更新 JoinAll 对象内部的状态
-                    *finished = true;
-                    self.finished_count += 1;
-                }
-
-                // 如果 future 未 Ready 则 continue 而不是 break
-                PollState::NotReady => continue,
+                PollState::Ready(value) => *self = MaybeDone::Done(value),
+                PollState::NotReady => return false,
             }
         }
+        true
+    }
+
+    fn take(&mut self) -> F::Output {
+        match std::mem::replace(self, MaybeDone::Taken) {
+            MaybeDone::Done(value) => value,
+            _ => panic!("polled a join after it resolved"),
+        }
+    }
+}
+
+/// Polls `a` and `b` together and resolves to both outputs
+pub fn join<A: Future, B: Future>(a: A, b: B) -> Join<A, B> {
+    Join {
+        a: MaybeDone::Pending(a),
+        b: MaybeDone::Pending(b),
+    }
+}
+
+pub struct Join<A: Future, B: Future> {
+    a: MaybeDone<A>,
+    b: MaybeDone<B>,
+}
+
+impl<A: Future, B: Future> Future for Join<A, B> {
+    type Output = (A::Output, B::Output);
+
+    fn poll(&mut self) -> PollState<Self::Output> {
+        // Both get polled, even if the first isn't ready
+        let a = self.a.poll();
+        let b = self.b.poll();
+        if a && b {
+            PollState::Ready((self.a.take(), self.b.take()))
+        } else {
+            PollState::NotReady
+        }
+    }
+}
+
+/// One of two things, like which future of a `select` was first
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+/// Polls `a` and `b` together and resolves to the output of the first one
+/// that's ready, along with the other one, which can still be polled
+pub fn select<A: Future, B: Future>(a: A, b: B) -> Select<A, B> {
+    Select(Some((a, b)))
+}
+
+pub struct Select<A, B>(Option<(A, B)>);
+
+impl<A: Future, B: Future> Future for Select<A, B> {
+    type Output = Either<(A::Output, B), (B::Output, A)>;
+
+    fn poll(&mut self) -> PollState<Self::Output> {
+        let (a, b) = self.0.as_mut().expect("`Select` polled after it resolved");
+        if let PollState::Ready(value) = a.poll() {
+            let (_, b) = self.0.take().unwrap();
+            return PollState::Ready(Either::Left((value, b)));
+        }
+        if let PollState::Ready(value) = b.poll() {
+            let (a, _) = self.0.take().unwrap();
+            return PollState::Ready(Either::Right((value, a)));
+        }
+        PollState::NotReady
+    }
+}
 
+/// Polls all the futures together and resolves to their outputs, in the
+/// order they were in
+pub fn join_all<F: Future>(futures: Vec<F>) -> JoinAll<F> {
+    let futures = futures.into_iter().map(MaybeDone::Pending).collect();
+    JoinAll { futures }
+}
+
+pub struct JoinAll<F: Future> {
+    futures: Vec<MaybeDone<F>>,
+}
+
+impl<F: Future> Future for JoinAll<F> {
+    type Output = Vec<F::Output>;
+
+    fn poll(&mut self) -> PollState<Self::Output> {
+        // 如果 future 未 Ready 则继续 poll 下一个而不是 break，
         // 只有所有 future 都完成了整个 poll 过程才返回 Ready
-        if self.finished_count == self.futures.len() {
-            PollState::Ready(String::new())
+        let mut finished = true;
+        for fut in self.futures.iter_mut() {
+            finished &= fut.poll();
+        }
+
+        if finished {
+            PollState::Ready(self.futures.iter_mut().map(MaybeDone::take).collect())
         } else {
             PollState::NotReady
         }
@@ -26,7 +26,7 @@ coroutine fn async_main() {
     // 也即调用 JoinAll 结构体对象的 poll 方法。
     //
     // 只有实现 Future trait 的对象才可以使用 .wait 作为后缀
-    future::join_all(futures).wait;
+    let _: Vec<String> = future::join_all(futures).wait;
 }
 
 
@@ -13,6 +13,8 @@ use crate::http::Http;
 
 fn main() {
     let start = Instant::now();
+    // 由于 async_main 的 coroutine 标记，将生成一个 future 对象，
+    // 在其 poll 方法中调用上述 JoinAll 结构体对象的 poll 方法。
     let mut future = async_main();
 
     loop {
@@ -25,6 +27,7 @@ fn main() {
     println!("\nELAPSED TIME: {}", start.elapsed().as_secs_f32());
 }
 
+
 // =================================
 // We rewrite this:
 // =================================
@@ -72,7 +75,7 @@ impl Future for Coroutine0 {
                     let path = format!("/{}/HelloWorld{i}", i * 1000);
 
                     // ---------------------------------
-                    let fut1 = Box::new( Http::get(&path));
+                    let fut1 = Box::new(Http::get(&path));
                     self.state = State0::Wait1(fut1);
                 }
 
@@ -101,6 +104,7 @@ impl Future for Coroutine0 {
 // We rewrite this:
 // =================================
     
+// /// 标有 coroutine 的函数的返回类型将被重写为 `impl Future<Output=String>`
 // coroutine fn async_main() {
 //     println!("Program starting");
 //     let mut futures = vec![];
@@ -109,7 +113,12 @@ impl Future for Coroutine0 {
 //         futures.push(request(i));
 //     }
 // 
-//     future::join_all(futures).wait;
+//     // 调用 join_all 函数，返回一个 JoinAll 结构体对象（Future 对象），
+//     // .wait 关键字调用将使生成的状态机代码中自动 poll 刚刚返回的 Future 对象，
+//     // 也即调用 JoinAll 结构体对象的 poll 方法。
+//     //
+//     // 只有实现 Future trait 的对象才可以使用 .wait 作为后缀
+//     let _: Vec<String> = future::join_all(futures).wait;
 
 // }
 
@@ -117,13 +126,14 @@ impl Future for Coroutine0 {
 // Into this:
 // =================================
 
+/// 标有 coroutine 的函数的返回类型将被重写为 `impl Future<Output=String>`
 fn async_main() -> impl Future<Output=String> {
     Coroutine1::new()
 }
         
 enum State1 {
     Start,
-    Wait1(Box<dyn Future<Output = String>>),
+    Wait1(Box<dyn Future<Output = Vec<String>>>),
     Resolved,
 }
 
@@ -153,6 +163,11 @@ impl Future for Coroutine1 {
         futures.push(request(i));
     }
 
+    // 调用 join_all 函数，返回一个 JoinAll 结构体对象（Future 对象），
+    // .wait 关键字调用将使生成的状态机代码中自动 poll 刚刚返回的 Future 对象，
+    // 也即调用 JoinAll 结构体对象的 poll 方法。
+    //
+    // 只有实现 Future trait 的对象才可以使用 .wait 作为后缀
 
                     // ---------------------------------
                     let fut1 = Box::new(future::join_all(futures));
@@ -0,0 +1,202 @@
+//! The `#[coroutine]` attribute does the same transformation as `corofy`,
+//! but at compile time. Put it on an ordinary function that uses `.wait`
+//! and it's replaced by a function that returns a state machine:
+//!
+//! ```ignore
+//! #[corofy_macro::coroutine]
+//! fn request(i: usize) {
+//!     let path = format!("/{}/HelloWorld{i}", i * 1000);
+//!     let txt = Http::get(&path).wait;
+//!     println!("{txt}");
+//! }
+//! ```
+//!
+//! Rust has an unstable built-in attribute called `coroutine`, so we have
+//! to refer to ours by its full path. A plain `#[coroutine]` is ambiguous
+//! even if we import it.
+//!
+//! The state machine implements the chapter 7 `Future` trait unless we ask
+//! for another one, the same way `corofy --target` does, and
+//! `syntax = "async"` reads `.await` instead of `.wait` like `corofy
+//! --async`:
+//!
+//! ```ignore
+//! #[corofy_macro::coroutine(target = "std", syntax = "async")]
+//! async fn request(i: usize) {
+//!     let path = format!("/{}/HelloWorld{i}", i * 1000);
+//!     let txt = async move { Http::get(&path).await }.await;
+//!     println!("{txt}");
+//! }
+//! ```
+//!
+//! rustc parses the function before we get it, so a `coroutine { .. }`
+//! block can't be in it. An `async` block can.
+//!
+//! We don't duplicate any of the work `corofy` does. We turn the function
+//! back into source code, put the `coroutine` keyword in front of `fn` and
+//! let `corofy` expand it.
+use std::{panic, thread};
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+#[proc_macro_attribute]
+pub fn coroutine(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let options = match options(attr) {
+        Ok(options) => options,
+        Err((span, message)) => return compile_error(span, &message),
+    };
+
+    let mut src = Source {
+        keyword: options.syntax.coroutine().to_string(),
+        ..Source::default()
+    };
+    src.push_tokens(item, true);
+
+    // `corofy` needs to know where in the source each token is. The spans
+    // the compiler gives `proc_macro2` don't tell, but it falls back to its
+    // own tokens, which do, on any thread the compiler doesn't run macros on.
+    // That only works if this crate never uses `proc_macro2` on this thread,
+    // which is why we stick to `proc_macro` here.
+    let expanded = thread::scope(|s| s.spawn(|| corofy::expand(&options, &src.text)).join());
+    let expanded = expanded.unwrap_or_else(|e| panic::resume_unwind(e));
+
+    match expanded {
+        Ok(code) => code.parse().unwrap(),
+        Err(e) if e.diagnostics().is_empty() => compile_error(Span::call_site(), &e.to_string()),
+        Err(e) => e
+            .diagnostics()
+            .iter()
+            .map(|d| compile_error(src.span_at(d.range.start), &d.message))
+            .collect(),
+    }
+}
+
+/// Reads `target = "ch08"` and `syntax = "async"`, separated by a comma
+fn options(attr: TokenStream) -> Result<corofy::Options, (Span, String)> {
+    let mut options = corofy::Options::default();
+    let tokens: Vec<TokenTree> = attr.into_iter().collect();
+    for arg in tokens.split(|tt| matches!(tt, TokenTree::Punct(p) if p.as_char() == ',')) {
+        let (name, value) = match arg {
+            [] => continue,
+            [TokenTree::Ident(name), TokenTree::Punct(eq), TokenTree::Literal(value)]
+                if eq.as_char() == '=' =>
+            {
+                (name, value)
+            }
+            [tt, ..] => {
+                return Err((
+                    tt.span(),
+                    String::from("expected `target = \"...\"` or `syntax = \"...\"`"),
+                ))
+            }
+        };
+        let text = value.to_string();
+        let Some(text) = text.strip_prefix('"').and_then(|t| t.strip_suffix('"')) else {
+            return Err((value.span(), String::from("expected a string")));
+        };
+        match name.to_string().as_str() {
+            "target" => options.target = text.parse().map_err(|e| (value.span(), e))?,
+            "syntax" => options.syntax = text.parse().map_err(|e| (value.span(), e))?,
+            _ => {
+                return Err((
+                    name.span(),
+                    format!("unknown argument `{name}`, expected `target` or `syntax`"),
+                ))
+            }
+        }
+    }
+    Ok(options)
+}
+
+/// `::core::compile_error! { "message" }`, pointing at `span`
+fn compile_error(span: Span, message: &str) -> TokenStream {
+    let punct = |c, spacing| TokenTree::Punct(Punct::new(c, spacing));
+    let mut message = Literal::string(message);
+    message.set_span(span);
+    let body = Group::new(Delimiter::Brace, TokenTree::Literal(message).into());
+    let tokens = [
+        punct(':', Spacing::Joint),
+        punct(':', Spacing::Alone),
+        TokenTree::Ident(Ident::new("core", span)),
+        punct(':', Spacing::Joint),
+        punct(':', Spacing::Alone),
+        TokenTree::Ident(Ident::new("compile_error", span)),
+        punct('!', Spacing::Alone),
+        TokenTree::Group(body),
+    ];
+    tokens
+        .into_iter()
+        .map(|mut tt| {
+            tt.set_span(span);
+            tt
+        })
+        .collect()
+}
+
+/// The function we got as source code, and where each token starts so we
+/// can point errors from `corofy` at the code the user wrote.
+#[derive(Default)]
+struct Source {
+    text: String,
+    spans: Vec<(usize, Span)>,
+    /// What we put in front of `fn`, unless it's there already, like the
+    /// `async` of an `async fn`
+    keyword: String,
+    found_fn: bool,
+}
+
+impl Source {
+    fn push_tokens(&mut self, tokens: TokenStream, top_level: bool) {
+        for tt in tokens {
+            match tt {
+                TokenTree::Group(g) => {
+                    let (open, close) = match g.delimiter() {
+                        Delimiter::Parenthesis => ("(", ")"),
+                        Delimiter::Brace => ("{", "}"),
+                        Delimiter::Bracket => ("[", "]"),
+                        Delimiter::None => ("", ""),
+                    };
+                    self.push(open, g.span_open(), true);
+                    self.push_tokens(g.stream(), false);
+                    self.push(close, g.span_close(), true);
+                }
+                TokenTree::Ident(ident) => {
+                    let name = ident.to_string();
+                    if top_level && !self.found_fn && name == self.keyword {
+                        self.found_fn = true;
+                    }
+                    if top_level && !self.found_fn && name == "fn" {
+                        self.text.push_str(&self.keyword);
+                        self.text.push(' ');
+                        self.found_fn = true;
+                    }
+                    self.push(&ident.to_string(), ident.span(), true);
+                }
+                // `::`, `->` and lifetimes are made from punctuation that's
+                // joined with the next token, so we can't put a space after it
+                TokenTree::Punct(p) => self.push(
+                    &p.as_char().to_string(),
+                    p.span(),
+                    p.spacing() == Spacing::Alone,
+                ),
+                TokenTree::Literal(lit) => self.push(&lit.to_string(), lit.span(), true),
+            }
+        }
+    }
+
+    fn push(&mut self, text: &str, span: Span, space: bool) {
+        self.spans.push((self.text.len(), span));
+        self.text.push_str(text);
+        if space {
+            self.text.push(' ');
+        }
+    }
+
+    fn span_at(&self, pos: usize) -> Span {
+        let i = self.spans.partition_point(|(start, _)| *start <= pos);
+        match i {
+            0 => Span::call_site(),
+            i => self.spans[i - 1].1,
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::{cell::RefCell, thread_local};
+
+pub trait Future {
+    type Output;
+    fn poll(&mut self) -> PollState<Self::Output>;
+}
+
+pub enum PollState<T> {
+    Ready(T),
+    NotReady,
+}
+
+thread_local! {
+    static LOG: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+}
+
+fn log(msg: String) {
+    LOG.with(|log| log.borrow_mut().push(msg));
+}
+
+/// A leaf future that is `NotReady` the first time it's polled
+struct Delay {
+    msg: &'static str,
+    polled: bool,
+}
+
+fn delay(msg: &'static str) -> Delay {
+    Delay { msg, polled: false }
+}
+
+impl Future for Delay {
+    type Output = String;
+
+    fn poll(&mut self) -> PollState<Self::Output> {
+        if self.polled {
+            PollState::Ready(self.msg.to_string())
+        } else {
+            self.polled = true;
+            PollState::NotReady
+        }
+    }
+}
+
+/// Two coroutines in the same module don't clash with each other
+#[corofy_macro::coroutine]
+fn first(i: usize) {
+    log(format!("first {i}"));
+    let txt = delay("one").wait;
+    log(txt);
+}
+
+#[corofy_macro::coroutine]
+pub fn second() {
+    log(String::from("second"));
+    first(1).wait;
+    let txt = delay("two").wait;
+    log(txt);
+}
+
+#[test]
+fn coroutine_runs_to_completion() {
+    let mut future = second();
+    let mut not_ready = 0;
+    while let PollState::NotReady = future.poll() {
+        not_ready += 1;
+    }
+
+    assert_eq!(not_ready, 2);
+    let log = LOG.with(|log| log.borrow().clone());
+    assert_eq!(log, ["second", "first 1", "one", "two"]);
+}
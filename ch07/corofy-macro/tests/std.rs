@@ -0,0 +1,55 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// A leaf future that is `Pending` the first time it's polled
+struct Delay {
+    msg: &'static str,
+    polled: bool,
+}
+
+fn delay(msg: &'static str) -> Delay {
+    Delay { msg, polled: false }
+}
+
+impl Future for Delay {
+    type Output = String;
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Self::Output> {
+        if self.polled {
+            Poll::Ready(self.msg.to_string())
+        } else {
+            self.polled = true;
+            Poll::Pending
+        }
+    }
+}
+
+/// Waits on an `async` block, which becomes a function of its own
+#[corofy_macro::coroutine(target = "std", syntax = "async")]
+async fn joined(prefix: String) -> String {
+    let inner = async move {
+        let txt = delay("one").await;
+        txt
+    };
+    let one: String = inner.await;
+    let two = delay("two").await;
+    format!("{prefix} {one} {two}")
+}
+
+#[test]
+fn coroutine_implements_std_future() {
+    let mut future = std::pin::pin!(joined(String::from("got")));
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut pending = 0;
+    let out = loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(out) => break out,
+            Poll::Pending => pending += 1,
+        }
+    };
+    assert_eq!(pending, 2);
+    assert_eq!(out, "got one two");
+}
@@ -0,0 +1,855 @@
+//! `coroutine { .. }` blocks and `coroutine move |args| { .. }` closures.
+//!
+//! We don't write state machines for these ourselves. Each of them becomes
+//! a `coroutine fn` at the end of the module, which takes what the block
+//! uses from the function around it as arguments, and the block becomes a
+//! call to that function:
+//!
+//! ```text
+//! fn main() {                                fn main() {
+//!     let path = format!("/{}", 1000);           let path = format!("/{}", 1000);
+//!     spawn(coroutine move {                     spawn(main_block0(path));
+//!         let txt = Http::get(&path).wait;   }
+//!         println!("{txt}");
+//!     });                                    coroutine fn main_block0(path: String) {
+//! }                                              let txt = Http::get(&path).wait;
+//!                                                println!("{txt}");
+//!                                            }
+//! ```
+//!
+//! A `move` block takes what it uses by value. Any other block borrows it,
+//! so without the `move` the argument would be `path: &String`. A closure
+//! passes its own arguments on first. The `coroutine fn`s are then rewritten
+//! like any other, and a block inside a block becomes a function the next
+//! time around.
+//!
+//! The arguments need types, which we find where the variables are
+//! declared, the same way we find the types of the variables a coroutine
+//! keeps between wait points.
+use std::collections::HashMap;
+use std::ops::Range;
+
+use proc_macro2::{Span, TokenStream};
+use quote::ToTokens;
+use syn::{
+    parse::Parser,
+    punctuated::Punctuated,
+    spanned::Spanned,
+    visit::{self, Visit},
+    Block, Expr, ExprAsync, ExprClosure, FnArg, Generics, ImplItemFn, ItemFn, ItemImpl, ItemMod,
+    Macro, Pat, ReturnType, Signature, Token, Type,
+};
+
+use crate::cfg::{names_in, Bindings, Infer};
+use crate::error::{CorofyError, Diagnostic};
+use crate::parse::{line_start_if_blank_before, replace_keywords, waited_on, Errors};
+use crate::source_map::Origin;
+use crate::Syntax;
+
+/// A source with its coroutine blocks turned into `coroutine fn`s, and the
+/// way back to the source
+pub(crate) struct Desugared<'a> {
+    /// The source we were given
+    orig: &'a str,
+    pub(crate) src: String,
+    /// Where the text of every round comes from in the text of the round
+    /// before it
+    rounds: Vec<Vec<Piece>>,
+}
+
+/// Some of the text of a round, and where it comes from
+struct Piece {
+    /// Where it starts in the new text
+    at: usize,
+    /// Where it starts in the old text, or the code we made it for
+    from: usize,
+    /// Copied as it was, rather than made up
+    copied: bool,
+}
+
+/// Turns every coroutine block and closure in `src` into a function, one
+/// level of blocks at a time
+pub(crate) fn desugar<'a>(src: &'a str, syntax: &Syntax) -> Result<Desugared<'a>, CorofyError> {
+    let mut desugared = Desugared {
+        orig: src,
+        src: src.to_string(),
+        rounds: vec![],
+    };
+    loop {
+        match round(&desugared.src, syntax) {
+            Ok(Some((text, pieces))) => {
+                desugared.src = text;
+                desugared.rounds.push(pieces);
+            }
+            Ok(None) => return Ok(desugared),
+            Err(e) => return Err(desugared.diagnostics(e)),
+        }
+    }
+}
+
+impl Desugared<'_> {
+    /// Where `pos` in `src` is in the source we were given
+    fn original(&self, mut pos: usize) -> usize {
+        for pieces in self.rounds.iter().rev() {
+            let i = pieces.partition_point(|p| p.at <= pos).saturating_sub(1);
+            pos = match &pieces[i] {
+                Piece {
+                    at,
+                    from,
+                    copied: true,
+                } => from + (pos - at),
+                Piece { from, .. } => *from,
+            };
+        }
+        pos
+    }
+
+    /// Points the errors we found in `src` at the same code in the source
+    pub(crate) fn diagnostics(&self, errors: syn::Error) -> CorofyError {
+        CorofyError::Unsupported(
+            errors
+                .into_iter()
+                .map(|e| {
+                    let range = e.span().byte_range();
+                    let start = self.original(range.start);
+                    let end = match range.is_empty() {
+                        true => start,
+                        false => (self.original(range.end - 1) + 1).max(start),
+                    };
+                    Diagnostic::new(self.orig, start..end, e.to_string())
+                })
+                .collect(),
+        )
+    }
+
+    /// Where the line `origin` points at in `src` is in the source
+    pub(crate) fn origin(&self, origin: Origin) -> Origin {
+        if self.rounds.is_empty() {
+            return origin;
+        }
+        let Some(start) = line_start(&self.src, origin.line) else {
+            return origin;
+        };
+        // The first bit of code on the line stands for the whole line
+        let line = &self.src[start..];
+        let line = &line[..line.find('\n').unwrap_or(line.len())];
+        let column = line.len() - line.trim_start().len();
+        let pos = self.original(start + column);
+        let orig_start = self.orig[..pos].rfind('\n').map_or(0, |i| i + 1);
+        Origin {
+            line: self.orig[..orig_start].matches('\n').count() + 1,
+            shift: origin.shift + (pos - orig_start) as isize - column as isize,
+        }
+    }
+}
+
+/// Where `line`, counting from 1, starts in `text`
+fn line_start(text: &str, line: usize) -> Option<usize> {
+    match line {
+        0 => None,
+        1 => Some(0),
+        _ => text.match_indices('\n').nth(line - 2).map(|(i, _)| i + 1),
+    }
+}
+
+/// Turns the outermost coroutine blocks in `src` into functions. Returns
+/// `None` if there aren't any.
+fn round(src: &str, syntax: &Syntax) -> syn::Result<Option<(String, Vec<Piece>)>> {
+    let tokens: TokenStream = src.parse().map_err(|e: proc_macro2::LexError| {
+        syn::Error::new(
+            e.span(),
+            "failed to tokenize the file (look for unbalanced delimiters or an unterminated string or comment)",
+        )
+    })?;
+    let mut blocks = vec![];
+    let tokens = replace_keywords(tokens, syntax, &mut vec![], &mut blocks);
+    if blocks.is_empty() {
+        return Ok(None);
+    }
+    let file: syn::File = syn::parse2(tokens)?;
+    let mut macros = MacroArgs::default();
+    macros.visit_file(&file);
+
+    let mut finder = Blocks {
+        src,
+        syntax,
+        macros: &macros.0,
+        keywords: blocks.iter().map(|s| s.byte_range().start).collect(),
+        insert_at: src.len(),
+        impl_generics: None,
+        func: None,
+        scope: vec![],
+        names: HashMap::new(),
+        edits: vec![],
+        errors: Errors::default(),
+    };
+    finder.visit_file(&file);
+    // A block we didn't get to would still be there next time around,
+    // unless it's inside one we moved, which we get to then. One we
+    // couldn't rewrite has told why already.
+    let failed = finder.errors.count() > 0;
+    for kw in blocks.iter().filter(|_| !failed) {
+        let at = kw.byte_range().start;
+        if !finder
+            .edits
+            .iter()
+            .any(|edit| edit.anchor == at || edit.range.contains(&at))
+        {
+            finder.errors.push(syn::Error::new(
+                *kw,
+                format!(
+                    "a `{}` block or closure can't be used here",
+                    syntax.coroutine()
+                ),
+            ));
+        }
+    }
+    finder.errors.finish()?;
+
+    // Edits at the same place stay in the order we found them in
+    let mut edits = finder.edits;
+    edits.sort_by_key(|edit| edit.range.start);
+    let mut out = Rewritten::default();
+    let mut pos = 0;
+    for edit in edits {
+        out.copy(src, pos..edit.range.start);
+        for part in edit.parts {
+            match part {
+                Part::Copy(range) => out.copy(src, range),
+                Part::Make(text) => out.make(&text, edit.anchor),
+            }
+        }
+        pos = edit.range.end;
+    }
+    out.copy(src, pos..src.len());
+    Ok(Some((out.text, out.pieces)))
+}
+
+/// The text of a round as we write it
+#[derive(Default)]
+struct Rewritten {
+    text: String,
+    pieces: Vec<Piece>,
+}
+
+impl Rewritten {
+    fn copy(&mut self, src: &str, range: Range<usize>) {
+        self.pieces.push(Piece {
+            at: self.text.len(),
+            from: range.start,
+            copied: true,
+        });
+        self.text.push_str(&src[range]);
+    }
+
+    fn make(&mut self, text: &str, anchor: usize) {
+        self.pieces.push(Piece {
+            at: self.text.len(),
+            from: anchor,
+            copied: false,
+        });
+        self.text.push_str(text);
+    }
+}
+
+/// Replaces `range` with `parts`. The code we make up comes from `anchor`.
+struct Edit {
+    range: Range<usize>,
+    anchor: usize,
+    parts: Vec<Part>,
+}
+
+enum Part {
+    Copy(Range<usize>),
+    Make(String),
+}
+
+/// What a block or closure runs
+#[derive(Clone, Copy)]
+enum Body<'a> {
+    Block(&'a Block),
+    /// A closure without braces, like `|i: usize| request(i).wait`
+    Expr(&'a Expr),
+}
+
+impl Body<'_> {
+    fn span(&self) -> Span {
+        match self {
+            Body::Block(block) => block.span(),
+            Body::Expr(expr) => expr.span(),
+        }
+    }
+
+    fn tokens(&self) -> TokenStream {
+        match self {
+            Body::Block(block) => block.to_token_stream(),
+            Body::Expr(expr) => expr.to_token_stream(),
+        }
+    }
+}
+
+/// A variable in scope, and its type if we know it
+struct Binding<'a> {
+    name: String,
+    ty: Option<String>,
+    declared: Option<&'a Type>,
+}
+
+/// The function the blocks we find are in
+struct Func<'a> {
+    name: String,
+    generics: &'a Generics,
+}
+
+/// Finds the outermost coroutine blocks and closures, and what they use
+/// from the function they're in
+struct Blocks<'a> {
+    src: &'a str,
+    syntax: &'a Syntax,
+    macros: &'a HashMap<usize, Vec<Expr>>,
+    /// Byte offset of every `coroutine` in front of a block or closure
+    keywords: Vec<usize>,
+    /// The end of the module we're in
+    insert_at: usize,
+    impl_generics: Option<&'a Generics>,
+    func: Option<Func<'a>>,
+    /// The variables in scope in `func`, innermost last
+    scope: Vec<Binding<'a>>,
+    /// How many blocks we found in each function, to name them
+    names: HashMap<String, usize>,
+    edits: Vec<Edit>,
+    errors: Errors,
+}
+
+impl<'a> Infer<'a> for Blocks<'a> {
+    fn src(&self) -> &'a str {
+        self.src
+    }
+
+    fn var_type(&self, name: &str) -> Option<String> {
+        self.binding(name)?.ty.clone()
+    }
+
+    fn declared(&self, name: &str) -> Option<&'a Type> {
+        self.binding(name)?.declared
+    }
+}
+
+impl<'a> Blocks<'a> {
+    fn binding(&self, name: &str) -> Option<&Binding<'a>> {
+        self.scope.iter().rev().find(|b| b.name == name)
+    }
+
+    fn is_coroutine(&self, kw: Option<&syn::Token![async]>) -> bool {
+        kw.is_some_and(|kw| self.keywords.contains(&kw.span.byte_range().start))
+    }
+
+    /// Declares the variables `pat` binds. `ty` is the type of the whole
+    /// pattern if we know it.
+    fn declare(&mut self, pat: &'a Pat, ty: Option<String>, declared: Option<&'a Type>) {
+        match pat {
+            Pat::Ident(p) if p.subpat.is_none() => self.scope.push(Binding {
+                name: p.ident.to_string(),
+                ty,
+                declared,
+            }),
+            Pat::Type(p) => {
+                let ty = self.text(p.ty.span()).to_string();
+                self.declare(&p.pat, Some(ty), Some(&p.ty))
+            }
+            pat => {
+                let mut bindings = Bindings::default();
+                bindings.visit_pat(pat);
+                for (ident, _) in bindings.0 {
+                    self.scope.push(Binding {
+                        name: ident.to_string(),
+                        ty: None,
+                        declared: None,
+                    });
+                }
+            }
+        }
+    }
+
+    fn func(&mut self, sig: &'a Signature, block: &'a Block) {
+        let outer_func = self.func.replace(Func {
+            name: sig.ident.to_string(),
+            generics: &sig.generics,
+        });
+        let outer_scope = std::mem::take(&mut self.scope);
+        for arg in &sig.inputs {
+            match arg {
+                FnArg::Receiver(_) => self.scope.push(Binding {
+                    name: String::from("self"),
+                    ty: None,
+                    declared: None,
+                }),
+                FnArg::Typed(arg) => {
+                    let ty = self.text(arg.ty.span()).to_string();
+                    self.declare(&arg.pat, Some(ty), Some(&arg.ty));
+                }
+            }
+        }
+        self.visit_block(block);
+        self.scope = outer_scope;
+        self.func = outer_func;
+    }
+
+    /// Turns a block or a closure into a function. `span` is all of it,
+    /// `args` the arguments of a closure and `body` what it runs.
+    fn coroutine(
+        &mut self,
+        span: Span,
+        kw: Span,
+        moves: bool,
+        args: Option<&'a ExprClosure>,
+        body: Body<'a>,
+    ) {
+        let Some(func) = &self.func else {
+            self.errors.push(syn::Error::new(
+                kw,
+                format!(
+                    "a `{}` block has to be in a function",
+                    self.syntax.coroutine()
+                ),
+            ));
+            return;
+        };
+        let kind = if args.is_some() { "closure" } else { "block" };
+        let n = self.names.entry(func.name.clone()).or_default();
+        let name = format!("{}_{kind}{n}", func.name);
+        *n += 1;
+
+        // The arguments of a closure are passed on as they are
+        let mut params = vec![];
+        let mut errors = Errors::default();
+        if let Some(closure) = args {
+            for input in &closure.inputs {
+                match input {
+                    Pat::Type(arg) if matches!(&*arg.pat, Pat::Ident(_)) => {
+                        params.push((
+                            self.text(arg.pat.span()).to_string(),
+                            self.text(arg.span()).to_string(),
+                        ));
+                    }
+                    arg => errors.push(syn::Error::new(
+                        arg.span(),
+                        "the arguments of a coroutine closure need a type, like `i: usize`",
+                    )),
+                }
+            }
+        }
+
+        // What the block uses from around it. A variable the block declares
+        // itself isn't used from around it after that.
+        let body_range = body.span().byte_range();
+        let mut own = Bindings::default();
+        match body {
+            Body::Block(block) => own.visit_block(block),
+            Body::Expr(expr) => own.visit_expr(expr),
+        }
+        let mut captures: Vec<(String, String)> = vec![];
+        for (used, at) in names_in(body.tokens()) {
+            let declared_before = own.0.iter().any(|(ident, _)| {
+                ident == &used && ident.span().byte_range().start < at.byte_range().start
+            });
+            if declared_before
+                || params.iter().any(|(param, _)| *param == used)
+                || captures.iter().any(|(capture, _)| *capture == used)
+            {
+                continue;
+            }
+            let Some(binding) = self.binding(&used) else {
+                continue;
+            };
+            if used == "self" {
+                errors.push(syn::Error::new(
+                    at,
+                    format!(
+                        "a `{}` {kind} can't use `self`, put what it needs in a variable first",
+                        self.syntax.coroutine()
+                    ),
+                ));
+                continue;
+            }
+            let Some(ty) = &binding.ty else {
+                errors.push(syn::Error::new(
+                    at,
+                    format!("can't tell the type of `{used}`, which this {kind} takes from the function it's in. Give it a type where it's declared, like `let {used}: Type = ...;`"),
+                ));
+                continue;
+            };
+            if names_in(ty.parse().unwrap_or_default())
+                .iter()
+                .any(|(n, _)| n == "Self")
+            {
+                errors.push(syn::Error::new(
+                    at,
+                    format!("the type of `{used}` is `{ty}`, and a `{}` {kind} can't use `Self`. Name the type instead", self.syntax.coroutine()),
+                ));
+                continue;
+            }
+            let ty = if moves { ty.clone() } else { format!("&{ty}") };
+            captures.push((used, ty));
+        }
+        let fail = errors.count() > 0;
+        self.errors.extend(errors);
+        if fail {
+            return;
+        }
+
+        let output = match args.map(|closure| &closure.output) {
+            Some(ReturnType::Type(_, ty)) => Some(self.text(ty.span()).to_string()),
+            _ => self.tail_type(body, kw, kind),
+        };
+        let output = match output {
+            Some(ty) => format!(" -> {ty}"),
+            None => String::new(),
+        };
+        let (generics, where_clause) = self.generics(&params, &captures, &output, body);
+
+        // The call that replaces the block
+        let call_args: Vec<String> = params
+            .iter()
+            .map(|(param, _)| param.clone())
+            .chain(captures.iter().map(|(capture, _)| match moves {
+                true => capture.clone(),
+                false => format!("&{capture}"),
+            }))
+            .collect();
+        let call = format!("{name}({})", call_args.join(", "));
+        let call = match args {
+            Some(_) => {
+                let mv = if moves { "move " } else { "" };
+                let inputs: Vec<&str> = params.iter().map(|(_, arg)| arg.as_str()).collect();
+                format!("{mv}|{}| {call}", inputs.join(", "))
+            }
+            None => call,
+        };
+        let kw_start = kw.byte_range().start;
+        self.edits.push(Edit {
+            range: span.byte_range(),
+            anchor: kw_start,
+            parts: vec![Part::Make(call)],
+        });
+
+        // The function, with the lines of the body as far left as the line
+        // the block starts on
+        let fn_args: Vec<String> = params
+            .iter()
+            .map(|(_, arg)| arg.clone())
+            .chain(
+                captures
+                    .iter()
+                    .map(|(capture, ty)| format!("{capture}: {ty}")),
+            )
+            .collect();
+        let line_start = self.src[..kw_start].rfind('\n').map_or(0, |i| i + 1);
+        let indent = self.src[line_start..kw_start].len()
+            - self.src[line_start..kw_start].trim_start().len();
+        let mut parts = vec![Part::Make(format!(
+            "\n{} fn {name}{generics}({}){output}{where_clause} ",
+            self.syntax.coroutine(),
+            fn_args.join(", ")
+        ))];
+        let is_block = matches!(body, Body::Block(_));
+        if !is_block {
+            parts.push(Part::Make(String::from("{\n    ")));
+        }
+        let mut pos = body_range.start;
+        for line in self.src[body_range.clone()].split_inclusive('\n') {
+            let start = pos;
+            pos += line.len();
+            // The first line starts at the block, and the lines of a
+            // multi-line string aren't indented
+            let skip = match start == body_range.start {
+                true => 0,
+                false => line.len() - line.trim_start_matches(' ').len(),
+            };
+            parts.push(Part::Copy(start + skip.min(indent)..pos));
+        }
+        if !is_block {
+            parts.push(Part::Make(String::from("\n}")));
+        }
+        parts.push(Part::Make(String::from("\n")));
+        self.edits.push(Edit {
+            range: self.insert_at..self.insert_at,
+            anchor: kw_start,
+            parts,
+        });
+    }
+
+    /// What a block that ends in an expression resolves to. We know the
+    /// variables it declares before that, but not what's in its branches.
+    fn tail_type(&mut self, body: Body<'a>, kw: Span, kind: &str) -> Option<String> {
+        let (stmts, tail) = match body {
+            Body::Block(block) => match block.stmts.split_last() {
+                Some((syn::Stmt::Expr(tail, None), stmts)) => (stmts, tail),
+                _ => return None,
+            },
+            Body::Expr(expr) => (&[][..], expr),
+        };
+        let outer = self.scope.len();
+        for stmt in stmts {
+            if let syn::Stmt::Local(local) = stmt {
+                let ty = self.local_type(local);
+                self.declare(&local.pat, ty, None);
+            }
+        }
+        let ty = self.local_type_of(tail);
+        self.scope.truncate(outer);
+        // `println!(..)` and the like are `()`, which is what an `async`
+        // block without a value resolves to anyway
+        if ty.is_none() && !self.syntax.is_async() {
+            self.errors.push(syn::Error::new(
+                kw,
+                format!("can't tell what this {kind} resolves to. End it with a variable that has a type, like `let out: Type = ...; out`, or with `;` to resolve to `{}`", self.syntax.default_output().1),
+            ));
+        }
+        ty
+    }
+
+    /// The type of the variable a `let` declares, if we can tell
+    fn local_type(&self, local: &syn::Local) -> Option<String> {
+        self.local_type_of(&local.init.as_ref()?.expr)
+    }
+
+    /// What a coroutine waits on outputs a `String` unless it says otherwise
+    fn local_type_of(&self, expr: &Expr) -> Option<String> {
+        match waited_on(expr) {
+            Some(_) => Some(String::from("String")),
+            None => self.expr_type(expr),
+        }
+    }
+
+    /// The generic parameters of the function and `impl` block that the
+    /// function we write uses, and their `where` clause
+    fn generics(
+        &self,
+        params: &[(String, String)],
+        captures: &[(String, String)],
+        output: &str,
+        body: Body,
+    ) -> (String, String) {
+        let mut used: Vec<String> = vec![];
+        for text in params
+            .iter()
+            .chain(captures)
+            .map(|(_, ty)| ty.as_str())
+            .chain([output])
+        {
+            used.extend(
+                names_in(text.parse().unwrap_or_default())
+                    .into_iter()
+                    .map(|(n, _)| n),
+            );
+        }
+        used.extend(names_in(body.tokens()).into_iter().map(|(n, _)| n));
+        // Lifetimes aren't names, so we look for them in the text
+        let uses = |name: &str| match name.strip_prefix('\'') {
+            Some(_) => {
+                params
+                    .iter()
+                    .chain(captures)
+                    .any(|(_, ty)| ty.contains(name))
+                    || output.contains(name)
+            }
+            None => used.iter().any(|n| n == name),
+        };
+
+        let func = self.func.as_ref().map(|f| f.generics);
+        let mut decls = vec![];
+        let mut predicates = vec![];
+        for generics in self.impl_generics.into_iter().chain(func) {
+            for param in &generics.params {
+                let name = match param {
+                    syn::GenericParam::Lifetime(l) => l.lifetime.to_string(),
+                    syn::GenericParam::Type(t) => t.ident.to_string(),
+                    syn::GenericParam::Const(c) => c.ident.to_string(),
+                };
+                if uses(&name) {
+                    decls.push(self.text(param.span()).to_string());
+                }
+            }
+            for predicate in generics.where_clause.iter().flat_map(|w| &w.predicates) {
+                // `T: Trait` goes with `T`
+                let bounded = match predicate {
+                    syn::WherePredicate::Type(t) => self.text(t.bounded_ty.span()).to_string(),
+                    syn::WherePredicate::Lifetime(l) => l.lifetime.to_string(),
+                    _ => continue,
+                };
+                if uses(&bounded) {
+                    predicates.push(self.text(predicate.span()).to_string());
+                }
+            }
+        }
+        let decls = match decls.is_empty() {
+            true => String::new(),
+            false => format!("<{}>", decls.join(", ")),
+        };
+        let predicates = match predicates.is_empty() {
+            true => String::new(),
+            false => format!(" where {}", predicates.join(", ")),
+        };
+        (decls, predicates)
+    }
+}
+
+/// The arguments of every macro that takes expressions separated by
+/// commas, like `println!` and `vec!`, by where their delimiter is
+#[derive(Default)]
+struct MacroArgs(HashMap<usize, Vec<Expr>>);
+
+impl<'ast> Visit<'ast> for MacroArgs {
+    fn visit_macro(&mut self, mac: &'ast Macro) {
+        let parser = Punctuated::<Expr, Token![,]>::parse_terminated;
+        if let Ok(exprs) = parser.parse2(mac.tokens.clone()) {
+            let exprs: Vec<Expr> = exprs.into_iter().collect();
+            for expr in &exprs {
+                self.visit_expr(expr);
+            }
+            let at = mac.delimiter.span().open().byte_range().start;
+            self.0.insert(at, exprs);
+        }
+    }
+}
+
+impl<'a> Visit<'a> for Blocks<'a> {
+    fn visit_macro(&mut self, mac: &'a Macro) {
+        let at = mac.delimiter.span().open().byte_range().start;
+        if let Some(exprs) = self.macros.get(&at) {
+            for expr in exprs {
+                self.visit_expr(expr);
+            }
+        }
+    }
+
+    fn visit_item_fn(&mut self, f: &'a ItemFn) {
+        let outer = self.impl_generics.take();
+        self.func(&f.sig, &f.block);
+        self.impl_generics = outer;
+    }
+
+    fn visit_item_impl(&mut self, i: &'a ItemImpl) {
+        let outer = self.impl_generics.replace(&i.generics);
+        visit::visit_item_impl(self, i);
+        self.impl_generics = outer;
+    }
+
+    fn visit_impl_item_fn(&mut self, f: &'a ImplItemFn) {
+        self.func(&f.sig, &f.block);
+    }
+
+    fn visit_item_mod(&mut self, m: &'a ItemMod) {
+        let Some((brace, _)) = &m.content else {
+            return;
+        };
+        let outer = self.insert_at;
+        self.insert_at =
+            line_start_if_blank_before(self.src, brace.span.close().byte_range().start);
+        visit::visit_item_mod(self, m);
+        self.insert_at = outer;
+    }
+
+    fn visit_block(&mut self, b: &'a Block) {
+        let outer = self.scope.len();
+        visit::visit_block(self, b);
+        self.scope.truncate(outer);
+    }
+
+    fn visit_local(&mut self, local: &'a syn::Local) {
+        if let Some(init) = &local.init {
+            self.visit_expr(&init.expr);
+            if let Some((_, diverge)) = &init.diverge {
+                self.visit_expr(diverge);
+            }
+        }
+        let ty = self.local_type(local);
+        self.declare(&local.pat, ty, None);
+    }
+
+    fn visit_expr_for_loop(&mut self, expr: &'a syn::ExprForLoop) {
+        self.visit_expr(&expr.expr);
+        let outer = self.scope.len();
+        let item = self.item_type(&expr.expr);
+        self.declare(&expr.pat, item, None);
+        self.visit_block(&expr.body);
+        self.scope.truncate(outer);
+    }
+
+    fn visit_expr_if(&mut self, expr: &'a syn::ExprIf) {
+        // `if let` declares its variables in the first branch only
+        let outer = self.scope.len();
+        self.visit_expr(&expr.cond);
+        self.visit_block(&expr.then_branch);
+        self.scope.truncate(outer);
+        if let Some((_, els)) = &expr.else_branch {
+            self.visit_expr(els);
+        }
+    }
+
+    fn visit_expr_while(&mut self, expr: &'a syn::ExprWhile) {
+        let outer = self.scope.len();
+        self.visit_expr(&expr.cond);
+        self.visit_block(&expr.body);
+        self.scope.truncate(outer);
+    }
+
+    fn visit_expr_let(&mut self, expr: &'a syn::ExprLet) {
+        self.visit_expr(&expr.expr);
+        self.declare(&expr.pat, None, None);
+    }
+
+    fn visit_arm(&mut self, arm: &'a syn::Arm) {
+        let outer = self.scope.len();
+        self.declare(&arm.pat, None, None);
+        if let Some((_, guard)) = &arm.guard {
+            self.visit_expr(guard);
+        }
+        self.visit_expr(&arm.body);
+        self.scope.truncate(outer);
+    }
+
+    fn visit_expr_closure(&mut self, closure: &'a ExprClosure) {
+        if self.is_coroutine(closure.asyncness.as_ref()) {
+            let kw = closure
+                .asyncness
+                .as_ref()
+                .map(|kw| kw.span)
+                .unwrap_or_else(Span::call_site);
+            let body = match &*closure.body {
+                Expr::Block(b) if b.label.is_none() && b.attrs.is_empty() => Body::Block(&b.block),
+                body => Body::Expr(body),
+            };
+            self.coroutine(
+                closure.span(),
+                kw,
+                closure.capture.is_some(),
+                Some(closure),
+                body,
+            );
+            return;
+        }
+        let outer = self.scope.len();
+        for input in &closure.inputs {
+            self.declare(input, None, None);
+        }
+        self.visit_expr(&closure.body);
+        self.scope.truncate(outer);
+    }
+
+    fn visit_expr_async(&mut self, block: &'a ExprAsync) {
+        if self.is_coroutine(Some(&block.async_token)) {
+            let body = Body::Block(&block.block);
+            self.coroutine(
+                block.span(),
+                block.async_token.span,
+                block.capture.is_some(),
+                None,
+                body,
+            );
+            return;
+        }
+        visit::visit_expr_async(self, block);
+    }
+}
@@ -0,0 +1,1822 @@
+//! The control-flow graph of a coroutine body.
+//!
+//! Every wait point ends a block, and so does every branch and loop with a
+//! `.wait` somewhere inside it. Everything else stays exactly the way the
+//! user wrote it. The state machine gets one state for each wait point and
+//! one for each block we can get to from more than one place (like the
+//! start of a loop), which is what lets a state resume in the middle of a
+//! loop or a branch.
+//!
+//! The variables a state needs from an earlier state are kept in a `Stack`
+//! struct next to the state machine, just like we do by hand in chapter 9.
+//! We find them with a liveness analysis on the graph: a variable is saved
+//! when we change state if the next state uses it before it's assigned again.
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ops::Range;
+
+use proc_macro2::{Spacing, Span, TokenStream, TokenTree};
+use syn::{
+    punctuated::Punctuated,
+    spanned::Spanned,
+    visit::{self, Visit},
+    Expr, ExprForLoop, ExprIf, ExprMatch, Lit, Local, Pat, Stmt, Token, Type,
+};
+
+use crate::parse::{
+    ends_in_expression, line_end_if_blank_after, line_start_if_blank_before, lines, tried,
+    waited_on, Errors, Lift, StepChecker,
+};
+use crate::{Syntax, W_KW};
+
+pub(crate) type BlockId = usize;
+
+pub(crate) struct Cfg {
+    /// The body starts in the first block
+    pub(crate) blocks: Vec<Block>,
+    /// The futures we wait on. The first one is `Wait1` in the state machine.
+    pub(crate) waits: Vec<WaitPoint>,
+    /// Every variable declared in the coroutine, arguments first
+    pub(crate) vars: Vec<Var>,
+    /// The item type of each `for` loop iterator we keep in the stack
+    pub(crate) iters: Vec<String>,
+    /// Something in the stack points to something else in the stack, so
+    /// the coroutine can't move once it has started
+    pub(crate) pinned: bool,
+    /// Where the value we resolve to at the end of the body is, unless
+    /// it's one we made up
+    pub(crate) result_at: Option<Location>,
+}
+
+pub(crate) struct Block {
+    pub(crate) code: Vec<Code>,
+    /// The lines of the source the code of the block comes from, with
+    /// their line numbers
+    pub(crate) source: Vec<(usize, String)>,
+    pub(crate) term: Terminator,
+    /// The block we go back to at the end of each round of a loop
+    pub(crate) header: bool,
+    /// The variables the block uses before it assigns them
+    pub(crate) uses: Vec<usize>,
+    /// The variables the block declares
+    defs: Vec<usize>,
+    /// The variables we need from the blocks before this one
+    pub(crate) live: Vec<usize>,
+}
+
+/// The code of a block. Most of it is exactly what the user wrote, but
+/// variables that are borrowed across a wait point have to move into the
+/// stack, and borrowing them has to borrow them there.
+pub(crate) enum Code {
+    Text(String),
+    /// A `let` statement (or an argument, which has no `text`) declaring a
+    /// variable something might borrow. `mutability` is where `mut` is in
+    /// `text`, since we don't need it once the variable is in the stack.
+    Declared {
+        var: usize,
+        text: String,
+        indent: String,
+        mutability: Option<Range<usize>>,
+    },
+    /// `let r = &mut x;` or `let r = &x;`. `at` is where `x` starts in `text`.
+    Borrow {
+        var: usize,
+        text: String,
+        at: usize,
+    },
+}
+
+/// What happens at the end of a block
+pub(crate) enum Terminator {
+    Goto(BlockId),
+    /// Stores the iterator of a `for` loop in the stack before we start the loop
+    IntoIter {
+        iter: usize,
+        expr: String,
+        next: BlockId,
+        /// Where `expr` is in the source
+        at: Location,
+    },
+    /// Takes the next item from the iterator of a `for` loop
+    Next {
+        iter: usize,
+        pat: String,
+        body: BlockId,
+        exit: BlockId,
+    },
+    If {
+        cond: String,
+        then: BlockId,
+        els: BlockId,
+        at: Location,
+    },
+    Match {
+        expr: String,
+        arms: Vec<(String, BlockId)>,
+        at: Location,
+    },
+    /// Waits on `Cfg::waits[wait]` and continues in `next` when it's ready
+    Wait {
+        wait: usize,
+        next: BlockId,
+    },
+    /// Resolves to the value the body ends with
+    Resolve,
+    /// Resolves early to the value of a `return`, which is at `Location`
+    /// in the source
+    Return(String, Location),
+}
+
+impl Terminator {
+    pub(crate) fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Goto(next)
+            | Terminator::IntoIter { next, .. }
+            | Terminator::Wait { next, .. } => vec![*next],
+            Terminator::Next { body, exit, .. } => vec![*body, *exit],
+            Terminator::If { then, els, .. } => vec![*then, *els],
+            Terminator::Match { arms, .. } => arms.iter().map(|(_, b)| *b).collect(),
+            Terminator::Resolve | Terminator::Return(..) => vec![],
+        }
+    }
+}
+
+/// A future we wait on, like `let txt: String = Http::get(&path).wait;`
+pub(crate) struct WaitPoint {
+    /// The pattern we bind the output to, or `_` if we don't
+    pub(crate) pat: String,
+    /// The output of the future. Unless we're told otherwise we expect a `String`.
+    pub(crate) ty: String,
+    pub(crate) fut: String,
+    /// It's `fut.wait?`, so the future resolves to a `Result`, `pat` binds
+    /// what's in `Ok` and an `Err` is what the coroutine resolves to
+    pub(crate) tried: bool,
+    /// Where `fut` is in the source
+    pub(crate) at: Location,
+}
+
+/// A line and a column in the source, both counting from 1 like rustc does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Location {
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+}
+
+impl Location {
+    /// Where `pos` is in `src`
+    pub(crate) fn of(src: &str, pos: usize) -> Self {
+        let line_start = src[..pos].rfind('\n').map_or(0, |i| i + 1);
+        Location {
+            line: src[..pos].matches('\n').count() + 1,
+            column: src[line_start..pos].chars().count() + 1,
+        }
+    }
+}
+
+pub(crate) struct Var {
+    pub(crate) name: String,
+    /// The name of the field in the stack. It's only different from `name`
+    /// when we keep two variables with the same name.
+    pub(crate) field: String,
+    pub(crate) ty: Option<String>,
+    pub(crate) mutable: bool,
+    pub(crate) span: Span,
+    /// The variable this one borrows, and if it's a `&mut`
+    pub(crate) borrows: Option<(usize, bool)>,
+    /// It's declared somewhere we can move it into the stack right away
+    pinnable: bool,
+    pub(crate) keep: Keep,
+}
+
+/// How we keep a variable between states
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Keep {
+    /// It's never used after a wait point
+    No,
+    /// Moved into the stack when we change state and out of it when the
+    /// next state starts
+    Moved,
+    /// Something else borrows it, so it moves into the stack when it's
+    /// declared and stays there. States borrow it from there.
+    Pinned,
+    /// It borrows a pinned variable. The stack keeps a raw pointer to it.
+    Pointer,
+}
+
+/// Where a state starts running
+pub(crate) enum Resume {
+    Start,
+    /// After the future we waited on is ready. Counts from 1.
+    Wait(usize),
+    /// Any other block we can get to from more than one place, named after
+    /// what it is (`Loop1`, `Join1`...)
+    Jump(String),
+}
+
+pub(crate) struct State {
+    pub(crate) block: BlockId,
+    pub(crate) resume: Resume,
+}
+
+impl Cfg {
+    /// The states of the state machine in the order their code appears in
+    /// the coroutine. Blocks we can't get to don't count.
+    pub(crate) fn states(&self) -> Vec<State> {
+        let reachable = self.reachable();
+        let mut preds = vec![0; self.blocks.len()];
+        let mut resumed_by = HashMap::new();
+        for (_, block) in self
+            .blocks
+            .iter()
+            .enumerate()
+            .filter(|(b, _)| reachable[*b])
+        {
+            for next in block.term.successors() {
+                preds[next] += 1;
+            }
+            if let Terminator::Wait { wait, next } = block.term {
+                resumed_by.insert(next, wait + 1);
+            }
+        }
+
+        let (mut loops, mut joins) = (0, 0);
+        let mut states = vec![];
+        for (b, block) in self
+            .blocks
+            .iter()
+            .enumerate()
+            .filter(|(b, _)| reachable[*b])
+        {
+            let resume = if b == 0 {
+                Resume::Start
+            } else if let Some(n) = resumed_by.get(&b) {
+                Resume::Wait(*n)
+            } else if preds[b] > 1 && block.header {
+                loops += 1;
+                Resume::Jump(format!("Loop{loops}"))
+            } else if preds[b] > 1 {
+                joins += 1;
+                Resume::Jump(format!("Join{joins}"))
+            } else {
+                continue;
+            };
+            states.push(State { block: b, resume });
+        }
+        states
+    }
+
+    fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut todo = vec![0];
+        while let Some(b) = todo.pop() {
+            if !reachable[b] {
+                reachable[b] = true;
+                todo.extend(self.blocks[b].term.successors());
+            }
+        }
+        reachable
+    }
+
+    /// Finds the variables each block needs from the blocks before it
+    fn liveness(&mut self) {
+        let reachable = self.reachable();
+        let mut live = vec![BTreeSet::new(); self.blocks.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for b in (0..self.blocks.len()).rev().filter(|b| reachable[*b]) {
+                let block = &self.blocks[b];
+                let mut live_in: BTreeSet<usize> = block
+                    .term
+                    .successors()
+                    .iter()
+                    .flat_map(|next| live[*next].iter().copied())
+                    .collect();
+                for def in &block.defs {
+                    live_in.remove(def);
+                }
+                live_in.extend(&block.uses);
+                if live_in != live[b] {
+                    live[b] = live_in;
+                    changed = true;
+                }
+            }
+        }
+        for (block, live) in self.blocks.iter_mut().zip(live) {
+            block.live = live.into_iter().collect();
+        }
+    }
+
+    /// Decides how to keep the variables that live across a state change
+    fn keep_vars(&mut self, errors: &mut Errors) {
+        let states = self.states();
+        let kept: BTreeSet<usize> = states
+            .iter()
+            .filter(|s| !matches!(s.resume, Resume::Start))
+            .flat_map(|s| self.blocks[s.block].live.iter().copied())
+            .collect();
+
+        for &v in &kept {
+            match self.vars[v].borrows {
+                Some((target, _)) => {
+                    self.vars[v].keep = Keep::Pointer;
+                    self.vars[target].keep = Keep::Pinned;
+                    self.pinned = true;
+                }
+                None if self.vars[v].keep == Keep::No => self.vars[v].keep = Keep::Moved,
+                None => (),
+            }
+        }
+
+        let mut taken = HashSet::new();
+        for i in 0..self.vars.len() {
+            let var = &self.vars[i];
+            match var.keep {
+                Keep::No => continue,
+                // A pointer to a pinned variable has the type of what it
+                // points to, and we complain about that if we don't know it
+                Keep::Pointer => {
+                    let (target, mutable) = var.borrows.unwrap();
+                    let ptr = if mutable { "*mut" } else { "*const" };
+                    let ty = self.vars[target].ty.as_ref().map(|ty| format!("{ptr} {ty}"));
+                    self.vars[i].ty = ty;
+                }
+                Keep::Pinned if !var.pinnable => errors.push(syn::Error::new(
+                    var.span,
+                    format!("`{}` is borrowed across a wait point, so it has to stay in the same place in the stack. We can only do that for arguments and variables declared with `let {0} = ...;`", var.name),
+                )),
+                // An operand that has to run before a wait to its right
+                _ if var.ty.is_none() && var.name.starts_with(SPILL) => errors.push(syn::Error::new(
+                    var.span,
+                    "can't tell the type of this, which we need to keep it until the wait to its right is done, since it has to run first. Put it in a variable with a type before this statement, like `let x: Type = ...;`",
+                )),
+                _ if var.ty.is_none() => errors.push(syn::Error::new(
+                    var.span,
+                    format!("can't tell the type of `{0}`, which we need to keep it between wait points. Give it a type, like `let {0}: Type = ...;`", var.name),
+                )),
+                _ => (),
+            }
+            let var = &self.vars[i];
+            let mut field = var.name.clone();
+            let mut n = 1;
+            while !taken.insert(field.clone()) {
+                n += 1;
+                field = format!("{}{n}", var.name);
+            }
+            self.vars[i].field = field;
+        }
+    }
+}
+
+/// Lowers the body of a coroutine to a control-flow graph. Returns the
+/// graph and the expression the coroutine resolves to.
+pub(crate) fn lower<'a>(
+    src: &'a str,
+    syntax: &'a Syntax,
+    body: &'a syn::Block,
+    args: &[(String, String)],
+    types: &[(String, &'a Type)],
+    output: Option<&'a Type>,
+    output_ty: &str,
+) -> syn::Result<(Cfg, String)> {
+    let mut borrowed = Borrowed::default();
+    borrowed.visit_block(body);
+    let mut lowerer = Lowerer {
+        src,
+        syntax,
+        cfg: Cfg {
+            blocks: vec![],
+            waits: vec![],
+            vars: vec![],
+            iters: vec![],
+            pinned: false,
+            result_at: None,
+        },
+        cur: 0,
+        names: vec![],
+        borrowed: borrowed.0,
+        loops: vec![],
+        types: types.iter().map(|(name, ty)| (name.clone(), *ty)).collect(),
+        nothing: match output {
+            None => Some(syntax.default_output().1),
+            Some(Type::Tuple(unit)) if unit.elems.is_empty() => Some("()"),
+            Some(_) => None,
+        },
+        result: output.and_then(|ty| result_type(src, ty)),
+        results: vec![],
+        shadows: vec![],
+        hidden: HashMap::new(),
+        errors: Errors::default(),
+    };
+    lowerer.new_block();
+
+    let open = body.brace_token.span.open().byte_range();
+    let close = body.brace_token.span.close().byte_range();
+    let mut stmts = &body.stmts[..];
+    let mut end = close.start;
+
+    // The arguments are declared before the body starts
+    let indent = stmts
+        .first()
+        .map_or(String::from("    "), |s| lowerer.indent(s.span()));
+    for (name, ty) in args {
+        let var = lowerer.declare(name, Some(ty.clone()), false, body.brace_token.span.open());
+        if lowerer.borrowed.contains(name) {
+            lowerer.cfg.vars[var].pinnable = true;
+            lowerer.cfg.blocks[0].code.push(Code::Declared {
+                var,
+                text: String::new(),
+                indent: indent.clone(),
+                mutability: None,
+            });
+        }
+    }
+
+    // Without a return type we resolve to an empty `String` just like
+    // before we supported return types. With one, we only know what to
+    // resolve to once we've seen the last statement.
+    let mut result = lowerer.nothing.map(String::from).unwrap_or_default();
+
+    // Like in Rust, an `async fn` without a return type resolves to the
+    // `()` its body ends with
+    let ends_in_value = output.is_some() || syntax.is_async();
+    let mut waits_on_tail = false;
+    let mut tail_stmt = None;
+    if let Some((tail, rest)) = stmts.split_last() {
+        // A branch or loop at the end of an `async fn` without a return type
+        // is just the last statement
+        let is_value = match tail {
+            Stmt::Expr(_, None) if output.is_some() => true,
+            tail => ends_in_value && ends_in_expression(tail).is_some(),
+        };
+        match (tail, ends_in_value) {
+            // When we wait on the last expression, its output is what the
+            // coroutine resolves to
+            (Stmt::Expr(expr, None), true) if waited_on(expr).is_some() => {
+                waits_on_tail = true;
+                result = String::from("output");
+            }
+            (Stmt::Expr(expr, None), false) if waited_on(expr).is_some() => (),
+            _ if is_value => {
+                let splits = splits(tail);
+                let in_place = matches!(tail, Stmt::Expr(expr, _) if lowers_in_place(expr));
+                if in_place && splits.needs_lowering() || splits.breaks || splits.returns {
+                    lowerer.errors.push(syn::Error::new(
+                        tail.span(),
+                        format!("the value a coroutine resolves to can't be a branch or loop with `.{}` or `return` inside it, assign it to a variable first", syntax.wait()),
+                    ));
+                } else {
+                    // We lift the waits out of it once the rest of the body is lowered
+                    let range = tail.span().byte_range();
+                    lowerer.cfg.result_at = Some(lowerer.at(range.start));
+                    if let Stmt::Expr(expr, None) = tail {
+                        lowerer.results.push(expr);
+                    }
+                    tail_stmt = Some(tail);
+                    end = range.start;
+                    stmts = rest;
+                }
+            }
+            (_, false) => {
+                if let Some(span) = ends_in_expression(tail) {
+                    lowerer.errors.push(syn::Error::new(
+                        span,
+                        "a coroutine without a return type always resolves to `String::new()` so its body can't end in an expression, add a `;` after it or declare the return type",
+                    ));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    lowerer.lower_stmts(stmts, open.end, end);
+    if let Some(tail) = tail_stmt {
+        let mut checker = StepChecker::lifting(syntax);
+        checker.visit_stmt(tail);
+        lowerer.errors.extend(checker.errors);
+        result = lowerer.lift(tail.span().byte_range(), checker.lifts);
+    }
+    lowerer.uses(&result);
+    lowerer.terminate(Terminator::Resolve);
+
+    if waits_on_tail {
+        if let Some(wait) = lowerer.cfg.waits.last_mut() {
+            wait.pat = String::from("output");
+            wait.ty = output_ty.to_string();
+        }
+    }
+    if result.is_empty() {
+        lowerer.errors.push(syn::Error::new(
+            body.brace_token.span.close(),
+            "a coroutine with a return type must end in an expression with the value it resolves to",
+        ));
+    }
+
+    lowerer.cfg.liveness();
+    lowerer.cfg.keep_vars(&mut lowerer.errors);
+    lowerer.check_results();
+    lowerer.check_shadows();
+    lowerer.errors.finish()?;
+    Ok((lowerer.cfg, result))
+}
+
+struct Loop {
+    label: Option<String>,
+    /// Where `continue` goes
+    next: BlockId,
+    /// The blocks that end in a `break`. We don't know where the loop ends
+    /// until we've lowered all of it.
+    breaks: Vec<BlockId>,
+}
+
+struct Lowerer<'a> {
+    src: &'a str,
+    syntax: &'a Syntax,
+    cfg: Cfg,
+    /// The block we're adding code to
+    cur: BlockId,
+    /// The variables in scope right now, innermost last
+    names: Vec<(String, usize)>,
+    /// The names of the variables that are borrowed with `let r = &x;`
+    /// somewhere, which might have to move into the stack
+    borrowed: HashSet<String>,
+    loops: Vec<Loop>,
+    /// The types we know about, which is the types of the arguments
+    types: HashMap<String, &'a Type>,
+    /// What a coroutine resolves to when it ends without a value, if it can
+    nothing: Option<&'static str>,
+    /// The return type split around the `T` in `Result<T, E>`, if it's a `Result`
+    result: Option<(String, String)>,
+    /// The values the coroutine resolves to, at the end and in each `return`
+    results: Vec<&'a Expr>,
+    /// The variables declared in a block we split up that have the same
+    /// name as one outside of it, with that one, and if it's used after
+    /// the block
+    shadows: Vec<(usize, usize, bool)>,
+    /// The variables a block we split up declared another one of, which is
+    /// still in scope once we've lowered the block, with the shadow in
+    /// `shadows`
+    hidden: HashMap<usize, usize>,
+    errors: Errors,
+}
+
+impl<'a> Lowerer<'a> {
+    fn text(&self, span: Span) -> &'a str {
+        &self.src[span.byte_range()]
+    }
+
+    /// The whitespace in front of the line `span` starts on
+    fn indent(&self, span: Span) -> String {
+        let start = span.byte_range().start;
+        let line_start = self.src[..start].rfind('\n').map_or(0, |i| i + 1);
+        self.src[line_start..start]
+            .chars()
+            .take_while(|c| c.is_whitespace())
+            .collect()
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        self.cfg.blocks.push(Block {
+            code: vec![],
+            source: vec![],
+            term: Terminator::Resolve,
+            header: false,
+            uses: vec![],
+            defs: vec![],
+            live: vec![],
+        });
+        self.cfg.blocks.len() - 1
+    }
+
+    fn terminate(&mut self, term: Terminator) {
+        self.cfg.blocks[self.cur].term = term;
+    }
+
+    fn push_code(&mut self, start: usize, end: usize) {
+        self.push_source(start..end);
+        self.push_text(lines(&self.src[start..end]));
+    }
+
+    fn push_source(&mut self, range: Range<usize>) {
+        let Location { mut line, column } = self.at(range.start);
+        // Code that starts in the middle of a line keeps its column
+        let mut indent = column - 1;
+        for text in self.src[range].split('\n') {
+            if !text.trim().is_empty() {
+                let text = format!("{:indent$}{text}", "");
+                self.cfg.blocks[self.cur].source.push((line, text));
+            }
+            line += 1;
+            indent = 0;
+        }
+    }
+
+    fn at(&self, pos: usize) -> Location {
+        Location::of(self.src, pos)
+    }
+
+    fn push_text(&mut self, text: String) {
+        let code = &mut self.cfg.blocks[self.cur].code;
+        match code.last_mut() {
+            Some(Code::Text(last)) => last.push_str(&text),
+            _ => code.push(Code::Text(text)),
+        }
+    }
+
+    /// Looks for things we can't handle in code we don't split up
+    fn check(&mut self, visit: impl FnOnce(&mut StepChecker)) {
+        let mut checker = StepChecker::new(self.syntax);
+        visit(&mut checker);
+        self.errors.extend(checker.errors);
+    }
+
+    /// Waits on every `.wait` in `expr` before the rest of it runs, and
+    /// returns its code with the outputs in their place
+    fn lift_expr(&mut self, expr: &Expr) -> String {
+        let mut checker = StepChecker::lifting(self.syntax);
+        checker.visit_expr(expr);
+        self.errors.extend(checker.errors);
+        self.lift(expr.span().byte_range(), checker.lifts)
+    }
+
+    /// Waits on each of `lifts` in turn and returns the code in `range`
+    /// with a variable holding the output of each one in its place. The
+    /// variables are named after the state that waits, so `__wait2` is what
+    /// we got in `Wait2`. The operands that have to run before a wait go in
+    /// variables like `__arg1` first, so everything still runs in order.
+    fn lift(&mut self, range: Range<usize>, lifts: Vec<Lift>) -> String {
+        let mut names = vec![];
+        for lift in &lifts {
+            for spill in &lift.spills {
+                let at = spill.span().byte_range();
+                let code = replace_spans(self.src, at.clone(), &names);
+                let n = self.cfg.vars.iter().filter(|v| v.name.starts_with(SPILL)).count();
+                let name = format!("{SPILL}{}", n + 1);
+                // A method call on it needs it to be `mut`, like the temporary was
+                let mutable = self.src[at.end..].trim_start().starts_with('.');
+                let mutability = if mutable { "mut " } else { "" };
+                self.uses(&code);
+                let indent = self.indent(lift.span);
+                self.push_text(format!("{indent}let {mutability}{name} = {code};\n"));
+                let ty = self.expr_type(spill);
+                self.declare(&name, ty, mutable, spill.span());
+                names.push((at, name));
+            }
+            let fut = self.lift_expr(&lift.fut);
+            let name = format!("__{W_KW}{}", self.cfg.waits.len() + 1);
+            let wait = WaitPoint {
+                pat: name.clone(),
+                ty: String::from("String"),
+                fut,
+                tried: lift.tried,
+                at: self.at(lift.span.byte_range().start),
+            };
+            self.wait_on(wait, lift.span);
+            self.declare(&name, Some(String::from("String")), false, lift.span);
+            names.push((lift.span.byte_range(), name));
+        }
+        replace_spans(self.src, range, &names)
+    }
+
+    /// Records the variables `code` uses in the current block
+    fn uses(&mut self, code: &str) {
+        for name in idents(code) {
+            let Some(&(_, var)) = self.names.iter().rev().find(|(n, _)| *n == name) else {
+                continue;
+            };
+            if let Some(&shadow) = self.hidden.get(&var) {
+                self.shadows[shadow].2 = true;
+            }
+            let block = &mut self.cfg.blocks[self.cur];
+            if !block.defs.contains(&var) && !block.uses.contains(&var) {
+                block.uses.push(var);
+            }
+        }
+    }
+
+    /// Declares a variable in the current block
+    fn declare(&mut self, name: &str, ty: Option<String>, mutable: bool, span: Span) -> usize {
+        let var = self.cfg.vars.len();
+        self.cfg.vars.push(Var {
+            name: name.to_string(),
+            field: name.to_string(),
+            ty,
+            mutable,
+            span,
+            borrows: None,
+            pinnable: false,
+            keep: Keep::No,
+        });
+        self.names.push((name.to_string(), var));
+        self.cfg.blocks[self.cur].defs.push(var);
+        var
+    }
+
+    /// Declares the variables a pattern binds. `ty` is the type of the
+    /// whole pattern if we know it.
+    fn declare_pat(&mut self, pat: &Pat, ty: Option<String>) -> Option<usize> {
+        match pat {
+            Pat::Ident(p) if p.by_ref.is_none() && p.subpat.is_none() => {
+                let name = p.ident.to_string();
+                // `None` and other unit variants look just like variables
+                if name.starts_with(char::is_uppercase) {
+                    return None;
+                }
+                Some(self.declare(&name, ty, p.mutability.is_some(), p.ident.span()))
+            }
+            Pat::Type(p) => {
+                let ty = self.text(p.ty.span()).to_string();
+                self.declare_pat(&p.pat, Some(ty))
+            }
+            pat => {
+                let mut bindings = Bindings::default();
+                bindings.visit_pat(pat);
+                for (ident, mutable) in bindings.0 {
+                    self.declare(&ident.to_string(), None, mutable, ident.span());
+                }
+                None
+            }
+        }
+    }
+
+    /// Ends the scope of the variables declared since there were `outer`
+    /// of them. The blocks we split up aren't blocks in the state machine,
+    /// so the variables they declare stay in scope in the code we write.
+    fn leave_scope(&mut self, outer: usize) {
+        for i in outer..self.names.len() {
+            let (name, inner) = &self.names[i];
+            if let Some(&(_, hidden)) = self.names[..outer].iter().rev().find(|(n, _)| n == name) {
+                self.hidden.insert(hidden, self.shadows.len());
+                self.shadows.push((*inner, hidden, false));
+            }
+        }
+        self.names.truncate(outer);
+    }
+
+    /// A variable that another one in a block we split up hides can't be
+    /// kept or used after the block, since it would be the other one in the
+    /// code we write
+    fn check_shadows(&mut self) {
+        for &(inner, outer, used_after) in &self.shadows {
+            if used_after || self.cfg.vars[outer].keep != Keep::No {
+                let Var { name, span, .. } = &self.cfg.vars[inner];
+                self.errors.push(syn::Error::new(
+                    *span,
+                    format!("this `{name}` hides another `{name}` that's kept between wait points or used after this block. The blocks we split up at wait points and branches aren't blocks in the state machine, so both would be the same variable there. Give one of them another name"),
+                ));
+            }
+        }
+    }
+
+    fn lower_block(&mut self, block: &'a syn::Block) {
+        let open = block.brace_token.span.open().byte_range();
+        let close = block.brace_token.span.close().byte_range();
+        let outer = self.names.len();
+        self.lower_stmts(&block.stmts, open.end, close.start);
+        self.leave_scope(outer);
+    }
+
+    /// Adds the code between `start` and `end` to the current block and
+    /// splits it up wherever a statement waits
+    fn lower_stmts(&mut self, stmts: &'a [Stmt], start: usize, end: usize) {
+        let mut code_start = line_end_if_blank_after(self.src, start);
+        for stmt in stmts {
+            let range = stmt.span().byte_range();
+            let stmt_start = line_start_if_blank_before(self.src, range.start);
+            let stmt_end = line_end_if_blank_after(self.src, range.end);
+            if splits(stmt).needs_lowering() {
+                self.push_code(code_start, stmt_start);
+                self.lower_stmt(stmt, stmt_start..stmt_end);
+                code_start = stmt_end;
+                continue;
+            }
+
+            self.check(|c| c.visit_stmt(stmt));
+            self.uses(self.text(stmt.span()));
+            let Stmt::Local(local) = stmt else {
+                continue;
+            };
+            let ty = local
+                .init
+                .as_ref()
+                .and_then(|init| self.expr_type(&init.expr));
+            let Some(var) = self.declare_pat(&local.pat, ty) else {
+                continue;
+            };
+
+            // Variables that are borrowed might have to move into the stack
+            // when they're declared, and borrows might have to borrow them
+            // there. Those statements get a piece of code of their own.
+            let borrow = local.init.as_ref().and_then(|init| match &*init.expr {
+                Expr::Reference(r) => match &*r.expr {
+                    Expr::Path(path) => {
+                        let name = path.path.get_ident()?.to_string();
+                        let target = self.resolve(&name)?;
+                        Some((target, r.mutability.is_some(), r.expr.span()))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            });
+            let src = self.src;
+            let text = &src[stmt_start..stmt_end];
+            if let Some((target, mutable, target_span)) = borrow {
+                self.cfg.vars[var].borrows = Some((target, mutable));
+                self.push_code(code_start, stmt_start);
+                let at = target_span.byte_range().start - stmt_start;
+                let text = text.to_string();
+                self.push_source(range.clone());
+                self.cfg.blocks[self.cur]
+                    .code
+                    .push(Code::Borrow { var, text, at });
+                code_start = stmt_end;
+            } else if self.borrowed.contains(&self.cfg.vars[var].name) {
+                let mutability = self.mutability(local, stmt_start);
+                self.push_code(code_start, stmt_start);
+                self.cfg.vars[var].pinnable = true;
+                self.push_source(range.clone());
+                let code = Code::Declared {
+                    var,
+                    text: text.to_string(),
+                    indent: self.indent(stmt.span()),
+                    mutability,
+                };
+                self.cfg.blocks[self.cur].code.push(code);
+                code_start = stmt_end;
+            }
+        }
+        let end = line_start_if_blank_before(self.src, end).max(code_start);
+        self.push_code(code_start, end);
+    }
+
+    /// Where `mut` and the whitespace after it are in a `let` statement
+    /// that starts at `stmt_start`
+    fn mutability(&self, local: &Local, stmt_start: usize) -> Option<Range<usize>> {
+        let mutability = match &local.pat {
+            Pat::Ident(p) => p.mutability,
+            Pat::Type(p) => match &*p.pat {
+                Pat::Ident(p) => p.mutability,
+                _ => None,
+            },
+            _ => None,
+        };
+        mutability.map(|m| {
+            let m = m.span.byte_range();
+            let rest = self.src[m.end..].trim_start();
+            m.start - stmt_start..self.src.len() - rest.len() - stmt_start
+        })
+    }
+
+    fn resolve(&self, name: &str) -> Option<usize> {
+        self.names
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, var)| *var)
+    }
+
+    fn lower_stmt(&mut self, stmt: &'a Stmt, code: Range<usize>) {
+        match self.wait_point(stmt) {
+            Ok(Some((wait, pat))) => {
+                let ty = wait.ty.clone();
+                self.push_source(stmt.span().byte_range());
+                self.wait_on(wait, stmt.span());
+                if let Some(pat) = pat {
+                    self.declare_pat(pat, Some(ty));
+                }
+            }
+            Ok(None) => match stmt {
+                Stmt::Expr(expr, _) if lowers_in_place(expr) => self.lower_expr(expr),
+                stmt => self.lift_stmt(stmt, code),
+            },
+            Err(e) => self.errors.push(e),
+        }
+    }
+
+    /// Lowers a statement that waits somewhere in the middle, like
+    /// `println!("{}", fut.wait);`. `code` is all of the statement's lines.
+    fn lift_stmt(&mut self, stmt: &'a Stmt, code: Range<usize>) {
+        let mut checker = StepChecker::lifting(self.syntax);
+        checker.visit_stmt(stmt);
+        if checker.errors.count() == 0 && splits(stmt).breaks {
+            self.errors.push(syn::Error::new(
+                stmt.span(),
+                format!("`break`, `continue` and `return` can only leave a loop or branch with `.{}` in it from a statement", self.syntax.wait()),
+            ));
+        }
+        self.errors.extend(checker.errors);
+        let text = self.lift(code.clone(), checker.lifts);
+        self.uses(&text);
+        self.push_source(stmt.span().byte_range());
+
+        let var = match stmt {
+            Stmt::Local(local) => {
+                let ty = local
+                    .init
+                    .as_ref()
+                    .and_then(|init| self.expr_type(&init.expr));
+                self.declare_pat(&local.pat, ty)
+            }
+            _ => None,
+        };
+        match (var, stmt) {
+            (Some(var), Stmt::Local(local)) if self.borrowed.contains(&self.cfg.vars[var].name) => {
+                self.cfg.vars[var].pinnable = true;
+                let code = Code::Declared {
+                    var,
+                    mutability: self.mutability(local, code.start),
+                    text,
+                    indent: self.indent(stmt.span()),
+                };
+                self.cfg.blocks[self.cur].code.push(code);
+            }
+            _ => self.push_text(lines(&text)),
+        }
+    }
+
+    /// Waits on `wait` and continues in a new block
+    fn wait_on(&mut self, mut wait: WaitPoint, span: Span) {
+        self.uses(&wait.fut);
+        // The future resolves to the same kind of `Result` as the coroutine
+        if wait.tried {
+            match &self.result {
+                Some((before, after)) => wait.ty = format!("{before}{}{after}", wait.ty),
+                None => self.errors.push(syn::Error::new(
+                    span,
+                    format!("`.{}?` can only be used in a coroutine that returns a `Result`", self.syntax.wait()),
+                )),
+            }
+        }
+        self.wait(wait);
+    }
+
+    fn lower_expr(&mut self, expr: &'a Expr) {
+        match expr {
+            Expr::If(expr) => self.lower_if(expr),
+            Expr::Match(expr) => self.lower_match(expr),
+            Expr::ForLoop(expr) => self.lower_for(expr),
+            Expr::While(expr) => {
+                let header = self.new_block();
+                self.cfg.blocks[header].header = true;
+                self.terminate(Terminator::Goto(header));
+                self.cur = header;
+                // We wait on the condition again every round
+                let cond = self.lift_expr(&expr.cond);
+                self.uses(&cond);
+                let test = self.cur;
+                let body = self.new_block();
+                let outer = self.names.len();
+                if let Expr::Let(cond) = &*expr.cond {
+                    self.cur = body;
+                    self.declare_pat(&cond.pat, None);
+                }
+                let breaks = self.lower_loop(&expr.label, header, body, &expr.body);
+                self.leave_scope(outer);
+                let exit = self.new_block();
+                self.cfg.blocks[test].term = Terminator::If {
+                    cond,
+                    then: body,
+                    els: exit,
+                    at: self.at(expr.cond.span().byte_range().start),
+                };
+                self.break_to(breaks, exit);
+            }
+            Expr::Loop(expr) => {
+                let body = self.new_block();
+                self.cfg.blocks[body].header = true;
+                self.terminate(Terminator::Goto(body));
+                let breaks = self.lower_loop(&expr.label, body, body, &expr.body);
+                let exit = self.new_block();
+                self.break_to(breaks, exit);
+            }
+            Expr::Block(block) if block.label.is_none() => self.lower_block(&block.block),
+            Expr::Break(brk) if brk.expr.is_none() => {
+                self.jump(brk.label.as_ref(), brk.break_token.span, true)
+            }
+            Expr::Continue(cont) => self.jump(cont.label.as_ref(), cont.continue_token.span, false),
+            Expr::Return(ret) => self.ret(ret),
+            expr => {
+                let before = self.errors.count();
+                self.check(|c| c.visit_expr(expr));
+                // If it doesn't wait, it breaks out of a loop we split up
+                if self.errors.count() == before {
+                    self.errors.push(syn::Error::new(
+                        expr.span(),
+                        format!("`break`, `continue` and `return` can only leave a loop or branch with `.{}` in it from a statement", self.syntax.wait()),
+                    ));
+                }
+            }
+        }
+    }
+
+    fn lower_if(&mut self, expr: &'a ExprIf) {
+        let cond = self.lift_expr(&expr.cond);
+        self.uses(&cond);
+        let start = self.cur;
+        let then = self.new_block();
+        self.cur = then;
+        let outer = self.names.len();
+        if let Expr::Let(cond) = &*expr.cond {
+            self.declare_pat(&cond.pat, None);
+        }
+        self.lower_block(&expr.then_branch);
+        self.leave_scope(outer);
+        let mut ends = vec![self.cur];
+
+        let els = match &expr.else_branch {
+            Some((_, els)) => {
+                let block = self.new_block();
+                self.cur = block;
+                match &**els {
+                    Expr::If(els) => self.lower_if(els),
+                    Expr::Block(els) => self.lower_block(&els.block),
+                    els => self.lower_expr(els),
+                }
+                ends.push(self.cur);
+                Some(block)
+            }
+            None => None,
+        };
+
+        let join = self.new_block();
+        for end in ends {
+            self.cfg.blocks[end].term = Terminator::Goto(join);
+        }
+        self.cfg.blocks[start].term = Terminator::If {
+            cond,
+            then,
+            els: els.unwrap_or(join),
+            at: self.at(expr.cond.span().byte_range().start),
+        };
+        self.cur = join;
+    }
+
+    fn lower_match(&mut self, expr: &'a ExprMatch) {
+        let scrutinee = self.lift_expr(&expr.expr);
+        self.uses(&scrutinee);
+        let start = self.cur;
+        let mut arms = vec![];
+        let mut ends = vec![];
+        for arm in &expr.arms {
+            let pat_end = match &arm.guard {
+                Some((_, guard)) => {
+                    self.check(|c| c.visit_expr(guard));
+                    self.uses(self.text(guard.span()));
+                    guard.span().byte_range().end
+                }
+                None => arm.pat.span().byte_range().end,
+            };
+            let pat = &self.src[arm.pat.span().byte_range().start..pat_end];
+            let block = self.new_block();
+            arms.push((pat.to_string(), block));
+            self.cur = block;
+            let outer = self.names.len();
+            self.declare_pat(&arm.pat, None);
+            match &*arm.body {
+                Expr::Block(body) if body.label.is_none() => self.lower_block(&body.block),
+                body => self.lower_arm_expr(body),
+            }
+            self.leave_scope(outer);
+            ends.push(self.cur);
+            self.cur = start;
+        }
+
+        let join = self.new_block();
+        for end in ends {
+            self.cfg.blocks[end].term = Terminator::Goto(join);
+        }
+        self.cfg.blocks[start].term = Terminator::Match {
+            expr: scrutinee,
+            arms,
+            at: self.at(expr.expr.span().byte_range().start),
+        };
+        self.cur = join;
+    }
+
+    /// Match arms don't need braces, like `0 => fut.wait,`
+    fn lower_arm_expr(&mut self, expr: &'a Expr) {
+        let splits = splits_expr(expr);
+        if let Some(fut) = waited_on(expr) {
+            let fut = self.lift_expr(fut);
+            self.push_source(expr.span().byte_range());
+            self.wait_on(
+                WaitPoint {
+                    pat: String::from("_"),
+                    ty: String::from("String"),
+                    fut,
+                    tried: false,
+                    at: self.at(expr.span().byte_range().start),
+                },
+                expr.span(),
+            );
+        } else if lowers_in_place(expr) && splits.needs_lowering() || splits.breaks || splits.returns {
+            self.lower_expr(expr);
+        } else {
+            let text = self.lift_expr(expr);
+            self.uses(&text);
+            let indent = self.indent(expr.span());
+            let code = format!("{indent}    {text};\n");
+            self.push_source(expr.span().byte_range());
+            self.push_text(code);
+        }
+    }
+
+    fn lower_for(&mut self, expr: &'a ExprForLoop) {
+        // We keep the iterator in the stack between rounds, so we have to
+        // be able to name its type
+        let Some(item) = self.item_type(&expr.expr) else {
+            self.errors.push(syn::Error::new(
+                expr.expr.span(),
+                format!("can't tell the type of the items in this loop, and we need it to keep the iterator between `.{}`s. Loop over a range, an array or a `vec![]` with a suffixed or cast end or item, like `0..4usize` or `[1u8, 2]`, or over an argument with a type like `Vec<T>`", self.syntax.wait()),
+            ));
+            return;
+        };
+        let iterable = self.lift_expr(&expr.expr);
+        self.uses(&iterable);
+        let iter = self.cfg.iters.len();
+        self.cfg.iters.push(item.clone());
+
+        let header = self.new_block();
+        self.cfg.blocks[header].header = true;
+        self.terminate(Terminator::IntoIter {
+            iter,
+            expr: iterable,
+            next: header,
+            at: self.at(expr.expr.span().byte_range().start),
+        });
+
+        let body = self.new_block();
+        self.cur = body;
+        let outer = self.names.len();
+        self.declare_pat(&expr.pat, Some(item));
+        let breaks = self.lower_loop(&expr.label, header, body, &expr.body);
+        self.leave_scope(outer);
+
+        let exit = self.new_block();
+        self.cfg.blocks[header].term = Terminator::Next {
+            iter,
+            pat: self.text(expr.pat.span()).to_string(),
+            body,
+            exit,
+        };
+        self.break_to(breaks, exit);
+    }
+
+    /// Lowers the body of a loop and returns the blocks that break out of it
+    fn lower_loop(
+        &mut self,
+        label: &Option<syn::Label>,
+        header: BlockId,
+        body: BlockId,
+        block: &'a syn::Block,
+    ) -> Vec<BlockId> {
+        self.loops.push(Loop {
+            label: label.as_ref().map(|l| l.name.ident.to_string()),
+            next: header,
+            breaks: vec![],
+        });
+        self.cur = body;
+        self.lower_block(block);
+        self.terminate(Terminator::Goto(header));
+        self.loops.pop().map(|l| l.breaks).unwrap_or_default()
+    }
+
+    fn break_to(&mut self, breaks: Vec<BlockId>, exit: BlockId) {
+        for b in breaks {
+            self.cfg.blocks[b].term = Terminator::Goto(exit);
+        }
+        self.cur = exit;
+    }
+
+    fn jump(&mut self, label: Option<&syn::Lifetime>, span: Span, is_break: bool) {
+        let label = label.map(|l| l.ident.to_string());
+        let target = self
+            .loops
+            .iter_mut()
+            .rev()
+            .find(|l| label.is_none() || l.label == label);
+        let Some(target) = target else {
+            self.errors.push(syn::Error::new(
+                span,
+                format!("can't find the loop this leaves, only loops can be left from a branch with `.{}` in it", self.syntax.wait()),
+            ));
+            return;
+        };
+        if is_break {
+            target.breaks.push(self.cur);
+        } else {
+            let next = target.next;
+            self.terminate(Terminator::Goto(next));
+        }
+        // Anything after a `break` or `continue` never runs, so it goes in a
+        // block nobody jumps to
+        self.cur = self.new_block();
+    }
+
+    fn ret(&mut self, ret: &'a syn::ExprReturn) {
+        let value = match (&ret.expr, self.nothing) {
+            (Some(expr), _) => {
+                self.results.push(expr);
+                self.lift_expr(expr)
+            }
+            (None, Some(nothing)) => String::from(nothing),
+            (None, None) => {
+                self.errors.push(syn::Error::new(
+                    ret.return_token.span,
+                    "this coroutine has a return type, so `return` needs a value",
+                ));
+                String::new()
+            }
+        };
+        self.uses(&value);
+        self.push_source(ret.span().byte_range());
+        let at = self.at(ret.expr.as_ref().map_or(ret.span(), |e| e.span()).byte_range().start);
+        self.terminate(Terminator::Return(value, at));
+        // Anything after a `return` never runs
+        self.cur = self.new_block();
+    }
+
+    /// A pinned variable is a reference into the stack by the time we
+    /// resolve, so the state machine can only move it out of there when
+    /// it's the whole value. Calling its methods is fine too.
+    fn check_results(&mut self) {
+        let pinned: HashSet<&str> = self
+            .cfg
+            .vars
+            .iter()
+            .filter(|v| v.keep == Keep::Pinned)
+            .map(|v| v.name.as_str())
+            .collect();
+        for expr in &self.results {
+            if matches!(expr, Expr::Path(path) if path.path.get_ident().is_some()) {
+                continue;
+            }
+            let mut by_value = ByValue { names: &pinned, found: vec![] };
+            by_value.visit_expr(expr);
+            for ident in by_value.found {
+                self.errors.push(syn::Error::new(
+                    ident.span(),
+                    format!("`{ident}` is borrowed across a wait point, so it's a reference into the coroutine's stack here. A coroutine can resolve to `{ident}` itself or to what one of its methods returns, but not to anything else that uses it"),
+                ));
+            }
+        }
+    }
+
+    fn wait(&mut self, wait: WaitPoint) {
+        let n = self.cfg.waits.len();
+        self.cfg.waits.push(wait);
+        let next = self.new_block();
+        self.terminate(Terminator::Wait { wait: n, next });
+        self.cur = next;
+    }
+
+    /// Returns the wait point and the pattern it binds if `stmt` is
+    /// `let pat = fut.wait;`, `let pat: Type = fut.wait;` or `fut.wait;`,
+    /// or the same with `fut.wait?`
+    fn wait_point(&mut self, stmt: &'a Stmt) -> syn::Result<Option<(WaitPoint, Option<&'a Pat>)>> {
+        let (wait, pat) = match stmt {
+            Stmt::Local(local) => {
+                let Some(init) = &local.init else {
+                    return Ok(None);
+                };
+                let Some(fut) = waited_on(&init.expr).or_else(|| tried(&init.expr)) else {
+                    return Ok(None);
+                };
+                if let Some((else_token, _)) = &init.diverge {
+                    return Err(syn::Error::new(
+                        else_token.span,
+                        format!("`let ... else` can't be used together with `.{}`", self.syntax.wait()),
+                    ));
+                }
+                let (pat, ty) = match &local.pat {
+                    Pat::Type(pat) => (&*pat.pat, self.text(pat.ty.span())),
+                    pat => (pat, "String"),
+                };
+                // Everything between `=` and `.wait`
+                let fut_range = init.eq_token.span.byte_range().end..fut.span().byte_range().end;
+                let mut checker = StepChecker::lifting(self.syntax);
+                checker.visit_expr(fut);
+                self.errors.extend(checker.errors);
+                let fut = self.lift(fut_range, checker.lifts);
+                let wait = WaitPoint {
+                    pat: self.text(pat.span()).to_string(),
+                    ty: ty.to_string(),
+                    fut: fut.trim_start().to_string(),
+                    tried: tried(&init.expr).is_some(),
+                    at: self.at(init.expr.span().byte_range().start),
+                };
+                (wait, Some(pat))
+            }
+            Stmt::Expr(expr, _) => {
+                let Some(fut) = waited_on(expr).or_else(|| tried(expr)) else {
+                    return Ok(None);
+                };
+                let wait = WaitPoint {
+                    pat: String::from("_"),
+                    ty: String::from("String"),
+                    fut: self.lift_expr(fut),
+                    tried: tried(expr).is_some(),
+                    at: self.at(expr.span().byte_range().start),
+                };
+                (wait, None)
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some((wait, pat)))
+    }
+}
+
+/// Figures out types from the syntax alone. A coroutine knows the types of
+/// its arguments and of the variables it declared so far, and a block we
+/// turn into a coroutine knows those of the function it's in.
+pub(crate) trait Infer<'a> {
+    fn src(&self) -> &'a str;
+
+    /// The type of the variable `name` in scope, if we know it
+    fn var_type(&self, name: &str) -> Option<String>;
+
+    /// The type the variable `name` is declared with, like an argument's
+    fn declared(&self, name: &str) -> Option<&'a Type>;
+
+    fn text(&self, span: Span) -> &'a str {
+        &self.src()[span.byte_range()]
+    }
+
+    /// Figures out the type of the value of an expression from the syntax
+    /// alone, for the few kinds of expressions where that's easy
+    fn expr_type(&self, expr: &Expr) -> Option<String> {
+        match expr {
+            Expr::Lit(_) => literal_type(expr),
+            Expr::Paren(expr) => self.expr_type(&expr.expr),
+            Expr::Group(expr) => self.expr_type(&expr.expr),
+            Expr::Cast(cast) => Some(self.text(cast.ty.span()).to_string()),
+            Expr::Macro(mac) if mac.mac.path.is_ident("format") => Some(String::from("String")),
+            Expr::Macro(mac) if mac.mac.path.is_ident("vec") => {
+                let elems = mac
+                    .mac
+                    .parse_body_with(Punctuated::<Expr, Token![,]>::parse_terminated)
+                    .ok()?;
+                let item = elems.iter().find_map(|e| self.expr_type(e))?;
+                Some(format!("Vec<{item}>"))
+            }
+            // `String::new()`, `String::from(..)` and `Instant::now()`
+            Expr::Call(call) => {
+                let Expr::Path(func) = &*call.func else {
+                    return None;
+                };
+                let segments = &func.path.segments;
+                let (ty, f) = match segments.len() {
+                    2 => (&segments[0].ident, &segments[1].ident),
+                    _ => return None,
+                };
+                if ty == "String" && (f == "new" || f == "from" || f == "with_capacity") {
+                    Some(String::from("String"))
+                } else if f == "now" {
+                    Some(ty.to_string())
+                } else {
+                    None
+                }
+            }
+            Expr::MethodCall(call) => {
+                let method = call.method.to_string();
+                match method.as_str() {
+                    "to_string" => Some(String::from("String")),
+                    "len" => Some(String::from("usize")),
+                    "clone" => self.expr_type(&call.receiver),
+                    "to_owned" => match self.expr_type(&call.receiver)?.as_str() {
+                        "&'static str" | "String" => Some(String::from("String")),
+                        _ => None,
+                    },
+                    _ => None,
+                }
+            }
+            Expr::Path(path) => self.var_type(&path.path.get_ident()?.to_string()),
+            Expr::Unary(unary) if !matches!(unary.op, syn::UnOp::Deref(_)) => {
+                self.expr_type(&unary.expr)
+            }
+            Expr::Binary(binary) => {
+                use syn::BinOp::*;
+                match binary.op {
+                    Eq(_) | Lt(_) | Le(_) | Ne(_) | Ge(_) | Gt(_) | And(_) | Or(_) => {
+                        Some(String::from("bool"))
+                    }
+                    Add(_) | Sub(_) | Mul(_) | Div(_) | Rem(_) | BitXor(_) | BitAnd(_)
+                    | BitOr(_) | Shl(_) | Shr(_) => {
+                        let [left, right] =
+                            [&binary.left, &binary.right].map(|e| self.expr_type(e));
+                        left.filter(|ty| ty != "&'static str").or(right)
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Figures out the item type of a `for` loop from the syntax alone
+    fn item_type(&self, expr: &Expr) -> Option<String> {
+        match expr {
+            Expr::Paren(expr) => self.item_type(&expr.expr),
+            Expr::Group(expr) => self.item_type(&expr.expr),
+            Expr::Range(range) => {
+                let ends = [&range.start, &range.end];
+                let ends = ends.iter().filter_map(|e| e.as_deref());
+                // An unsuffixed `0` could be any integer, so one of the ends
+                // has to say which
+                for end in ends {
+                    match end {
+                        Expr::Lit(lit) => match &lit.lit {
+                            Lit::Int(int) if !int.suffix().is_empty() => {
+                                return Some(int.suffix().to_string())
+                            }
+                            Lit::Int(_) => (),
+                            _ => return None,
+                        },
+                        Expr::Cast(cast) => return Some(self.text(cast.ty.span()).to_string()),
+                        Expr::Path(path) => {
+                            let Some(name) = path.path.get_ident().map(|i| i.to_string()) else {
+                                continue;
+                            };
+                            if let Some(ty) = self.var_type(&name) {
+                                return Some(ty);
+                            }
+                            if let Some(ty) = self.declared(&name) {
+                                return Some(self.text(ty.span()).to_string());
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+                None
+            }
+            // Cloning a collection or turning it into an iterator doesn't change the items
+            Expr::MethodCall(call)
+                if call.args.is_empty()
+                    && (call.method == "clone" || call.method == "into_iter") =>
+            {
+                self.item_type(&call.receiver)
+            }
+            Expr::Array(array) => array.elems.iter().find_map(|e| self.expr_type(e)),
+            Expr::Macro(mac) if mac.mac.path.is_ident("vec") => {
+                let elems = mac
+                    .mac
+                    .parse_body_with(Punctuated::<Expr, Token![,]>::parse_terminated)
+                    .ok()?;
+                elems.iter().find_map(|e| self.expr_type(e))
+            }
+            Expr::Path(path) => {
+                let ty = self.declared(&path.path.get_ident()?.to_string())?;
+                self.element_type(ty)
+            }
+            _ => None,
+        }
+    }
+
+    /// The `T` in `Vec<T>`, `[T; N]` and the other collections we know
+    fn element_type(&self, ty: &Type) -> Option<String> {
+        match ty {
+            Type::Array(array) => Some(self.text(array.elem.span()).to_string()),
+            Type::Path(path) => {
+                let last = path.path.segments.last()?;
+                let collections = ["Vec", "VecDeque", "LinkedList", "HashSet", "BTreeSet"];
+                if !collections.iter().any(|c| last.ident == c) {
+                    return None;
+                }
+                match &last.arguments {
+                    syn::PathArguments::AngleBracketed(args) if args.args.len() == 1 => {
+                        Some(self.text(args.args.span()).to_string())
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<'a> Infer<'a> for Lowerer<'a> {
+    fn src(&self) -> &'a str {
+        self.src
+    }
+
+    fn var_type(&self, name: &str) -> Option<String> {
+        let var = self.resolve(name)?;
+        self.cfg.vars[var].ty.clone()
+    }
+
+    fn declared(&self, name: &str) -> Option<&'a Type> {
+        self.types.get(name).copied()
+    }
+}
+
+/// What we call the variables that keep operands we have to run before a wait
+const SPILL: &str = "__arg";
+
+/// The code in `range` with each of the outermost `spans` in it replaced by
+/// the name that goes with it
+fn replace_spans(src: &str, range: Range<usize>, spans: &[(Range<usize>, String)]) -> String {
+    let mut inside: Vec<_> = spans
+        .iter()
+        .filter(|(at, _)| range.start <= at.start && at.end <= range.end)
+        .collect();
+    inside.sort_by_key(|(at, _)| (at.start, Reverse(at.end)));
+    let mut code = String::new();
+    let mut pos = range.start;
+    for (at, name) in inside {
+        if at.start >= pos {
+            code.push_str(&src[pos..at.start]);
+            code.push_str(name);
+            pos = at.end;
+        }
+    }
+    code.push_str(&src[pos..range.end]);
+    code
+}
+
+/// Splits `Result<T, E>` (or `io::Result<T>`) into what's before and
+/// after the `T`
+fn result_type(src: &str, ty: &Type) -> Option<(String, String)> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let last = path.path.segments.last()?;
+    let syn::PathArguments::AngleBracketed(args) = &last.arguments else {
+        return None;
+    };
+    let first = args.args.first()?;
+    if last.ident != "Result" || !matches!(first, syn::GenericArgument::Type(_)) {
+        return None;
+    }
+    let ty = ty.span().byte_range();
+    let first = first.span().byte_range();
+    Some((
+        src[ty.start..first.start].to_string(),
+        src[first.end..ty.end].to_string(),
+    ))
+}
+
+fn literal_type(expr: &Expr) -> Option<String> {
+    let Expr::Lit(lit) = expr else {
+        return None;
+    };
+    let ty = match &lit.lit {
+        Lit::Str(_) => "&'static str",
+        // Rust might infer another type for `0` or `0.0` than the
+        // defaults, so we'd rather ask
+        Lit::Int(int) if !int.suffix().is_empty() => int.suffix(),
+        Lit::Float(float) if !float.suffix().is_empty() => float.suffix(),
+        Lit::Bool(_) => "bool",
+        Lit::Char(_) => "char",
+        _ => return None,
+    };
+    Some(ty.to_string())
+}
+
+/// The names the code uses, also inside macros and format strings. Fields
+/// and methods aren't variables, so we skip names right after a `.`.
+fn idents(code: &str) -> Vec<String> {
+    match code.parse() {
+        Ok(tokens) => names_in(tokens).into_iter().map(|(name, _)| name).collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Same as `idents`, with where each name is. A name in a format string is
+/// where the string is.
+pub(crate) fn names_in(tokens: TokenStream) -> Vec<(String, Span)> {
+    fn scan(tokens: TokenStream, out: &mut Vec<(String, Span)>) {
+        // The name after a `.` is a field or a method, but the one after
+        // the `..` of a range isn't
+        let mut prev_dot = false;
+        let mut prev_joint_dot = false;
+        for tt in tokens {
+            match &tt {
+                TokenTree::Ident(ident) if !prev_dot => out.push((ident.to_string(), ident.span())),
+                TokenTree::Group(group) => scan(group.stream(), out),
+                // Captured by a format string, like `{name}` or `{name:?}`
+                TokenTree::Literal(lit) => {
+                    let text = lit.to_string();
+                    for part in text.split('{').skip(1) {
+                        let end = part
+                            .find(|c: char| !c.is_alphanumeric() && c != '_')
+                            .unwrap_or(part.len());
+                        if end > 0 && part[end..].starts_with(['}', ':']) {
+                            out.push((part[..end].to_string(), lit.span()));
+                        }
+                    }
+                }
+                _ => (),
+            }
+            let dot = matches!(&tt, TokenTree::Punct(p) if p.as_char() == '.');
+            prev_dot = dot && !prev_joint_dot;
+            prev_joint_dot = matches!(&tt, TokenTree::Punct(p) if p.as_char() == '.' && p.spacing() == Spacing::Joint);
+        }
+    }
+    let mut out = vec![];
+    scan(tokens, &mut out);
+    out
+}
+
+/// The variables a pattern binds, and if they're `mut`
+#[derive(Default)]
+pub(crate) struct Bindings(pub(crate) Vec<(syn::Ident, bool)>);
+
+impl<'ast> Visit<'ast> for Bindings {
+    fn visit_pat_ident(&mut self, pat: &'ast syn::PatIdent) {
+        // `None` and other unit variants are parsed as identifiers too
+        if !pat.ident.to_string().starts_with(char::is_uppercase) {
+            self.0.push((pat.ident.clone(), pat.mutability.is_some()));
+        }
+        visit::visit_pat_ident(self, pat);
+    }
+}
+
+/// The names of the variables borrowed by `let r = &x;` or `let r = &mut x;`
+#[derive(Default)]
+struct Borrowed(HashSet<String>);
+
+impl<'ast> Visit<'ast> for Borrowed {
+    fn visit_local(&mut self, local: &'ast Local) {
+        if let Some(init) = &local.init {
+            if let Expr::Reference(r) = &*init.expr {
+                if let Expr::Path(path) = &*r.expr {
+                    if let Some(ident) = path.path.get_ident() {
+                        self.0.insert(ident.to_string());
+                    }
+                }
+            }
+        }
+        visit::visit_local(self, local);
+    }
+
+    fn visit_item(&mut self, _: &'ast syn::Item) {}
+}
+
+/// The variables called one of `names` that an expression uses by value,
+/// and not through a method call, a field or a reference
+struct ByValue<'n> {
+    names: &'n HashSet<&'n str>,
+    found: Vec<syn::Ident>,
+}
+
+impl<'ast> Visit<'ast> for ByValue<'_> {
+    fn visit_expr(&mut self, expr: &'ast Expr) {
+        match expr {
+            Expr::Path(path) => {
+                if let Some(ident) = path.path.get_ident() {
+                    if self.names.contains(ident.to_string().as_str()) {
+                        self.found.push(ident.clone());
+                    }
+                }
+            }
+            Expr::MethodCall(call) if matches!(&*call.receiver, Expr::Path(_)) => {
+                call.args.iter().for_each(|arg| self.visit_expr(arg));
+            }
+            Expr::Field(field) if matches!(&*field.base, Expr::Path(_)) => (),
+            Expr::Reference(r) if matches!(&*r.expr, Expr::Path(_)) => (),
+            expr => visit::visit_expr(self, expr),
+        }
+    }
+
+    fn visit_expr_closure(&mut self, _: &'ast syn::ExprClosure) {}
+    fn visit_item(&mut self, _: &'ast syn::Item) {}
+}
+
+/// Finds out why a statement has to be split up into blocks
+/// The expressions we split up into blocks of their own when they wait,
+/// instead of lifting the waits out of them
+fn lowers_in_place(expr: &Expr) -> bool {
+    match expr {
+        Expr::If(_)
+        | Expr::Match(_)
+        | Expr::ForLoop(_)
+        | Expr::While(_)
+        | Expr::Loop(_)
+        | Expr::Continue(_)
+        | Expr::Return(_) => true,
+        Expr::Block(block) => block.label.is_none(),
+        Expr::Break(brk) => brk.expr.is_none(),
+        _ => false,
+    }
+}
+
+#[derive(Default)]
+struct Splits {
+    waits: bool,
+    /// It has a `return`, which has to go through the state machine
+    returns: bool,
+    /// It has a `break` or a `continue` for a loop outside of it
+    breaks: bool,
+    loops: usize,
+    labels: Vec<String>,
+}
+
+impl Splits {
+    fn needs_lowering(&self) -> bool {
+        self.waits || self.breaks || self.returns
+    }
+
+    fn leaves(&mut self, label: &Option<syn::Lifetime>) {
+        match label {
+            Some(label) if !self.labels.contains(&label.ident.to_string()) => self.breaks = true,
+            None if self.loops == 0 => self.breaks = true,
+            _ => (),
+        }
+    }
+
+    fn in_loop(&mut self, label: &Option<syn::Label>, visit: impl FnOnce(&mut Self)) {
+        self.loops += 1;
+        if let Some(label) = label {
+            self.labels.push(label.name.ident.to_string());
+        }
+        visit(self);
+        if label.is_some() {
+            self.labels.pop();
+        }
+        self.loops -= 1;
+    }
+}
+
+fn splits(stmt: &Stmt) -> Splits {
+    let mut splits = Splits::default();
+    splits.visit_stmt(stmt);
+    splits
+}
+
+fn splits_expr(expr: &Expr) -> Splits {
+    let mut splits = Splits::default();
+    splits.visit_expr(expr);
+    splits
+}
+
+impl<'ast> Visit<'ast> for Splits {
+    fn visit_expr_field(&mut self, field: &'ast syn::ExprField) {
+        if matches!(&field.member, syn::Member::Named(ident) if ident == W_KW) {
+            self.waits = true;
+        }
+        visit::visit_expr_field(self, field);
+    }
+
+    fn visit_macro(&mut self, mac: &'ast syn::Macro) {
+        fn scan(tokens: TokenStream) -> bool {
+            let mut prev_dot = false;
+            for tt in tokens {
+                match &tt {
+                    TokenTree::Ident(ident) if prev_dot && ident == W_KW => return true,
+                    TokenTree::Group(g) if scan(g.stream()) => return true,
+                    _ => (),
+                }
+                prev_dot = matches!(&tt, TokenTree::Punct(p) if p.as_char() == '.');
+            }
+            false
+        }
+        self.waits |= scan(mac.tokens.clone());
+    }
+
+    fn visit_expr_break(&mut self, brk: &'ast syn::ExprBreak) {
+        self.leaves(&brk.label);
+        visit::visit_expr_break(self, brk);
+    }
+
+    fn visit_expr_continue(&mut self, cont: &'ast syn::ExprContinue) {
+        self.leaves(&cont.label);
+    }
+
+    fn visit_expr_return(&mut self, ret: &'ast syn::ExprReturn) {
+        self.returns = true;
+        visit::visit_expr_return(self, ret);
+    }
+
+    fn visit_expr_for_loop(&mut self, expr: &'ast syn::ExprForLoop) {
+        self.visit_expr(&expr.expr);
+        self.in_loop(&expr.label, |s| s.visit_block(&expr.body));
+    }
+
+    fn visit_expr_while(&mut self, expr: &'ast syn::ExprWhile) {
+        self.visit_expr(&expr.cond);
+        self.in_loop(&expr.label, |s| s.visit_block(&expr.body));
+    }
+
+    fn visit_expr_loop(&mut self, expr: &'ast syn::ExprLoop) {
+        self.in_loop(&expr.label, |s| s.visit_block(&expr.body));
+    }
+
+    fn visit_expr_block(&mut self, expr: &'ast syn::ExprBlock) {
+        // A labeled block can be left with `break 'label`, but a plain
+        // `break` inside it still leaves the loop around it
+        match &expr.label {
+            Some(label) => {
+                self.labels.push(label.name.ident.to_string());
+                visit::visit_expr_block(self, expr);
+                self.labels.pop();
+            }
+            None => visit::visit_expr_block(self, expr),
+        }
+    }
+
+    // Waits in closures and async blocks are reported as misplaced when we
+    // check the statement, and they can't leave our loops
+    fn visit_expr_closure(&mut self, _: &'ast syn::ExprClosure) {}
+    fn visit_expr_async(&mut self, _: &'ast syn::ExprAsync) {}
+    fn visit_item(&mut self, _: &'ast syn::Item) {}
+}
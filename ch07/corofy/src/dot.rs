@@ -0,0 +1,147 @@
+//! Draws the state machine of a coroutine as a Graphviz graph.
+//!
+//! Every state is a node, and every way to get from one state to the next
+//! is an edge labeled with the branches it takes and the lines of the
+//! coroutine it runs on the way. It's the same walk through the
+//! control-flow graph we do when we write `poll`, so the graph shows
+//! exactly what the state machine does.
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::cfg::{BlockId, Resume, Terminator};
+use crate::parse::CoroutineFn;
+
+/// Returns the graph of `coroutine`
+pub(crate) fn graph(coroutine: &CoroutineFn) -> String {
+    let cfg = &coroutine.cfg;
+    let states = cfg.states();
+    let names: HashMap<BlockId, String> = states
+        .iter()
+        .map(|s| {
+            let name = match &s.resume {
+                Resume::Start => String::from("Start"),
+                Resume::Wait(n) => format!("Wait{n}"),
+                Resume::Jump(name) => name.clone(),
+            };
+            (s.block, name)
+        })
+        .collect();
+
+    let mut out = format!(
+        "digraph {} {{
+    node [shape=box, style=rounded, fontname=\"monospace\"];
+    edge [fontname=\"monospace\"];
+",
+        quote(&coroutine.name)
+    );
+
+    let args: Vec<String> = coroutine
+        .args
+        .iter()
+        .map(|(name, ty)| format!("{name}: {ty}"))
+        .collect();
+    writeln!(out, "    Start [label={}];", quote(&format!("Start({})", args.join(", ")))).unwrap();
+    for (n, wait) in cfg.waits.iter().enumerate() {
+        let n = n + 1;
+        let label = format!("Wait{n}\n{}\n-> {}", wait.fut.trim(), wait.ty);
+        writeln!(out, "    Wait{n} [label={}];", quote(&label)).unwrap();
+    }
+    writeln!(out, "    Resolved [shape=doublecircle, style=solid];").unwrap();
+
+    let mut edges = Edges {
+        coroutine,
+        names: &names,
+        out: &mut out,
+    };
+    for state in &states {
+        let from = &names[&state.block];
+        let mut label = vec![];
+        if let Resume::Wait(n) = state.resume {
+            let wait = &cfg.waits[n - 1];
+            if wait.tried {
+                let err = [String::from("Ready(Err(e))"), String::from("return Err(From::from(e))")];
+                edges.edge(from, "Resolved", &err);
+                label.push(format!("Ready(Ok({}))", wait.pat));
+            } else {
+                label.push(format!("Ready({})", wait.pat));
+            }
+            writeln!(edges.out, "    {from} -> {from} [label=\"NotReady\", style=dashed];").unwrap();
+        }
+        edges.walk(from, state.block, label, true);
+    }
+
+    out.push_str("}\n");
+    out
+}
+
+struct Edges<'a> {
+    coroutine: &'a CoroutineFn,
+    /// The states, by the block they start in
+    names: &'a HashMap<BlockId, String>,
+    out: &'a mut String,
+}
+
+impl Edges<'_> {
+    /// Follows the blocks we run in place from `block` until we get to
+    /// another state, and draws an edge there with everything we ran
+    fn walk(&mut self, from: &str, block: BlockId, mut label: Vec<String>, first: bool) {
+        if !first {
+            if let Some(to) = self.names.get(&block) {
+                return self.edge(from, to, &label);
+            }
+        }
+        let cfg = &self.coroutine.cfg;
+        let b = &cfg.blocks[block];
+        for (line, text) in &b.source {
+            label.push(format!("{line}: {}", text.trim()));
+        }
+
+        match &b.term {
+            Terminator::Goto(next) => self.walk(from, *next, label, false),
+            Terminator::IntoIter { expr, next, .. } => {
+                label.push(format!("[for .. in {expr}]"));
+                self.walk(from, *next, label, false)
+            }
+            Terminator::Next { pat, body, exit, .. } => {
+                self.branch(from, &label, &format!("Some({pat})"), *body);
+                self.branch(from, &label, "None", *exit);
+            }
+            Terminator::If { cond, then, els, .. } => {
+                self.branch(from, &label, &format!("if {cond}"), *then);
+                self.branch(from, &label, "else", *els);
+            }
+            Terminator::Match { expr, arms, .. } => {
+                for (pat, arm) in arms {
+                    self.branch(from, &label, &format!("match {expr}: {pat}"), *arm);
+                }
+            }
+            // The wait and the `return` are the last lines we ran
+            Terminator::Wait { wait, .. } => self.edge(from, &format!("Wait{}", wait + 1), &label),
+            Terminator::Resolve => {
+                label.push(format!("resolve to {}", self.coroutine.result));
+                self.edge(from, "Resolved", &label)
+            }
+            Terminator::Return(..) => self.edge(from, "Resolved", &label),
+        }
+    }
+
+    fn branch(&mut self, from: &str, label: &[String], cond: &str, next: BlockId) {
+        let mut label = label.to_vec();
+        label.push(format!("[{cond}]"));
+        self.walk(from, next, label, false);
+    }
+
+    fn edge(&mut self, from: &str, to: &str, label: &[String]) {
+        // `\l` ends a line and aligns it to the left
+        let label: String = label.iter().map(|line| format!("{}\\l", escape(line))).collect();
+        writeln!(self.out, "    {from} -> {to} [label=\"{label}\"];").unwrap();
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", escape(s))
+}
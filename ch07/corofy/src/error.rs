@@ -0,0 +1,177 @@
+//! The errors corofy reports. Problems with the code we're asked to rewrite
+//! are rendered the way rustc renders its errors, with the line they're on
+//! and the offending code underlined.
+use std::fmt::Display;
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+
+use crate::Syntax;
+
+#[derive(Debug)]
+pub enum CorofyError {
+    /// Code we can't transform. We look for every problem before we give
+    /// up, so there's at least one of them.
+    Unsupported(Vec<Diagnostic>),
+    /// There's no `coroutine fn` in the input. `keyword` is what we looked
+    /// for in front of `fn`.
+    NoCoroutine {
+        keyword: String,
+        file: Option<PathBuf>,
+    },
+    /// The command line doesn't make sense
+    Usage(String),
+    /// We couldn't read the input or write the output
+    Io {
+        file: Option<PathBuf>,
+        error: io::Error,
+    },
+}
+
+impl CorofyError {
+    pub(crate) fn no_coroutine(syntax: &Syntax) -> Self {
+        CorofyError::NoCoroutine {
+            keyword: syntax.coroutine().to_string(),
+            file: None,
+        }
+    }
+
+    /// Tells the error which file it's about, so we can point at it
+    pub fn with_file(self, path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        match self {
+            CorofyError::Unsupported(diagnostics) => CorofyError::Unsupported(
+                diagnostics
+                    .into_iter()
+                    .map(|d| Diagnostic {
+                        file: Some(path.clone()),
+                        ..d
+                    })
+                    .collect(),
+            ),
+            CorofyError::NoCoroutine { keyword, .. } => CorofyError::NoCoroutine {
+                keyword,
+                file: Some(path),
+            },
+            CorofyError::Usage(msg) => CorofyError::Usage(msg),
+            CorofyError::Io { error, .. } => CorofyError::Io {
+                file: Some(path),
+                error,
+            },
+        }
+    }
+
+    /// The problems in the code, if that's what went wrong
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        match self {
+            CorofyError::Unsupported(diagnostics) => diagnostics,
+            _ => &[],
+        }
+    }
+}
+
+impl Display for CorofyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CorofyError::Unsupported(diagnostics) => {
+                for (i, d) in diagnostics.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "\n\n")?;
+                    }
+                    write!(f, "{d}")?;
+                }
+                Ok(())
+            }
+            CorofyError::NoCoroutine { keyword, file: None } => {
+                write!(f, "No `{keyword}` function found.")
+            }
+            CorofyError::NoCoroutine {
+                keyword,
+                file: Some(file),
+            } => write!(f, "No `{keyword}` function found in {}.", file.display()),
+            CorofyError::Usage(msg) => write!(f, "error: {msg}"),
+            CorofyError::Io { file: None, error } => write!(f, "error: {error}"),
+            CorofyError::Io {
+                file: Some(file),
+                error,
+            } => write!(f, "error: {}: {error}", file.display()),
+        }
+    }
+}
+
+impl std::error::Error for CorofyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CorofyError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CorofyError {
+    fn from(error: io::Error) -> Self {
+        CorofyError::Io { file: None, error }
+    }
+}
+
+/// Points at the code in the input that we can't transform and explains why
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub file: Option<PathBuf>,
+    /// Byte range in the input
+    pub range: Range<usize>,
+    pub line: usize,
+    /// Starts at 1 just like `line`, and counts characters, not bytes
+    pub column: usize,
+    pub message: String,
+    /// The whole line the problem starts on
+    pub source_line: String,
+}
+
+impl Diagnostic {
+    pub(crate) fn new(src: &str, range: Range<usize>, message: String) -> Self {
+        let line_start = src[..range.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = src[range.start..]
+            .find('\n')
+            .map_or(src.len(), |i| range.start + i);
+        Diagnostic {
+            file: None,
+            line: src[..line_start].matches('\n').count() + 1,
+            column: src[line_start..range.start].chars().count() + 1,
+            range,
+            message,
+            source_line: src[line_start..line_end].trim_end_matches('\r').to_string(),
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let line = self.line;
+        let pad = " ".repeat(line.to_string().len());
+        writeln!(f, "error: {}", self.message)?;
+        match &self.file {
+            Some(file) => writeln!(f, "{pad}--> {}:{line}:{}", file.display(), self.column)?,
+            None => writeln!(f, "{pad}--> line {line}, column {}", self.column)?,
+        }
+
+        // Underline the part of the range that's on the first line, and
+        // keep the tabs in front of it so it lines up
+        let before: String = self
+            .source_line
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let start = self
+            .source_line
+            .char_indices()
+            .nth(self.column - 1)
+            .map_or(self.source_line.len(), |(i, _)| i);
+        let end = (start + self.range.len()).min(self.source_line.len());
+        let width = self.source_line[start..end].chars().count().max(1);
+        writeln!(f, "{pad} |")?;
+        writeln!(f, "{line} | {}", self.source_line)?;
+        write!(f, "{pad} | {before}{}", "^".repeat(width))
+    }
+}
@@ -1,129 +1,296 @@
-use once_cell::sync::OnceCell;
+use std::collections::HashMap;
 use std::error::Error;
-use std::fmt::{Display, Write as WriteFmt};
-use std::fs::File;
+use std::fmt::Write as WriteFmt;
 use std::io::Write;
 
+use cfg::{BlockId, Cfg, Code, Keep, Location, Resume, State, Terminator, Var};
+use parse::CoroutineFn;
+use source_map::{Lines, Origin, Output};
+pub use error::{CorofyError, Diagnostic};
+pub use source_map::SourceMap;
+pub use syntax::Syntax;
+pub use target::Target;
+pub use trace::Trace;
+
+mod block;
+mod cfg;
+mod dot;
+mod error;
+mod parse;
+mod source_map;
+mod syntax;
+mod target;
+mod trace;
+
+/// The keywords we look for unless we're told otherwise. Whatever the wait
+/// keyword is, it's `W_KW` by the time we parse the file.
 const FN_KW: &str = "coroutine";
 const W_KW: &str = "wait";
-static L_TRM_LN: OnceCell<usize> = OnceCell::new();
 
-fn l_trm_len() -> usize {
-    *L_TRM_LN.get().expect("Line terminator len not set")
+/// What we rewrite `coroutine fn`s to and how
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    /// Whose `Future` trait the state machines implement
+    pub target: Target,
+    /// The keywords we look for, `coroutine fn` and `.wait` by default
+    pub syntax: Syntax,
+    /// How the state machines report what they do
+    pub trace: Trace,
+    /// Whether to return where each line of the output comes from in the
+    /// source. The map's `file` and `source` are left empty for the caller
+    /// to fill in.
+    pub source_map: bool,
 }
 
-pub fn rewrite(src: String, dest: File) -> Result<(), impl Display> {
-    detect_line_ending(&src);
-    let mut dest = dest;
-    // Find the start point of async blocks
-    let start_points = find_kw_start_points(&src);
-
-    // No keywords, no async functions, do nothing
-    if start_points.is_empty() {
-        return Err(format!("No `{FN_KW}` function found."));
+/// Rewrites every `coroutine fn` in `src` to a state machine and returns
+/// the whole file, with the source map if `options` asks for one. The
+/// output uses the same line endings as `src`.
+pub fn rewrite(options: &Options, src: &str) -> Result<(String, Option<SourceMap>), CorofyError> {
+    // We only write `\n` ourselves, so we work on the source with `\n`
+    // line endings and put the `\r\n` back at the end
+    let (mut out, map) = with_lf(src, |src| rewrite_lf(options, src))?;
+    if src.contains("\r\n") {
+        out = out.replace('\n', "\r\n");
     }
+    Ok((out, options.source_map.then_some(map)))
+}
 
-    let mut async_start_end = vec![];
-
-    for start in start_points {
-        // find the end
-        // Find the end of the async function
-        let mut brackets_counter = 0;
-        let mut end = start;
-
-        for char in src[start..].chars() {
-            end += 1;
-            match char {
-                '{' => brackets_counter += 1,
-                '}' => {
-                    brackets_counter -= 1;
-                    if brackets_counter == 0 {
-                        break;
-                    }
-                }
-                _ => (),
-            }
-        }
+/// Same as `rewrite`, but writes the file to `dest`. Nothing is written if
+/// there's something we can't transform.
+pub fn rewrite_into(
+    options: &Options,
+    src: &str,
+    mut dest: impl Write,
+) -> Result<Option<SourceMap>, CorofyError> {
+    let (out, map) = rewrite(options, src)?;
+    dest.write_all(out.as_bytes())?;
+    Ok(map)
+}
 
-        // store the locations
-        async_start_end.push((start, end));
-    }
+/// Draws the state machine of every coroutine in `src` as a Graphviz graph,
+/// looking for the keywords in `syntax`. Returns the name of each coroutine
+/// with its graph.
+pub fn graphs(syntax: &Syntax, src: &str) -> Result<Vec<(String, String)>, CorofyError> {
+    with_lf(src, |src| {
+        let desugared = block::desugar(src, syntax)?;
+        let coroutines = parse::parse(&desugared.src, syntax).map_err(|e| desugared.diagnostics(e))?;
+        if coroutines.is_empty() {
+            return Err(CorofyError::no_coroutine(syntax));
+        }
+        Ok(coroutines
+            .iter()
+            .map(|c| (c.name.clone(), dot::graph(c)))
+            .collect())
+    })
+}
 
-    // Write everything except the async functions back to the file
-    // (we put the rewritten code last in the file since it's easier
-    // to see)
-    let mut pos_tracker = 0;
-    for (start, end) in &async_start_end {
-        dest.write_all(&src[pos_tracker..*start].as_bytes())
-            .unwrap();
-        pos_tracker = *end;
+/// Calls `f` with `src` with `\n` line endings, and points the errors it
+/// returns at the same code in `src`
+fn with_lf<T>(src: &str, f: impl FnOnce(&str) -> Result<T, CorofyError>) -> Result<T, CorofyError> {
+    if src.contains("\r\n") {
+        let lf = src.replace("\r\n", "\n");
+        f(&lf).map_err(|e| match e {
+            // Point at the same code in `src`, which has one more byte on every line
+            CorofyError::Unsupported(diagnostics) => CorofyError::Unsupported(
+                diagnostics
+                    .into_iter()
+                    .map(|d| {
+                        let crlf = |pos: usize| pos + lf[..pos].matches('\n').count();
+                        Diagnostic { range: crlf(d.range.start)..crlf(d.range.end), ..d }
+                    })
+                    .collect(),
+            ),
+            e => e,
+        })
+    } else {
+        f(src)
     }
-    // Write everything after the last async fn
-    dest.write_all(&src[pos_tracker..].as_bytes()).unwrap();
-
-    // transform the async functions and write them to the file
+}
 
-    for (i, (start, end)) in async_start_end.into_iter().enumerate() {
-        let id = i.to_string();
+fn rewrite_lf(options: &Options, src: &str) -> Result<(String, SourceMap), CorofyError> {
+    let Options { target, syntax, trace, .. } = options;
+    // Coroutine blocks and closures become `coroutine fn`s first
+    let desugared = block::desugar(src, syntax)?;
+    let src = desugared.src.as_str();
 
-        let async_fn = String::from(&src[start..end - 1]);
+    // Find and parse all the coroutines before we write anything
+    let coroutines = parse::parse(src, syntax)
+        .and_then(|c| check_target(c, *target))
+        .map_err(|e| desugared.diagnostics(e))?;
 
-        // transfrom the async fn
-        let transformed = transform(&async_fn, &id);
+    // No keywords, no async functions, do nothing
+    if coroutines.is_empty() {
+        return Err(CorofyError::no_coroutine(syntax));
+    }
 
-        // Write the coroutine implementation to file
-        dest.write_all(transformed.as_bytes()).unwrap();
+    // We remove the coroutines from where they're declared and write the
+    // transformed code at the end of the file (or the end of the module
+    // they're in) since it's easier to see. The edits are sorted by
+    // position and we keep the order we found them in for edits at the
+    // same position.
+    let mut edits = vec![];
+    for (i, coroutine) in coroutines.iter().enumerate() {
+        let at = Location::of(src, coroutine.orig_start);
+        let (in_place, transformed) = transform(coroutine, &i.to_string(), *target, trace, at);
+        edits.push((coroutine.span.start, coroutine.span.end, in_place));
+        edits.push((coroutine.insert_at, coroutine.insert_at, transformed));
     }
+    edits.sort_by_key(|(start, _, _)| *start);
 
-    Ok(())
+    let mut out = Output::new();
+    let mut pos_tracker = 0;
+    for (start, end, (text, lines)) in edits {
+        out.copy(src, pos_tracker..start);
+        out.push(&text, &lines);
+        pos_tracker = end;
+    }
+    // Write everything after the last edit
+    out.copy(src, pos_tracker..src.len());
+    let (out, mut map) = out.finish();
+    map.map_origins(|origin| desugared.origin(origin));
+    Ok((out, map))
 }
 
-fn find_kw_start_points(s: &str) -> Vec<usize> {
-    let mut start_points = vec![];
-    let mut index = 0;
+/// A coroutine that borrows across a wait point can't move once it has
+/// started, which only the targets with a pinned `poll` can promise
+fn check_target(coroutines: Vec<CoroutineFn>, target: Target) -> syn::Result<Vec<CoroutineFn>> {
+    if target.pins() {
+        return Ok(coroutines);
+    }
+    let mut errors = parse::Errors::default();
+    for coroutine in &coroutines {
+        for var in &coroutine.cfg.vars {
+            if var.keep == Keep::Pointer {
+                errors.push(syn::Error::new(
+                    var.span,
+                    format!("`{}` is a reference into the coroutine's own stack, so it can't move once it has started. That needs a `Future` trait with `self: Pin<&mut Self>`, use the `ch09-pin` or `std` target", var.name),
+                ));
+            }
+        }
+    }
+    errors.finish()?;
+    Ok(coroutines)
+}
 
-    for line in s.lines() {
-        // Remove everything that's commented
-        let (txt, _) = match line.split_once("//") {
-            Some((txt, commented)) => (txt, commented),
-            None => (line, ""),
-        };
+/// Expands the source of a single `coroutine fn` to a function that
+/// returns its state machine, for `options.target` and with
+/// `options.trace`. The state machine is declared inside the function
+/// instead of next to it, so every coroutine can use the same names for
+/// its types, and so are the functions its coroutine blocks and closures
+/// become. This is what the `#[coroutine]` attribute in `corofy-macro`
+/// expands to.
+pub fn expand(options: &Options, src: &str) -> Result<String, CorofyError> {
+    let Options { target, syntax, trace, .. } = options;
+    if declared_fns(src, syntax) != 1 {
+        return Err(CorofyError::Unsupported(vec![Diagnostic::new(
+            src,
+            0..src.len(),
+            format!("expected exactly one `{} fn`", syntax.coroutine()),
+        )]));
+    }
+    let desugared = block::desugar(src, syntax)?;
+    let coroutines = parse::parse(&desugared.src, syntax)
+        .and_then(|c| check_target(c, *target))
+        .map_err(|e| desugared.diagnostics(e))?;
 
-        // Search in the text that's not commented
-        match txt.find(FN_KW) {
-            Some(kw_start) => {
-                start_points.push(index + kw_start);
-                index += line.len() + l_trm_len();
-            }
+    // The functions the blocks became come after the one we were given,
+    // and each of them declares its own state machine too
+    let Some((coroutine, blocks)) = coroutines.split_first() else {
+        return Err(CorofyError::no_coroutine(syntax));
+    };
+    let mut items = rewrite_async_fn(coroutine, "0", *target, trace).unwrap().0;
+    for block in blocks {
+        let (state_machine, _) = rewrite_async_fn(block, "0", *target, trace).unwrap();
+        items.push_str(&create_new_async_fn(block, "0", *target, &state_machine));
+    }
+    Ok(create_new_async_fn(coroutine, "0", *target, &items))
+}
 
-            None => {
-                // An empty line will only be a `\n`
-                let len = if line.len() == 0 {
-                    l_trm_len()
-                } else {
-                    line.len() + l_trm_len()
-                };
-                index += len;
+/// How many `coroutine fn`s `src` declares outside of any braces
+fn declared_fns(src: &str, syntax: &Syntax) -> usize {
+    // We let `parse` tell what's wrong with code we can't even tokenize
+    let Ok(tokens) = src.parse::<proc_macro2::TokenStream>() else {
+        return 1;
+    };
+    let tokens: Vec<_> = tokens.into_iter().collect();
+    tokens
+        .windows(2)
+        .filter(|pair| match pair {
+            [proc_macro2::TokenTree::Ident(kw), proc_macro2::TokenTree::Ident(f)] => {
+                kw == syntax.coroutine() && f == "fn"
             }
-        }
-    }
-    start_points
+            _ => false,
+        })
+        .count()
 }
 
 // Transforms an async function into a state machine, "mimmicing"
-// what happens when compiling an async function in Rust
-fn transform(async_fn: &str, id: &str) -> String {
+// what happens when compiling an async function in Rust. Returns what we
+// write in place of the coroutine and what we write after it, and where
+// their lines come from. `at` is where the coroutine starts in the source.
+fn transform(
+    coroutine: &CoroutineFn,
+    id: &str,
+    target: Target,
+    trace: &Trace,
+    at: Location,
+) -> ((String, Lines), (String, Lines)) {
     // first Comment out the async function
-    let commented = comment_orig(&async_fn);
+    let indent = coroutine.in_impl.as_deref().map_or(0, str::len);
+    let commented = comment_orig(&coroutine.orig, at, indent);
     // Then  rewrite the async function itself
-    let (args, new_async_fn) = create_new_async_fn(&async_fn, &id);
+    let new_async_fn = create_new_async_fn(coroutine, id, target, "");
     // Rewrite the async function to a state machine
-    let rewritten = rewrite_async_fn(&async_fn, &id, args).unwrap();
-    format!("{commented}{new_async_fn}{rewritten}")
+    let rewritten = rewrite_async_fn(coroutine, id, target, trace).unwrap();
+    let mut out = Output::new();
+    match &coroutine.in_impl {
+        // A method has to stay in its `impl` block, but the state machine can't
+        // go there
+        Some(indent) => {
+            let method: Vec<String> = new_async_fn
+                .trim_end()
+                .lines()
+                .map(|line| match line {
+                    "" => String::new(),
+                    line => format!("{indent}{line}"),
+                })
+                .collect();
+            let method = method.join("\n");
+            let lines = signature_lines(&method, at);
+            out.push(&commented.0, &commented.1);
+            out.push(&rewritten.0, &rewritten.1);
+            ((method, lines), out.into_parts())
+        }
+        None => {
+            out.push(&commented.0, &commented.1);
+            out.push(&new_async_fn, &signature_lines(&new_async_fn, at));
+            out.push(&rewritten.0, &rewritten.1);
+            ((String::new(), vec![]), out.into_parts())
+        }
+    }
 }
 
-/// Format and comment out the original "async" function
-fn comment_orig(orig: &str) -> String {
+/// The function that returns the state machine comes from the coroutine's
+/// signature
+fn signature_lines(text: &str, at: Location) -> Lines {
+    text.split('\n')
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let shift = at.column as isize - 1 - indent_of(line) as isize;
+            (i, Origin { line: at.line, shift })
+        })
+        .collect()
+}
+
+fn indent_of(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// Format and comment out the original "async" function. The lines of a
+/// method are `indent` further left than in the source.
+fn comment_orig(orig: &str, at: Location, indent: usize) -> (String, Lines) {
+    let mut lines = vec![];
     let mut res = String::new();
     writeln!(
         &mut res,
@@ -135,7 +302,11 @@ fn comment_orig(orig: &str) -> String {
     "
     )
     .unwrap();
-    for line in orig.lines() {
+    for (i, line) in orig.lines().enumerate() {
+        // The first line starts where the coroutine does
+        let column = if i == 0 { at.column - 1 } else { indent };
+        let shift = column as isize - "// ".len() as isize;
+        lines.push((res.matches('\n').count(), Origin { line: at.line + i, shift }));
         writeln!(&mut res, "// {line}").unwrap();
     }
     writeln!(
@@ -150,104 +321,133 @@ fn comment_orig(orig: &str) -> String {
     )
     .unwrap();
 
-    res
+    (res, lines)
 }
 
-// Returns the new async function and it's arguments
-fn create_new_async_fn(func: &str, coro_id: &str) -> (Vec<(String, String)>, String) {
-    // first line is expected to be the function definition `keyword fn name() -> ReturnType`
-    // remove the keyword
-    let def = &func.lines().nth(0).unwrap()[FN_KW.len() + 1..];
-
-    // get the name of the function
-    let (fn_name, arg_start) = def.split_once("(").expect("Expected `(`").clone();
-    let (args, _) = arg_start.split_once(")").expect("Expected `)`");
+// Returns the new async function. `items` is written at the start of its
+// body.
+fn create_new_async_fn(
+    coroutine: &CoroutineFn,
+    coro_id: &str,
+    target: Target,
+    items: &str,
+) -> String {
+    let CoroutineFn {
+        attrs,
+        vis,
+        name,
+        args,
+        receiver,
+        generics,
+        output,
+        ..
+    } = coroutine;
 
-    let args = get_args(&args);
+    // Attributes and doc comments belong to the function we return
+    let attrs: String = attrs.iter().map(|attr| format!("{attr}\n")).collect();
 
-    let args_fmt = format_args_name_and_types(&args);
-    let arg_names = if args.is_empty() {
-        format!("()")
-    } else {
-        format_args_names_only(&args)
-    };
+    // A method takes `self` and passes it on as `this`
+    let args = &args[receiver.iter().count()..];
+    let args_fmt: Vec<String> = receiver
+        .iter()
+        .cloned()
+        .chain(args.iter().map(|(n, ty)| format!("{n}: {ty}")))
+        .collect();
+    let args_fmt = args_fmt.join(",");
+    let arg_names: Vec<&str> = receiver
+        .iter()
+        .map(|_| "self")
+        .chain(args.iter().map(|(n, _)| n.as_str()))
+        .collect();
+    let arg_names = format!("({})", arg_names.join(","));
+    let params = generics.fn_decl();
+    let captures = generics.captures();
+    let where_clause = generics.fn_where_clause();
+    let future = target.future();
 
-    let new_async_fn = format!(
-        "{fn_name}({args_fmt}) -> impl Future<Output=String> {{
+    format!(
+        "{attrs}{vis}fn {name}{params}({args_fmt}) -> impl {future}<Output={output}>{captures}{where_clause} {{{items}
     Coroutine{coro_id}::new{arg_names}
 }}
         "
-    );
-
-    (args, new_async_fn)
+    )
 }
 
-/// Rewrite the async function (this is very brittle, but does
-/// the job for our example)
+/// Rewrite the async function to a state machine with one state for
+/// each wait point, and one for each place we jump back to in a loop or
+/// join after a branch
 fn rewrite_async_fn(
-    s: &str,
+    coroutine: &CoroutineFn,
     id: &str,
-    args: Vec<(String, String)>,
-) -> Result<String, Box<dyn Error>> {
-    let w_kw_len = W_KW.len();
-
-    // Store the code in each "step" in this variable
-    let mut steps = vec![];
-    // Store the future call that we yield on
-    let mut futures = vec![];
-
-    let mut buffer = String::new();
-    // Skip the first line since that's the function definition
-    for line in s.lines().skip(1) {
-        // If the line contains the keyword it's an await-point
-        if line.contains(W_KW) {
-            // Store the steps since last await point as a "step"
-            steps.push(buffer.clone());
-            buffer.clear();
-            // Remove the keyword itself (i.e. `.await`)
-            let l = &line.trim_end()[..line.len() - 1 - w_kw_len - 1];
-            // we need both the future call and the variable name since
-            // we most likely reference this variable name in the next "step"
-            // This could be:
-            // `let txt = Http::get("...").await`
-            // or simply
-            // `join_all(futures).await`
-            match l.split_once("=") {
-                Some((var, fut)) => {
-                    // This could fail in so many ways...
-                    let varname = &var[var.find("let").unwrap() + 3..].trim();
-                    futures.push((varname.to_string(), fut.to_string()));
-                }
-                None => futures.push(("_".to_string(), l.trim().to_string())),
-            }
-
-            // We store the variable name and the future as a tuple since they're connected
-        } else {
-            buffer.push_str(line);
-            buffer.push_str("\n");
-        }
-    }
-
-    steps.push(buffer);
+    target: Target,
+    trace: &Trace,
+) -> Result<(String, Lines), Box<dyn Error>> {
+    let CoroutineFn {
+        name,
+        args,
+        receiver,
+        generics,
+        cfg,
+        output,
+        result,
+        ..
+    } = coroutine;
+    let states = cfg.states();
+    // A coroutine that keeps a reference into its own stack can't move
+    // once it has started, so we only get here with a target where `poll`
+    // takes a `Pin<&mut Self>`. A method already uses `this` for `self`.
+    let (wait_box, me) = match (target.pins(), receiver) {
+        (true, None) => ("std::pin::Pin<Box<dyn {future}<Output = {ty}>{bound}>>", "this"),
+        (true, Some(_)) => ("std::pin::Pin<Box<dyn {future}<Output = {ty}>{bound}>>", "coro"),
+        (false, _) => ("Box<dyn {future}<Output = {ty}>{bound}>", "self"),
+    };
+    let wait_box = wait_box
+        .replace("{future}", target.future())
+        .replace("{bound}", &generics.bound());
+    let decl = generics.decl();
+    let params = generics.args();
+    let where_clause = generics.where_clause();
 
     // Write our steps enum. We know it will start with "Start" and end with "Resolved"
     // but we need to add one step for each await point
 
-    let step_args = format_args_types_only(&args);
+    // A generic state machine has to use all of its parameters, which we
+    // do in the `Start` state
+    let mut start_args = args.clone();
+    let mut start_pat = args.clone();
+    if let Some(marker) = generics.marker() {
+        start_args.push((String::from("std::marker::PhantomData"), marker.clone()));
+        start_pat.push((String::from("_"), marker));
+    }
+    // Arguments that may not be `Copy` are moved out of the state instead
+    // of copied
+    let move_start = !args.iter().all(|(_, ty)| is_copy(ty));
+    let step_args = format_args_types_only(&start_args);
 
     let mut steps_enum = format!(
         "
-enum State{id} {{
+enum State{id}{decl}{where_clause} {{
     Start{step_args},"
     );
 
-    // We only support this kind of future
-    for i in 1..steps.len() {
-        write!(
-            &mut steps_enum,
-            "
-    Wait{i}(Box<dyn Future<Output = String>>),"
-        )?;
+    // Each wait point stores the future we wait on
+    for state in &states {
+        match &state.resume {
+            Resume::Start => (),
+            Resume::Wait(n) => {
+                let ty = wait_box.replace("{ty}", &cfg.waits[n - 1].ty);
+                write!(
+                    &mut steps_enum,
+                    "
+    Wait{n}({ty}),"
+                )?;
+            }
+            Resume::Jump(name) => write!(
+                &mut steps_enum,
+                "
+    {name},"
+            )?,
+        }
     }
 
     write!(
@@ -257,155 +457,615 @@ enum State{id} {{
 }}"
     )?;
 
+    // A traced coroutine tells which state it's in when it's polled
+    if *trace != Trace::Off {
+        write!(
+            &mut steps_enum,
+            "
+
+impl{decl} State{id}{params}{where_clause} {{
+    fn name(&self) -> &'static str {{
+        match self {{
+            Self::Start {{ .. }} => \"Start\","
+        )?;
+        for state in &states {
+            let name = match &state.resume {
+                Resume::Start => continue,
+                Resume::Wait(n) => format!("Wait{n}"),
+                Resume::Jump(name) => name.clone(),
+            };
+            write!(&mut steps_enum, "\n            Self::{name} {{ .. }} => \"{name}\",")?;
+        }
+        write!(
+            &mut steps_enum,
+            "
+            Self::Resolved => \"Resolved\",
+        }}
+    }}
+}}"
+        )?;
+    }
+
+    // Variables we need after a wait point are stored in the stack while we
+    // wait, and so are the iterators of the loops we wait in
+    let mut stack = String::new();
+    let mut stack_field = String::new();
+    let mut stack_init = String::new();
+    let kept: Vec<&Var> = cfg.vars.iter().filter(|v| v.keep != Keep::No).collect();
+    if !kept.is_empty() || !cfg.iters.is_empty() {
+        // `derive(Default)` would want every type parameter to be `Default`
+        let derive = if generics.is_empty() { "\n#[derive(Default)]" } else { "" };
+        write!(
+            &mut stack,
+            "{derive}
+struct Stack{id}{decl}{where_clause} {{"
+        )?;
+        let bound = generics.bound();
+        let mut fields = vec![];
+        for (i, item) in cfg.iters.iter().enumerate() {
+            let i = i + 1;
+            write!(
+                &mut stack,
+                "
+    iter{i}: Option<Box<dyn Iterator<Item = {item}>{bound}>>,"
+            )?;
+            fields.push(format!("iter{i}: None"));
+        }
+        for Var { field, ty, .. } in &kept {
+            let ty = ty.as_deref().unwrap_or_default();
+            write!(
+                &mut stack,
+                "
+    {field}: Option<{ty}>,"
+            )?;
+            fields.push(format!("{field}: None"));
+        }
+        if let Some(marker) = generics.marker() {
+            write!(&mut stack, "\n    _marker: {marker},")?;
+            fields.push(String::from("_marker: std::marker::PhantomData"));
+        }
+        writeln!(&mut stack, "\n}}")?;
+        stack_field = format!("\n    stack: Stack{id}{params},");
+        stack_init = if generics.is_empty() {
+            format!(", stack: Stack{id}::default()")
+        } else {
+            format!(", stack: Stack{id} {{ {} }}", fields.join(", "))
+        };
+    }
+    let (pin_field, pin_init) = if cfg.pinned {
+        ("\n    _pin: std::marker::PhantomPinned,", ", _pin: std::marker::PhantomPinned")
+    } else {
+        ("", "")
+    };
+
     // So, our `State` enum is finished, we create a coroutine struct and a simple
     // `new` implementation
-    let coro_args = format_args_name_and_types(&args);
-    let coro_args_names = format_args_names_only(&args);
+    let coro_args = format_args_name_and_types(args);
+    let coro_args_names = format_args_names_only(&start_args);
 
     let coroutine = format!(
-        "
-struct Coroutine{id} {{
-    state: State{id},
+        "{stack}
+struct Coroutine{id}{decl}{where_clause} {{{stack_field}
+    state: State{id}{params},{pin_field}
 }}
 
-impl Coroutine{id} {{
+impl{decl} Coroutine{id}{params}{where_clause} {{
     fn new({coro_args}) -> Self {{
-        Self {{ state: State{id}::Start{coro_args_names} }}
+        Self {{ state: State{id}::Start{coro_args_names}{stack_init}{pin_init} }}
     }}
 }}
 "
     );
 
     // This is our future implementation
-    // NB! Notice how we force all futures to return a string even if they
-    // don't (if not we this get's very complicated without type information available)
+    // NB! Unless the coroutine declares a return type we force it to return
+    // a string (if not we this get's very complicated without type information available)
+    let receiver = if target.pins() {
+        "self: std::pin::Pin<&mut Self>"
+    } else {
+        "&mut self"
+    };
+    let poll_args = target.poll_args();
+    let poll_state = target.poll_state();
+    let this = if target.pins() {
+        format!("\n        let {me} = unsafe {{ self.get_unchecked_mut() }};")
+    } else {
+        String::new()
+    };
+    // We can't tell if a state changes a `mut` variable it takes out of the
+    // stack, so we don't want to hear about it when it doesn't
+    let allow = if kept.iter().any(|v| v.mutable) {
+        "#[allow(unused_mut)]\n    "
+    } else {
+        ""
+    };
+    let traced = match trace.poll(name, me) {
+        Some(call) => format!("\n        {call}"),
+        None => String::new(),
+    };
+    let future = target.future();
     let mut imp = format!(
         "
-impl Future for Coroutine{id} {{
-    type Output = String;
+impl{decl} {future} for Coroutine{id}{params}{where_clause} {{
+    type Output = {output};
 
-    fn poll(&mut self) -> PollState<Self::Output> {{
-        loop {{"
+    {allow}fn poll({receiver}{poll_args}) -> {poll_state}<Self::Output> {{{this}{traced}
+        loop {{
+        match {me}.state {{"
     );
 
-    for (i, step) in steps.iter().enumerate() {
-        // This is the index for the next step in the state machine
-        let next = i + 1;
+    let mut arms = Arms {
+        out: &mut imp,
+        cfg,
+        id,
+        me,
+        target,
+        trace,
+        name,
+        result,
+        start_args: format_args_names_only(&start_pat),
+        move_start,
+        lines: vec![],
+        jumps: states
+            .iter()
+            .filter_map(|s| match &s.resume {
+                Resume::Jump(name) => Some((s.block, name.as_str())),
+                _ => None,
+            })
+            .collect(),
+    };
+    for state in &states {
+        arms.state(state)?;
+    }
+    let lines = arms.lines;
 
-        // We need to special case the first call since that
-        // happens before we reach an `await` point
+    // If we poll the future after it has resolved, we panic
+    writeln!(
+        &mut imp,
+        "
+                State{id}::Resolved => panic!(\"Polled a resolved future\")
+            }}
+        }}
+    }}
+}}"
+    )?;
 
-        // This will receive the input args to the function
-        let impl_fut_first_args = format_args_names_only(&args);
+    // Format the different parts of the Coroutine implementation to a string
+    let out = format!("{steps_enum}\n{coroutine}\n");
+    let before = out.matches('\n').count();
+    let lines = lines.into_iter().map(|(i, origin)| (before + i, origin)).collect();
+    Ok((format!("{out}{imp}"), lines))
+}
+
+/// Writes the match arm for each state of the state machine
+struct Arms<'a> {
+    out: &'a mut String,
+    cfg: &'a Cfg,
+    id: &'a str,
+    /// `self`, or `this` when we have to unpin `self` first
+    me: &'a str,
+    target: Target,
+    trace: &'a Trace,
+    /// The name of the coroutine, which is what we trace it as
+    name: &'a str,
+    result: &'a str,
+    start_args: String,
+    move_start: bool,
+    /// Where the lines we write come from in the source
+    lines: Lines,
+    /// The blocks we can only get to by changing state, and the name of
+    /// their state
+    jumps: HashMap<BlockId, &'a str>,
+}
 
-        if i == 0 {
-            // if there are no futures in this "coro" fn we only have Start -> Resolved
-            if futures.is_empty() {
+impl Arms<'_> {
+    fn state(&mut self, state: &State) -> std::fmt::Result {
+        let id = self.id;
+        let block = state.block;
+        match &state.resume {
+            // This will receive the input args to the function
+            Resume::Start if self.move_start => {
+                let args = &self.start_args;
+                let me = self.me;
                 write!(
-                    &mut imp,
+                    self.out,
                     "
-        match self.state {{
-                State{id}::Start{impl_fut_first_args} => {{
-                    // ---- Code you actually wrote ----
-                {step}
-                    // ---------------------------------
-                    self.state = State{id}::Resolved;
-                    break PollState::Ready(String::new());
-                }}
+                State{id}::Start(..) => {{
+                    let State{id}::Start{args} = std::mem::replace(&mut {me}.state, State{id}::Resolved) else {{
+                        unreachable!()
+                    }};
 "
                 )?;
-                continue;
+                self.block(block, 20, true)?;
+                writeln!(self.out, "{:16}}}", "")
             }
-
-            let futname = &futures[i].1;
-            write!(
-                &mut imp,
-                "
-        match self.state {{
-                State{id}::Start{impl_fut_first_args} => {{
-                    // ---- Code you actually wrote ----
-                {step}
-                    // ---------------------------------
-                    let fut{next} = Box::new({futname});
-                    self.state = State{id}::Wait{next}(fut{next});
-                }}
+            Resume::Start => {
+                let args = &self.start_args;
+                write!(self.out, "\n{:16}State{id}::Start{args} => {{\n", "")?;
+                self.block(block, 20, true)?;
+                writeln!(self.out, "{:16}}}", "")
+            }
+            // These are wait points where we await a future
+            Resume::Wait(n) => {
+                let wait = &self.cfg.waits[n - 1];
+                // `fut.wait?` goes on with what's in `Ok` and resolves to an `Err`
+                let pat = match wait.tried {
+                    true => format!("Ok({})", wait.pat),
+                    false => wait.pat.clone(),
+                };
+                let poll = if self.target.pins() { "as_mut().poll" } else { "poll" };
+                let waker = self.target.waker();
+                let poll_state = self.target.poll_state();
+                let not_ready = self.target.not_ready();
+                write!(
+                    self.out,
+                    "
+                State{id}::Wait{n}(ref mut f{n}) => {{
+                    match f{n}.{poll}({waker}) {{
+                        {poll_state}::Ready({pat}) => {{
 "
-            )?;
-
-        // These steps are await-points where we await a future
-        } else if i < steps.len() - 1 {
-            let varname = &futures[i - 1].0;
-            let fut = &futures[i].1;
-            write!(
-                &mut imp,
-                "
-                State{id}::Wait{i}(ref mut f{i}) => {{
-                    match f{i}.poll() {{
-                        PollState::Ready({varname}) => {{
-                            // ---- Code you actually wrote ----
-                        {step}
-                            // ---------------------------------
-                            let fut{next} = Box::new({fut});
-                            self.state = State{id}::Wait{next}(fut{next});
+                )?;
+                self.wrote(wait.at, "");
+                self.restore(block, 28)?;
+                self.block(block, 28, true)?;
+                if wait.tried {
+                    write!(self.out, "{:24}}}\n{:24}{poll_state}::Ready(Err(e)) => {{\n", "", "")?;
+                    self.resolve("Err(From::from(e))", 28)?;
+                    self.wrote(wait.at, "");
+                }
+                write!(self.out, "{:24}}}\n{:24}{poll_state}::{not_ready} => ", "", "")?;
+                match self.trace.event(self.name, "not_ready", &format!("Wait{n}")) {
+                    Some(call) => write!(
+                        self.out,
+                        "{{
+                            {call}
+                            break {poll_state}::{not_ready};
                         }}
-                        PollState::NotReady => break PollState::NotReady,
-                    }}
-                }}
 "
-            )?;
+                    )?,
+                    None => writeln!(self.out, "break {poll_state}::{not_ready},")?,
+                }
+                writeln!(self.out, "{:20}}}\n{:16}}}", "", "")
+            }
+            Resume::Jump(name) => {
+                write!(self.out, "\n{:16}State{id}::{name} => {{\n", "")?;
+                self.restore(block, 20)?;
+                self.block(block, 20, false)?;
+                writeln!(self.out, "{:16}}}", "")
+            }
+        }
+    }
 
-        // This is the part after the last await point. There is no need to yield any more
-        } else {
-            let varname = &futures[i - 1].0;
+    /// The code of a block. Variables that are borrowed across a wait point
+    /// move into the stack, and are borrowed from there.
+    fn code(&self, block: BlockId) -> String {
+        let me = self.me;
+        let mut out = String::new();
+        for code in &self.cfg.blocks[block].code {
+            match code {
+                Code::Text(text) => out.push_str(text),
+                Code::Declared {
+                    var,
+                    text,
+                    indent,
+                    mutability,
+                } => {
+                    let Var {
+                        name,
+                        field,
+                        keep,
+                        mutable,
+                        ..
+                    } = &self.cfg.vars[*var];
+                    if *keep != Keep::Pinned {
+                        out.push_str(&parse::lines(text));
+                        continue;
+                    }
+                    let mut text = text.clone();
+                    if let Some(mutability) = mutability {
+                        text.replace_range(mutability.clone(), "");
+                    }
+                    out.push_str(&parse::lines(&text));
+                    let borrow = if *mutable { "" } else { "&*" };
+                    writeln!(out, "{indent}let {name} = {borrow}{me}.stack.{field}.insert({name});")
+                        .unwrap();
+                }
+                Code::Borrow { var, text, at } => {
+                    let target = self.cfg.vars[*var].borrows.map(|(target, _)| target);
+                    let pinned = target.is_some_and(|t| self.cfg.vars[t].keep == Keep::Pinned);
+                    let mut text = text.clone();
+                    if pinned {
+                        text.insert(*at, '*');
+                    }
+                    out.push_str(&parse::lines(&text));
+                }
+            }
+        }
+        out
+    }
+
+    /// Writes the code of a block followed by whatever happens at the end of
+    /// it. The code of the blocks we go to next is written in place unless
+    /// we need a new state to get there.
+    fn block(&mut self, block: BlockId, indent: usize, first: bool) -> std::fmt::Result {
+        let id = self.id;
+        let me = self.me;
+        let code = self.code(block);
+        if first || !code.trim().is_empty() {
+            // The code starts on the line after the comment
+            let line = self.out.matches('\n').count() + 1;
+            self.wrote_code(block, line, &format!("{:1$}{code}", "", indent - 4));
             write!(
-                &mut imp,
-                "
-                State{id}::Wait{i}(ref mut f{i}) => {{
-                    match f{i}.poll() {{
-                        PollState::Ready({varname}) => {{
-                            // ---- Code you actually wrote ----
-                        {step}
-                            // ---------------------------------
-                            self.state = State{id}::Resolved;
-                            break PollState::Ready(String::new());
-                        }}
-                        PollState::NotReady => break PollState::NotReady,
-                    }}
-                }}
-"
+                self.out,
+                "{:indent$}// ---- Code you actually wrote ----
+{:code_indent$}{code}
+{:indent$}// ---------------------------------
+",
+                "",
+                "",
+                "",
+                code_indent = indent - 4,
             )?;
         }
+
+        match &self.cfg.blocks[block].term {
+            Terminator::Goto(next) => self.goto(*next, indent),
+            Terminator::IntoIter {
+                iter,
+                expr,
+                next,
+                at,
+            } => {
+                let iter = iter + 1;
+                writeln!(
+                    self.out,
+                    "{:indent$}{me}.stack.iter{iter} = Some(Box::new(IntoIterator::into_iter({expr})));",
+                    ""
+                )?;
+                self.wrote(*at, expr);
+                self.goto(*next, indent)
+            }
+            Terminator::Next {
+                iter,
+                pat,
+                body,
+                exit,
+            } => {
+                let iter = iter + 1;
+                writeln!(
+                    self.out,
+                    "{:indent$}match {me}.stack.iter{iter}.as_mut().unwrap().next() {{",
+                    ""
+                )?;
+                self.arm(&format!("Some({pat})"), *body, indent + 4)?;
+                self.arm("None", *exit, indent + 4)?;
+                writeln!(self.out, "{:indent$}}}", "")
+            }
+            Terminator::If {
+                cond,
+                then,
+                els,
+                at,
+            } => {
+                writeln!(self.out, "{:indent$}if {cond} {{", "")?;
+                self.wrote(*at, cond);
+                self.goto(*then, indent + 4)?;
+                writeln!(self.out, "{:indent$}}} else {{", "")?;
+                self.goto(*els, indent + 4)?;
+                writeln!(self.out, "{:indent$}}}", "")
+            }
+            Terminator::Match { expr, arms, at } => {
+                writeln!(self.out, "{:indent$}match {expr} {{", "")?;
+                self.wrote(*at, expr);
+                for (pat, block) in arms {
+                    self.arm(pat, *block, indent + 4)?;
+                }
+                writeln!(self.out, "{:indent$}}}", "")
+            }
+            Terminator::Wait { wait, next } => {
+                let n = wait + 1;
+                let fut = &self.cfg.waits[*wait].fut;
+                let new = if self.target.pins() { "Box::pin" } else { "Box::new" };
+                writeln!(self.out, "{:indent$}let fut{n} = {new}({fut});", "")?;
+                self.wrote(self.cfg.waits[*wait].at, fut);
+                writeln!(self.out, "{:indent$}{me}.state = State{id}::Wait{n}(fut{n});", "")?;
+                self.enter(&format!("Wait{n}"), indent)?;
+                self.save(*next, indent)
+            }
+            Terminator::Resolve => {
+                self.resolve(self.result, indent)?;
+                if let Some(at) = self.cfg.result_at {
+                    self.wrote(at, self.result);
+                }
+                Ok(())
+            }
+            Terminator::Return(value, at) => {
+                self.resolve(value, indent)?;
+                self.wrote(*at, value);
+                Ok(())
+            }
+        }
     }
 
-    // If we poll the future after it has resolved, we panic
-    writeln!(
-        &mut imp,
-        "
-                State{id}::Resolved => panic!(\"Polled a resolved future\")
-            }}
-        }}
-    }}
-}}"
-    )?;
+    /// Remembers that the line we just wrote comes from the code at `at`.
+    /// `code` is the code that's there, which we line the columns up with
+    /// if it's on the line. Otherwise the line starts at `at`.
+    fn wrote(&mut self, at: Location, code: &str) {
+        let end = self.out.len() - 1;
+        let start = self.out[..end].rfind('\n').map_or(0, |i| i + 1);
+        let written = &self.out[start..end];
+        let column = match written.find(code.trim()) {
+            Some(i) if !code.trim().is_empty() => written[..i].chars().count(),
+            _ => indent_of(written),
+        };
+        let shift = at.column as isize - 1 - column as isize;
+        let line = self.out.matches('\n').count() - 1;
+        self.lines.push((line, Origin { line: at.line, shift }));
+    }
 
-    // Format the different parts of the Coroutine implementation to a string
-    Ok(format!("{steps_enum}\n{coroutine}\n{imp}"))
-}
+    /// Remembers where the lines of `code` come from. Most of them are
+    /// lines of the block as the user wrote them, in the same order.
+    /// `line` is the line we write the code on.
+    fn wrote_code(&mut self, block: BlockId, line: usize, code: &str) {
+        let source = &self.cfg.blocks[block].source;
+        let mut next = 0;
+        for (i, text) in code.split('\n').enumerate() {
+            if text.trim().is_empty() {
+                continue;
+            }
+            let Some(found) = source[next..].iter().position(|(_, s)| s.trim() == text.trim()) else {
+                continue;
+            };
+            let (n, s) = &source[next + found];
+            next += found + 1;
+            let shift = indent_of(s) as isize - indent_of(text) as isize;
+            self.lines.push((line + i, Origin { line: *n, shift }));
+        }
+    }
+
+    fn resolve(&mut self, value: &str, indent: usize) -> std::fmt::Result {
+        let id = self.id;
+        let me = self.me;
+        writeln!(self.out, "{:indent$}{me}.state = State{id}::Resolved;", "")?;
+        self.enter("Resolved", indent)?;
+        // Free what we kept in the stack for as long as the coroutine ran,
+        // once we're done with it
+        let pinned: Vec<&Var> = self.cfg.vars.iter().filter(|v| v.keep == Keep::Pinned).collect();
+        // A pinned variable is a reference into the stack by now, so we
+        // resolve to what's in the stack instead
+        let owned = pinned.iter().rev().find(|v| v.name == value.trim()).map(|v| v.field.as_str());
+        let value = match owned {
+            Some(field) => {
+                writeln!(self.out, "{:indent$}let output = {me}.stack.{field}.take().unwrap();", "")?;
+                String::from("output")
+            }
+            None if pinned.is_empty() => value.to_string(),
+            None => {
+                writeln!(self.out, "{:indent$}let output = {value};", "")?;
+                String::from("output")
+            }
+        };
+        for var in pinned.iter().filter(|v| Some(v.field.as_str()) != owned) {
+            writeln!(self.out, "{:indent$}let _ = {me}.stack.{}.take();", "", var.field)?;
+        }
+        let poll_state = self.target.poll_state();
+        writeln!(self.out, "{:indent$}break {poll_state}::Ready({value});", "")
+    }
 
-// this expects something like `txt: String, i: usize` or an empty string
-fn get_args(s: &str) -> Vec<(String, String)> {
-    let mut res = vec![];
-    if s.trim().is_empty() {
-        return res;
+    fn arm(&mut self, pat: &str, block: BlockId, indent: usize) -> std::fmt::Result {
+        writeln!(self.out, "{:indent$}{pat} => {{", "")?;
+        self.goto(block, indent + 4)?;
+        writeln!(self.out, "{:indent$}}}", "")
     }
 
-    let args = s.split(",");
-    for arg in args {
-        let (argname, ty) = arg.split_once(":").expect("Expected `:`");
-        res.push((argname.trim().to_string(), ty.trim().to_string()));
+    fn goto(&mut self, block: BlockId, indent: usize) -> std::fmt::Result {
+        match self.jumps.get(&block) {
+            Some(name) => {
+                let id = self.id;
+                let me = self.me;
+                writeln!(self.out, "{:indent$}{me}.state = State{id}::{name};", "")?;
+                self.enter(name, indent)?;
+                self.save(block, indent)
+            }
+            None => self.block(block, indent, false),
+        }
     }
 
-    res
+    /// Reports that we moved to `state`, if we trace the coroutine
+    fn enter(&mut self, state: &str, indent: usize) -> std::fmt::Result {
+        match self.trace.event(self.name, "enter", state) {
+            Some(call) => writeln!(self.out, "{:indent$}{call}", ""),
+            None => Ok(()),
+        }
+    }
+
+    /// Saves the variables the next state needs in the stack. Pinned
+    /// variables are in the stack already.
+    fn save(&mut self, next: BlockId, indent: usize) -> std::fmt::Result {
+        let me = self.me;
+        for &var in &self.cfg.blocks[next].live {
+            let Var {
+                name, field, keep, ..
+            } = &self.cfg.vars[var];
+            if matches!(keep, Keep::Moved | Keep::Pointer) {
+                writeln!(self.out, "{:indent$}{me}.stack.{field} = Some({name});", "")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The variables used by the code of the state that starts in `block`
+    fn state_uses(&self, block: BlockId) -> Vec<usize> {
+        let mut uses = vec![];
+        let mut todo = vec![block];
+        let mut seen = vec![block];
+        while let Some(b) = todo.pop() {
+            let block = &self.cfg.blocks[b];
+            uses.extend(&block.uses);
+            if let Terminator::Wait { .. } = block.term {
+                continue;
+            }
+            for next in block.term.successors() {
+                if !self.jumps.contains_key(&next) && !seen.contains(&next) {
+                    seen.push(next);
+                    todo.push(next);
+                }
+            }
+        }
+        uses
+    }
+
+    /// Gets the variables this state needs from the stack. A pointer to a
+    /// pinned variable the state uses too is borrowed from that variable,
+    /// since borrowing the variable from the stack invalidates the pointer.
+    fn restore(&mut self, block: BlockId, indent: usize) -> std::fmt::Result {
+        let me = self.me;
+        let uses = self.state_uses(block);
+        for &var in &self.cfg.blocks[block].live {
+            let Var {
+                name,
+                field,
+                mutable,
+                keep,
+                borrows,
+                ..
+            } = &self.cfg.vars[var];
+            let mutable = if *mutable { "mut " } else { "" };
+            let value = match keep {
+                Keep::No => continue,
+                // It stays in the stack, so we only need it if we use it here
+                Keep::Pinned if !uses.contains(&var) => continue,
+                Keep::Moved => format!("{me}.stack.{field}.take().unwrap()"),
+                Keep::Pinned if mutable.is_empty() => format!("{me}.stack.{field}.as_ref().unwrap()"),
+                Keep::Pinned => format!("{me}.stack.{field}.as_mut().unwrap()"),
+                Keep::Pointer => {
+                    let (target, mutable) = borrows.unwrap();
+                    let borrow = if mutable { "&mut " } else { "&" };
+                    if uses.contains(&target) {
+                        // We just borrowed what it points to from the stack,
+                        // which the pointer we kept can't be used after
+                        writeln!(self.out, "{:indent$}{me}.stack.{field} = None;", "")?;
+                        format!("{borrow}*{}", self.cfg.vars[target].name)
+                    } else {
+                        format!("unsafe {{ {borrow}*{me}.stack.{field}.take().unwrap() }}")
+                    }
+                }
+            };
+            // A pinned variable is already a reference to the stack
+            let mutable = if *keep == Keep::Pinned { "" } else { mutable };
+            writeln!(self.out, "{:indent$}let {mutable}{name} = {value};", "")?;
+        }
+        Ok(())
+    }
+}
+
+/// If `ty` is a type we know is `Copy`, going by its name alone
+fn is_copy(ty: &str) -> bool {
+    const PRIMITIVES: [&str; 15] = [
+        "bool", "char", "f32", "f64", "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16",
+        "u32", "u64", "usize",
+    ];
+    (ty.starts_with('&') && !ty.contains("mut ")) || PRIMITIVES.contains(&ty)
 }
 
 /// Gets:
@@ -436,7 +1096,7 @@ fn format_args_name_and_types(args: &[(String, String)]) -> String {
         let mut args_fmt: String = args.iter().map(|(n, ty)| format!("{n}: {ty},")).collect();
         // remove last `,`
         args_fmt.pop();
-        format!("{args_fmt}")
+        args_fmt
     }
 }
 
@@ -455,32 +1115,3 @@ fn format_args_names_only(args: &[(String, String)]) -> String {
         format!("({args_fmt})")
     }
 }
-
-/// Since both text editors and github can change the line endings of files
-/// we need to account for \n line endings on Windows as well as \r\n.
-fn detect_line_ending(s: &str) {
-    let mut chars = s.chars().into_iter().peekable();
-
-    let len = loop {
-        let current = chars.next();
-        match chars.peek() {
-            Some(&'\n') => {
-                if let Some('\r') = current {
-                    break 2;
-                } else {
-                    break 1;
-                }
-            }
-            Some(_) => (),
-            None => {
-                // defaults if we for some reason fall through
-                if cfg!(windows) {
-                    break 2;
-                } else {
-                    break 1;
-                }
-            }
-        }
-    };
-    L_TRM_LN.set(len).unwrap();
-}
\ No newline at end of file
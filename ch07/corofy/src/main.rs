@@ -1,41 +1,424 @@
-use std::{fs, env, error::Error, path::{Path, PathBuf}};
+use std::{fs, env, collections::HashSet, io::{self, Read, Write}, path::{Path, PathBuf}, process::ExitCode};
 
-use corofy::rewrite;
+use corofy::{graphs, rewrite, CorofyError, Options, SourceMap, Syntax, Trace};
+use similar::TextDiff;
 
+const USAGE: &str = "\
+usage: corofy [--target ch07|ch08|ch09-pin|std] [--check | --diff | --in-place] [--source-map] [--trace[=<hook>]] <src> [dest]
+       corofy [--target ch07|ch08|ch09-pin|std] [--check | --diff] [--source-map] [--trace[=<hook>]] <dir>...
+       corofy --emit dot <src> [dest-dir]
+       corofy --remap <dest>.map < rustc-output
 
-fn main() -> Result<(), Box<dyn Error>> {
+Use `-` as <src> to read from stdin, and as [dest] to write to stdout.
+`--keywords <fn-kw>,<wait-kw>` looks for `<fn-kw> fn` and `fut.<wait-kw>`
+instead of `coroutine fn` and `fut.wait`, and `--async` reads `async fn`
+and `fut.await` the way rustc does.
+`--trace` makes the state machines print every poll, every state they
+enter and every `NotReady` they return to stderr, and `--trace=<hook>`
+calls `<hook>(coroutine, event, state)` with three `&str`s instead.
+`--source-map` also writes where each line of [dest] comes from to
+[dest].map, which `--remap` uses to point the errors and panics rustc
+reports in [dest] at the coroutines they come from.";
 
-    let args: Vec<String> = env::args().collect();
+/// The names the examples in the book give the sources of the
+/// `src/main_corofied.rs` next to them
+const SOURCE_NAMES: [&str; 2] = ["original_main.rs", "main_orig.rs"];
 
-    let src = match args.get(1) {
-        Some(path) => Path::new(path),
-        None => {
-            println!("Missing source file. Please provide a path to a source file and try again.");
-            return Ok(());
-        },
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Write the rewritten code to the destination
+    Write,
+    /// Only tell if the destination is out of date
+    Check,
+    /// Show how the destination would change
+    Diff,
+    /// Replace the source with the rewritten code
+    InPlace,
+}
+
+/// What we write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Emit {
+    /// The code with the coroutines rewritten to state machines
+    Rust,
+    /// A Graphviz graph of the state machine of each coroutine
+    Dot,
+}
+
+impl Emit {
+    fn parse(name: &str) -> Result<Self, CorofyError> {
+        match name {
+            "rust" => Ok(Emit::Rust),
+            "dot" => Ok(Emit::Dot),
+            _ => Err(usage(&format!("unknown `--emit` value `{name}`, expected rust or dot"))),
+        }
+    }
+}
+
+/// Where we read the code from, or write it to
+#[derive(Debug, Clone)]
+enum Place {
+    Std,
+    File(PathBuf),
+}
+
+impl Place {
+    fn new(arg: &str) -> Self {
+        if arg == "-" {
+            Place::Std
+        } else {
+            Place::File(PathBuf::from(arg))
+        }
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Place::Std => "-".to_string(),
+            Place::File(path) => path.display().to_string(),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Returns `false` if some of the files were out of date or couldn't be
+/// rewritten
+fn run() -> Result<bool, CorofyError> {
+    let mut options = Options::default();
+    let mut mode = Mode::Write;
+    let mut emit = Emit::Rust;
+    let mut remap_with = None;
+    let mut paths = vec![];
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let new_mode = match arg.as_str() {
+            "--check" => Mode::Check,
+            "--diff" => Mode::Diff,
+            "--in-place" => Mode::InPlace,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(true);
+            }
+            // `--target ch08` or `--target=ch08` can go anywhere
+            "--target" => {
+                let Some(name) = args.next() else {
+                    return Err(usage("`--target` needs a value: ch07, ch08, ch09-pin or std"));
+                };
+                options.target = name.parse().map_err(CorofyError::Usage)?;
+                continue;
+            }
+            _ if arg.starts_with("--target=") => {
+                options.target = arg["--target=".len()..].parse().map_err(CorofyError::Usage)?;
+                continue;
+            }
+            "--keywords" => {
+                let Some(keywords) = args.next() else {
+                    return Err(usage("`--keywords` needs the coroutine and the wait keyword, like `coroutine,wait`"));
+                };
+                options.syntax = keywords.parse().map_err(|e: String| usage(&e))?;
+                continue;
+            }
+            _ if arg.starts_with("--keywords=") => {
+                options.syntax = arg["--keywords=".len()..].parse().map_err(|e: String| usage(&e))?;
+                continue;
+            }
+            "--async" => {
+                options.syntax = Syntax::async_await();
+                continue;
+            }
+            "--emit" => {
+                let Some(name) = args.next() else {
+                    return Err(usage("`--emit` needs a value: rust or dot"));
+                };
+                emit = Emit::parse(&name)?;
+                continue;
+            }
+            _ if arg.starts_with("--emit=") => {
+                emit = Emit::parse(&arg["--emit=".len()..])?;
+                continue;
+            }
+            "--trace" => {
+                options.trace = Trace::Stderr;
+                continue;
+            }
+            _ if arg.starts_with("--trace=") => {
+                options.trace = arg["--trace=".len()..].parse().map_err(|e: String| usage(&e))?;
+                continue;
+            }
+            "--source-map" => {
+                options.source_map = true;
+                continue;
+            }
+            "--remap" => {
+                let Some(map) = args.next() else {
+                    return Err(usage("`--remap` needs the source map to use"));
+                };
+                remap_with = Some(PathBuf::from(map));
+                continue;
+            }
+            _ if arg.starts_with("--remap=") => {
+                remap_with = Some(PathBuf::from(&arg["--remap=".len()..]));
+                continue;
+            }
+            _ if arg.starts_with('-') && arg != "-" => {
+                return Err(usage(&format!("unknown option `{arg}`")));
+            }
+            _ => {
+                paths.push(arg);
+                continue;
+            }
+        };
+        if mode != Mode::Write && mode != new_mode {
+            return Err(usage("`--check`, `--diff` and `--in-place` can't be used together"));
+        }
+        mode = new_mode;
+    }
+
+    if let Some(map) = remap_with {
+        if !paths.is_empty() || mode != Mode::Write || emit != Emit::Rust || options.source_map || options.trace != Trace::Off {
+            return Err(usage("`--remap` reads what rustc wrote from stdin and takes nothing else"));
+        }
+        return remap(&map);
+    }
+    if options.source_map && matches!(mode, Mode::Check | Mode::Diff) {
+        return Err(usage("`--source-map` can't be used with `--check` or `--diff`"));
+    }
+
+    if emit == Emit::Dot {
+        if mode != Mode::Write || options.source_map || options.trace != Trace::Off {
+            return Err(usage("`--emit dot` can't be used with `--check`, `--diff`, `--in-place`, `--source-map` or `--trace`"));
+        }
+        return emit_dot(&options.syntax, &paths);
+    }
+
+    let jobs = jobs(&paths, mode)?;
+    let mut ok = true;
+    for (src, dest) in jobs {
+        match corofy(&options, mode, &src, &dest) {
+            Ok(up_to_date) => ok &= up_to_date,
+            Err(e) => {
+                eprintln!("{e}");
+                ok = false;
+            }
+        }
+    }
+    Ok(ok)
+}
+
+fn usage(msg: &str) -> CorofyError {
+    CorofyError::Usage(format!("{msg}\n\n{USAGE}"))
+}
+
+/// Pairs every source we're asked to rewrite with its destination
+fn jobs(paths: &[String], mode: Mode) -> Result<Vec<(Place, Place)>, CorofyError> {
+    let Some(first) = paths.first() else {
+        return Err(usage("Missing source file. Please provide a path to a source file and try again."));
     };
 
-    let dest = match args.get(2) {
-        Some(path) => PathBuf::from(path),
-        None => {
+    if Path::new(first).is_dir() {
+        if mode == Mode::InPlace {
+            return Err(usage("`--in-place` rewrites a single file, not a directory"));
+        }
+        let mut jobs = vec![];
+        for path in paths {
+            let path = Path::new(path);
+            if !path.is_dir() {
+                return Err(usage(&format!("`{}` isn't a directory", path.display())));
+            }
+            find_sources(path, &mut jobs).map_err(|e| CorofyError::from(e).with_file(path))?;
+        }
+        if jobs.is_empty() {
+            return Err(usage(&format!("no {} found", SOURCE_NAMES.join(" or "))));
+        }
+        // Checking or diffing a directory only looks at the files that
+        // are checked in
+        if mode != Mode::Write {
+            jobs.retain(|(src, dest)| match dest {
+                Place::File(path) if !path.exists() => {
+                    eprintln!("skipped {}: nothing to check against, {} doesn't exist", src.name(), dest.name());
+                    false
+                }
+                _ => true,
+            });
+        }
+        return Ok(jobs);
+    }
+
+    if paths.len() > 2 {
+        return Err(usage("expected a source and at most one destination"));
+    }
+    let src = Place::new(first);
+    let dest = match (paths.get(1), &src) {
+        _ if mode == Mode::InPlace => {
+            if paths.len() > 1 || matches!(src, Place::Std) {
+                return Err(usage("`--in-place` needs a single source file and no destination"));
+            }
+            src.clone()
+        }
+        (Some(dest), _) => Place::new(dest),
+        (None, Place::Std) => Place::Std,
+        (None, Place::File(src)) => {
             let src_n = src.file_stem().map(|x|x.to_string_lossy()).unwrap_or_default();
             let src_ext = src.extension().map(|x|x.to_string_lossy()).unwrap_or_default();
             let clone = format!("{src_n}_corofied.{src_ext}");
 
             match src.parent() {
-                Some(path) => path.join(&clone).clone(),
-                None => PathBuf::from("./").join(&clone),
+                Some(path) => Place::File(path.join(&clone)),
+                None => Place::File(PathBuf::from("./").join(&clone)),
             }
-        },
+        }
     };
+    Ok(vec![(src, dest)])
+}
 
-    let src = fs::read_to_string(src)?;
-    // Will truncate if exists
-    let dest = fs::File::create(dest)?;
-
-    if let Err(e) = rewrite(src, dest) {
-        println!("{e}");
+/// Looks for the sources of the examples under `dir`. Their rewritten code
+/// goes in `src/main_corofied.rs` next to them.
+fn find_sources(dir: &Path, jobs: &mut Vec<(Place, Place)>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if entry.file_type()?.is_dir() {
+            // Skip build output and hidden directories like `.git`
+            if name != "target" && !name.starts_with('.') {
+                find_sources(&path, jobs)?;
+            }
+        } else if SOURCE_NAMES.contains(&name.as_ref()) {
+            jobs.push((Place::File(path), Place::File(dir.join("src").join("main_corofied.rs"))));
+        }
     }
     Ok(())
 }
 
+/// Writes the graph of each coroutine in the source to `{stem}_{name}.dot`
+/// in the destination directory, which is next to the source unless we're
+/// told otherwise. All of them go to stdout if the destination is `-`, or
+/// if we read from stdin and got no destination.
+fn emit_dot(syntax: &Syntax, paths: &[String]) -> Result<bool, CorofyError> {
+    let (Some(first), 1..=2) = (paths.first(), paths.len()) else {
+        return Err(usage("`--emit dot` needs a source file and at most one directory to write to"));
+    };
+    if Path::new(first).is_dir() {
+        return Err(usage("`--emit dot` draws the coroutines of a single file, not a directory"));
+    }
+    let src = Place::new(first);
+    let code = read(&src)?;
+    let graphs = graphs(syntax, &code).map_err(|e| match &src {
+        Place::File(path) => e.with_file(path),
+        Place::Std => e,
+    })?;
+
+    let dir = match (paths.get(1).map(String::as_str), &src) {
+        (Some("-"), _) | (None, Place::Std) => None,
+        (Some(dir), _) => Some(PathBuf::from(dir)),
+        (None, Place::File(path)) => Some(path.parent().unwrap_or(Path::new(".")).to_path_buf()),
+    };
+    let Some(dir) = dir else {
+        let all: Vec<&str> = graphs.iter().map(|(_, graph)| graph.as_str()).collect();
+        write(&Place::Std, &all.join("\n"))?;
+        return Ok(true);
+    };
+
+    fs::create_dir_all(&dir).map_err(|e| CorofyError::from(e).with_file(&dir))?;
+    let stem = match &src {
+        Place::File(path) => path.file_stem().map(|x| x.to_string_lossy()).unwrap_or_default(),
+        Place::Std => "stdin".into(),
+    };
+    // Coroutines in different modules can have the same name
+    let mut taken = HashSet::new();
+    for (name, graph) in graphs {
+        let mut file = format!("{stem}_{name}.dot");
+        let mut n = 1;
+        while !taken.insert(file.clone()) {
+            n += 1;
+            file = format!("{stem}_{name}{n}.dot");
+        }
+        write(&Place::File(dir.join(file)), &graph)?;
+    }
+    Ok(true)
+}
+
+/// Points the locations in what rustc wrote to stdin that are in the
+/// file `map` is for at the coroutines they come from
+fn remap(map: &Path) -> Result<bool, CorofyError> {
+    let json = read(&Place::File(map.to_path_buf()))?;
+    let map = SourceMap::from_json(&json).map_err(|e| e.with_file(map))?;
+    let text = read(&Place::Std)?;
+    write(&Place::Std, &map.remap(&text))?;
+    Ok(true)
+}
+
+/// Rewrites `src` and does what `mode` says with the result. Returns
+/// `false` if `dest` is out of date when checking or diffing. With a
+/// source map, where each line comes from goes to `{dest}.map`.
+fn corofy(options: &Options, mode: Mode, src: &Place, dest: &Place) -> Result<bool, CorofyError> {
+    let code = read(src)?;
+    let (out, map) = rewrite(options, &code).map_err(|e| match src {
+        Place::File(path) => e.with_file(path),
+        Place::Std => e,
+    })?;
+
+    match mode {
+        Mode::Write | Mode::InPlace => {
+            if let Some(mut map) = map {
+                let Place::File(path) = dest else {
+                    return Err(usage("`--source-map` needs a file to write to, not stdout"));
+                };
+                map.file = dest.name();
+                map.source = src.name();
+                let mut map_path = path.clone().into_os_string();
+                map_path.push(".map");
+                write(&Place::File(map_path.into()), &map.to_json())?;
+            }
+            write(dest, &out)?;
+            Ok(true)
+        }
+        Mode::Check | Mode::Diff => {
+            // A destination that doesn't exist yet is as out of date as it gets
+            let old = match dest {
+                Place::File(path) if !path.exists() => String::new(),
+                dest => read(dest)?,
+            };
+            if old == out {
+                return Ok(true);
+            }
+            let name = dest.name();
+            if mode == Mode::Check {
+                eprintln!("{name} is out of date with {}", src.name());
+            } else {
+                let diff = TextDiff::from_lines(&old, &out);
+                let diff = diff.unified_diff().header(&name, &name).to_string();
+                write(&Place::Std, &diff)?;
+            }
+            Ok(false)
+        }
+    }
+}
+
+fn read(place: &Place) -> Result<String, CorofyError> {
+    let mut code = String::new();
+    match place {
+        Place::Std => io::stdin().read_to_string(&mut code).map(|_| code).map_err(CorofyError::from),
+        Place::File(path) => fs::read_to_string(path).map_err(|e| CorofyError::from(e).with_file(path)),
+    }
+}
+
+fn write(place: &Place, code: &str) -> Result<(), CorofyError> {
+    match place {
+        Place::Std => io::stdout().write_all(code.as_bytes()).map_err(CorofyError::from),
+        // Will truncate if exists
+        Place::File(path) => fs::write(path, code).map_err(|e| CorofyError::from(e).with_file(path)),
+    }
+}
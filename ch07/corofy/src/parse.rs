@@ -0,0 +1,1258 @@
+//! Finds the `coroutine fn`s in a source file and checks that we can
+//! transform them. Their bodies are lowered to a control-flow graph in `cfg`.
+//!
+//! `coroutine` isn't a Rust keyword, so `syn` can't parse our files as they
+//! are. Instead we tokenize the file first and swap every `coroutine` that's
+//! followed by `fn` for `async`. The tokens keep the spans they had in the
+//! original text, so after parsing we can still cut out the exact code the
+//! user wrote, comments and formatting included.
+use std::{borrow::Cow, collections::HashSet, iter, ops::Range};
+
+use proc_macro2::{Group, Ident, Spacing, Span, TokenStream, TokenTree};
+use quote::ToTokens;
+use syn::{
+    parse::Parser,
+    punctuated::Punctuated,
+    spanned::Spanned,
+    visit::{self, Visit},
+    Attribute, Block, Expr, FnArg, GenericParam, ImplItemFn, ItemFn, ItemImpl, ItemMod, Macro,
+    MacroDelimiter, Member, Pat, Receiver, ReturnType, Signature, Stmt, Token, TraitItemFn, Type,
+    Visibility,
+};
+
+use crate::cfg::{self, Cfg};
+use crate::{Syntax, W_KW};
+
+/// A `coroutine fn` split up into the parts we need to write its state machine.
+pub(crate) struct CoroutineFn {
+    /// The text we remove from the file: attributes, visibility and the function itself
+    pub(crate) span: Range<usize>,
+    /// Where we write the state machine. That's the end of the file, or the
+    /// end of the module the coroutine is declared in.
+    pub(crate) insert_at: usize,
+    /// The original function without its closing `}`
+    pub(crate) orig: String,
+    /// Where `orig` starts in the file
+    pub(crate) orig_start: usize,
+    pub(crate) attrs: Vec<String>,
+    pub(crate) vis: String,
+    pub(crate) name: String,
+    /// The arguments, starting with `this` for the receiver of a method
+    pub(crate) args: Vec<(String, String)>,
+    /// How the function we write in its place takes `self`, like `&'coro self`
+    pub(crate) receiver: Option<String>,
+    pub(crate) generics: Generics,
+    /// The indentation of a coroutine declared in an `impl` block. The
+    /// function that returns its state machine stays in the `impl` block.
+    pub(crate) in_impl: Option<String>,
+    pub(crate) cfg: Cfg,
+    /// The type the coroutine resolves to
+    pub(crate) output: String,
+    /// The expression we resolve to when we reach the end of the body
+    pub(crate) result: String,
+}
+
+pub(crate) fn parse(src: &str, syntax: &Syntax) -> syn::Result<Vec<CoroutineFn>> {
+    let tokens: TokenStream = src.parse().map_err(|e: proc_macro2::LexError| {
+        syn::Error::new(
+            e.span(),
+            "failed to tokenize the file (look for unbalanced delimiters or an unterminated string or comment)",
+        )
+    })?;
+
+    let mut keywords = vec![];
+    let mut blocks = vec![];
+    let tokens = replace_keywords(tokens, syntax, &mut keywords, &mut blocks);
+    let file: syn::File = syn::parse2(tokens)?;
+
+    let mut finder = Finder {
+        src,
+        syntax,
+        keywords: keywords.iter().map(|s| s.byte_range().start).collect(),
+        used: HashSet::new(),
+        insert_at: src.len(),
+        depth: 0,
+        impl_block: None,
+        coroutines: vec![],
+        errors: Errors::default(),
+    };
+    finder.visit_file(&file);
+
+    // Any keyword we didn't find a function for is used somewhere we don't support
+    for kw in keywords {
+        if !finder.used.contains(&kw.byte_range().start) {
+            finder.errors.push(syn::Error::new(
+                kw,
+                format!("`{} fn` can't be used here, only on functions and methods", syntax.coroutine()),
+            ));
+        }
+    }
+    // We turn the blocks into functions before we get here, when we can
+    for kw in blocks {
+        finder.errors.push(syn::Error::new(
+            kw,
+            format!("a `{}` block or closure can't be used here, only in a file corofy rewrites", syntax.coroutine()),
+        ));
+    }
+
+    finder.errors.finish()?;
+    Ok(finder.coroutines)
+}
+
+/// Replaces `coroutine` with `async` everywhere it's followed by `fn` and
+/// records the span of each keyword we replaced. The same goes for the
+/// `coroutine` in front of a block or a closure, which goes in `blocks`.
+/// The wait keyword becomes `wait` after a `.`, whatever the user calls it,
+/// so `fut.await` is the same field access `fut.wait` is to everything
+/// after this. A field called `wait` when that isn't the keyword becomes
+/// `r#wait`, so it stays a field.
+pub(crate) fn replace_keywords(
+    tokens: TokenStream,
+    syntax: &Syntax,
+    keywords: &mut Vec<Span>,
+    blocks: &mut Vec<Span>,
+) -> TokenStream {
+    let mut out = vec![];
+    let mut tokens = tokens.into_iter().peekable();
+    let mut prev_dot = false;
+    // A variable called `coroutine` can be followed by a block too, like
+    // in `if a && coroutine { .. }`, or by a `|`, so a block or a closure
+    // has to start an expression
+    let mut prev: Option<TokenTree> = None;
+    let mut before_prev_joint = None;
+    while let Some(tt) = tokens.next() {
+        let dot = matches!(&tt, TokenTree::Punct(p) if p.as_char() == '.');
+        let starts = starts_expr(prev.as_ref(), before_prev_joint);
+        before_prev_joint = match &prev {
+            Some(TokenTree::Punct(p)) if p.spacing() == Spacing::Joint => Some(p.as_char()),
+            _ => None,
+        };
+        prev = Some(tt.clone());
+        match tt {
+            TokenTree::Group(g) => {
+                let stream = replace_keywords(g.stream(), syntax, keywords, blocks);
+                let mut group = Group::new(g.delimiter(), stream);
+                group.set_span(g.span());
+                out.push(TokenTree::Group(group));
+            }
+            // `async unsafe fn` and `async extern fn` parse, so we get to
+            // tell why a coroutine can't be either
+            TokenTree::Ident(ident)
+                if ident == syntax.coroutine()
+                    && matches!(tokens.peek(), Some(TokenTree::Ident(next)) if next == "fn" || next == "unsafe" || next == "extern") =>
+            {
+                keywords.push(ident.span());
+                out.push(TokenTree::Ident(Ident::new("async", ident.span())));
+            }
+            TokenTree::Ident(ident)
+                if ident == syntax.coroutine() && starts && starts_block(tokens.clone()) =>
+            {
+                blocks.push(ident.span());
+                out.push(TokenTree::Ident(Ident::new("async", ident.span())));
+            }
+            TokenTree::Ident(ident) if prev_dot && ident == syntax.wait() => {
+                out.push(TokenTree::Ident(Ident::new(W_KW, ident.span())));
+            }
+            // A field that's really called `wait`, which no wait point is
+            // once it's a raw identifier
+            TokenTree::Ident(ident) if prev_dot && ident == W_KW => {
+                out.push(TokenTree::Ident(Ident::new_raw(W_KW, ident.span())));
+            }
+            tt => out.push(tt),
+        }
+        prev_dot = dot;
+    }
+    out.into_iter().collect()
+}
+
+/// If an expression can start after `prev`, which is the first token of a
+/// group when there's none. `before_prev` is the punctuation before it if
+/// they're joined, like the `=` of `=>`.
+fn starts_expr(prev: Option<&TokenTree>, before_prev: Option<char>) -> bool {
+    match prev {
+        None => true,
+        Some(TokenTree::Punct(p)) => match p.as_char() {
+            ',' | ';' => true,
+            // Not the end of `==`, `<=` or `+=`
+            '=' => before_prev.is_none(),
+            '>' => before_prev == Some('='),
+            _ => false,
+        },
+        Some(TokenTree::Ident(ident)) => ident == "return",
+        _ => false,
+    }
+}
+
+/// `{ .. }`, `move { .. }` or the `|` of a closure's arguments, which
+/// is `||` or has another `|` after the arguments. `coroutine | 2` is
+/// just an expression.
+fn starts_block(mut next: impl Iterator<Item = TokenTree>) -> bool {
+    match next.next() {
+        Some(TokenTree::Group(g)) => g.delimiter() == proc_macro2::Delimiter::Brace,
+        Some(TokenTree::Ident(ident)) => ident == "move",
+        Some(TokenTree::Punct(p)) if p.as_char() == '|' => {
+            p.spacing() == Spacing::Joint
+                || next
+                    .take_while(|tt| !matches!(tt, TokenTree::Punct(p) if p.as_char() == ';'))
+                    .take_while(|tt| !matches!(tt, TokenTree::Literal(_)))
+                    .any(|tt| matches!(tt, TokenTree::Punct(p) if p.as_char() == '|'))
+        }
+        _ => false,
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Errors(Option<syn::Error>);
+
+impl Errors {
+    pub(crate) fn push(&mut self, err: syn::Error) {
+        match &mut self.0 {
+            Some(errors) => errors.combine(err),
+            None => self.0 = Some(err),
+        }
+    }
+
+    pub(crate) fn extend(&mut self, errors: Errors) {
+        if let Some(err) = errors.0 {
+            self.push(err);
+        }
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.0.as_ref().map_or(0, |errors| errors.into_iter().count())
+    }
+
+    pub(crate) fn finish(self) -> syn::Result<()> {
+        match self.0 {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+struct Finder<'a> {
+    src: &'a str,
+    syntax: &'a Syntax,
+    /// Byte offset of every `coroutine` keyword we replaced
+    keywords: HashSet<usize>,
+    used: HashSet<usize>,
+    insert_at: usize,
+    /// How many blocks deep we are. Only functions at depth 0 are module level.
+    depth: usize,
+    /// The `impl` block we're in, if any
+    impl_block: Option<ImplBlock>,
+    coroutines: Vec<CoroutineFn>,
+    errors: Errors,
+}
+
+struct ImplBlock {
+    self_ty: String,
+    params: Vec<Param>,
+    predicates: Vec<String>,
+    /// `impl Trait for Type` rather than `impl Type`
+    of_trait: bool,
+}
+
+/// The generic parameters of a coroutine, and of the `impl` block it's in.
+/// The state machine needs all of them.
+#[derive(Default)]
+pub(crate) struct Generics {
+    /// The lifetime of the state machine. References in the arguments
+    /// that don't name a lifetime get this one, and every other lifetime
+    /// and type outlives it. Only generic coroutines and coroutines that
+    /// hold on to references have one.
+    pub(crate) lifetime: Option<String>,
+    pub(crate) impl_params: Vec<Param>,
+    pub(crate) params: Vec<Param>,
+    pub(crate) impl_predicates: Vec<String>,
+    pub(crate) predicates: Vec<String>,
+}
+
+#[derive(Clone)]
+pub(crate) struct Param {
+    /// How it's declared, like `T: Display`
+    pub(crate) decl: String,
+    pub(crate) name: String,
+    pub(crate) kind: ParamKind,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ParamKind {
+    Lifetime,
+    Type,
+    Const,
+}
+
+impl Generics {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.lifetime.is_none()
+    }
+
+    /// Lifetimes have to come first
+    fn sorted<'a>(&'a self, params: impl Iterator<Item = &'a Param>) -> Vec<&'a Param> {
+        let mut params: Vec<&Param> = params.collect();
+        params.sort_by_key(|p| p.kind != ParamKind::Lifetime);
+        params
+    }
+
+    fn all(&self) -> Vec<&Param> {
+        self.sorted(self.impl_params.iter().chain(&self.params))
+    }
+
+    fn list(&self, params: Vec<&Param>, decl: bool) -> String {
+        let Some(lifetime) = &self.lifetime else {
+            return String::new();
+        };
+        let params: Vec<&str> = params
+            .iter()
+            .map(|p| if decl { &p.decl } else { &p.name })
+            .map(String::as_str)
+            .collect();
+        format!(
+            "<{lifetime}{}>",
+            params.iter().map(|p| format!(", {p}")).collect::<String>()
+        )
+    }
+
+    /// `<'coro, 'a, T: Display>` for declaring the state machine
+    pub(crate) fn decl(&self) -> String {
+        self.list(self.all(), true)
+    }
+
+    /// `<'coro, 'a, T>` for using it
+    pub(crate) fn args(&self) -> String {
+        self.list(self.all(), false)
+    }
+
+    /// The parameters of the function that returns the state machine
+    pub(crate) fn fn_decl(&self) -> String {
+        self.list(self.sorted(self.params.iter()), true)
+    }
+
+    fn where_predicates(&self, impl_predicates: bool) -> String {
+        let Some(lifetime) = &self.lifetime else {
+            return String::new();
+        };
+        let outlives = self
+            .all()
+            .into_iter()
+            .filter(|p| p.kind != ParamKind::Const)
+            .map(|p| format!("{}: {lifetime}", p.name));
+        let impl_predicates = self.impl_predicates.iter().filter(|_| impl_predicates);
+        let predicates: Vec<String> = outlives
+            .chain(impl_predicates.cloned())
+            .chain(self.predicates.iter().cloned())
+            .collect();
+        if predicates.is_empty() {
+            String::new()
+        } else {
+            format!(" where {}", predicates.join(", "))
+        }
+    }
+
+    /// ` where T: 'coro, T: Display` for the state machine
+    pub(crate) fn where_clause(&self) -> String {
+        self.where_predicates(true)
+    }
+
+    /// The `where` clause of the function that returns the state machine,
+    /// which gets the predicates of the `impl` block from the `impl` block
+    pub(crate) fn fn_where_clause(&self) -> String {
+        self.where_predicates(false)
+    }
+
+    /// ` + use<'coro, 'a, T>`, since the future we return holds on to all of them
+    pub(crate) fn captures(&self) -> String {
+        match &self.lifetime {
+            Some(_) => format!(" + use{}", self.args()),
+            None => String::new(),
+        }
+    }
+
+    /// ` + 'coro`, so the futures we wait on can borrow what the coroutine borrows
+    pub(crate) fn bound(&self) -> String {
+        match &self.lifetime {
+            Some(lifetime) => format!(" + {lifetime}"),
+            None => String::new(),
+        }
+    }
+
+    /// A type that uses every parameter, for the parameters nothing else uses
+    pub(crate) fn marker(&self) -> Option<String> {
+        let lifetime = self.lifetime.as_ref()?;
+        let mut uses = vec![format!("&{lifetime} ()")];
+        for p in self.all() {
+            match p.kind {
+                ParamKind::Lifetime => uses.push(format!("&{} ()", p.name)),
+                ParamKind::Type => uses.push(format!("fn() -> {}", p.name)),
+                ParamKind::Const => (),
+            }
+        }
+        let uses = if uses.len() == 1 {
+            uses.remove(0)
+        } else {
+            format!("({})", uses.join(", "))
+        };
+        Some(format!("std::marker::PhantomData<{uses}>"))
+    }
+}
+
+const LIFETIME: &str = "'coro";
+/// What `self` is called in the state machine, since `self` is the state
+/// machine there. It has to be as long as `self` so every span still
+/// points at the same code after we rename it.
+pub(crate) const RECEIVER: &str = "this";
+
+impl Finder<'_> {
+    fn is_coroutine(&mut self, asyncness: &Option<syn::Token![async]>) -> bool {
+        let Some(kw) = asyncness else {
+            return false;
+        };
+        let start = kw.span.byte_range().start;
+        let found = self.keywords.contains(&start);
+        if found {
+            self.used.insert(start);
+        }
+        found
+    }
+
+    fn text(&self, span: Span) -> &str {
+        &self.src[span.byte_range()]
+    }
+
+    fn params(&self, generics: &syn::Generics, errors: &mut Errors) -> (Vec<Param>, Vec<String>) {
+        let mut params = vec![];
+        for param in &generics.params {
+            let (name, kind) = match param {
+                GenericParam::Lifetime(l) => (l.lifetime.to_string(), ParamKind::Lifetime),
+                GenericParam::Type(t) => (t.ident.to_string(), ParamKind::Type),
+                GenericParam::Const(c) => (c.ident.to_string(), ParamKind::Const),
+            };
+            if name == LIFETIME {
+                errors.push(syn::Error::new(
+                    param.span(),
+                    format!("`{LIFETIME}` is the lifetime we give the state machine, name this one something else"),
+                ));
+            }
+            params.push(Param {
+                decl: self.text(param.span()).to_string(),
+                name,
+                kind,
+            });
+        }
+        let predicates = generics
+            .where_clause
+            .iter()
+            .flat_map(|w| &w.predicates)
+            .map(|p| self.text(p.span()).to_string())
+            .collect();
+        (params, predicates)
+    }
+
+    /// The text of a type with `Self` replaced by `self_ty` and the
+    /// lifetimes it leaves out named `'coro`
+    fn type_text(
+        &self,
+        ty: &Type,
+        self_ty: Option<&str>,
+        elided: &mut bool,
+        errors: &mut Errors,
+    ) -> String {
+        let range = ty.span().byte_range();
+        let mut names = TypeNames {
+            self_ty,
+            edits: vec![],
+            errors,
+        };
+        names.visit_type(ty);
+        let mut text = self.src[range.clone()].to_string();
+        for (at, len, with) in names.edits.into_iter().rev() {
+            if with.starts_with(LIFETIME) {
+                *elided = true;
+            }
+            let at = at - range.start;
+            text.replace_range(at..at + len, &with);
+        }
+        text
+    }
+
+    /// The receiver of the function we write in place of the coroutine,
+    /// and the type of `this`
+    fn receiver(
+        &self,
+        recv: &Receiver,
+        self_ty: &str,
+        elided: &mut bool,
+        errors: &mut Errors,
+    ) -> (String, String) {
+        if recv.colon_token.is_some() {
+            let ty = self.type_text(&recv.ty, Some(self_ty), elided, errors);
+            let receiver = format!("self: {}", self.type_text(&recv.ty, None, elided, errors));
+            return (receiver, ty);
+        }
+        match &recv.reference {
+            Some((_, lifetime)) => {
+                let lifetime = match lifetime {
+                    Some(lifetime) => lifetime.to_string(),
+                    None => {
+                        *elided = true;
+                        LIFETIME.to_string()
+                    }
+                };
+                let mutability = if recv.mutability.is_some() {
+                    "mut "
+                } else {
+                    ""
+                };
+                (
+                    format!("&{lifetime} {mutability}self"),
+                    format!("&{lifetime} {mutability}{self_ty}"),
+                )
+            }
+            None => (String::from("self"), self_ty.to_string()),
+        }
+    }
+
+    fn coroutine_fn(
+        &self,
+        attrs: &[Attribute],
+        vis: &Visibility,
+        sig: &Signature,
+        block: &Block,
+    ) -> syn::Result<CoroutineFn> {
+        let mut errors = Errors::default();
+        let impl_block = self.impl_block.as_ref();
+        let self_ty = impl_block.map(|i| i.self_ty.as_str());
+
+        if let Some(kw) = &sig.constness {
+            errors.push(syn::Error::new(kw.span, "a coroutine can't be `const`"));
+        }
+        if let Some(kw) = &sig.unsafety {
+            errors.push(syn::Error::new(kw.span, "a coroutine can't be `unsafe`"));
+        }
+        if let Some(abi) = &sig.abi {
+            errors.push(syn::Error::new(abi.span(), "a coroutine can't be `extern`"));
+        }
+        if let Some(variadic) = &sig.variadic {
+            errors.push(syn::Error::new(
+                variadic.span(),
+                "a coroutine can't be variadic",
+            ));
+        }
+
+        let mut generics = Generics::default();
+        if let Some(impl_block) = impl_block {
+            generics.impl_params = impl_block.params.clone();
+            generics.impl_predicates = impl_block.predicates.clone();
+        }
+        (generics.params, generics.predicates) = self.params(&sig.generics, &mut errors);
+
+        // References that don't name their lifetime get `'coro`
+        let mut elided = false;
+        let output = match &sig.output {
+            ReturnType::Default => None,
+            ReturnType::Type(_, ty) => {
+                if let Type::ImplTrait(_) = &**ty {
+                    errors.push(syn::Error::new(
+                        ty.span(),
+                        "a coroutine can't return `impl Trait`, name the type instead",
+                    ));
+                }
+                Some(&**ty)
+            }
+        };
+        let output_ty = match output {
+            Some(ty) => self.type_text(ty, self_ty, &mut elided, &mut errors),
+            None => String::from(self.syntax.default_output().0),
+        };
+
+        let mut args = vec![];
+        let mut arg_types = vec![];
+        let mut receiver = None;
+        for arg in &sig.inputs {
+            match arg {
+                FnArg::Receiver(recv) => match self_ty {
+                    None => errors.push(syn::Error::new(
+                        recv.span(),
+                        "only a coroutine in an `impl` block can take `self`",
+                    )),
+                    Some(_) if recv.reference.is_none() && recv.mutability.is_some() => {
+                        errors.push(syn::Error::new(
+                            recv.span(),
+                            "a coroutine can't take `mut self`, take `self` and use `let mut me = self;` in the body",
+                        ))
+                    }
+                    Some(self_ty) => {
+                        let (recv, ty) = self.receiver(recv, self_ty, &mut elided, &mut errors);
+                        receiver = Some(recv);
+                        args.push((RECEIVER.to_string(), ty));
+                    }
+                },
+                FnArg::Typed(arg) => match &*arg.pat {
+                    Pat::Ident(p) if p.by_ref.is_none() && p.mutability.is_none() && p.subpat.is_none() => {
+                        let ty = self.type_text(&arg.ty, self_ty, &mut elided, &mut errors);
+                        args.push((p.ident.to_string(), ty));
+                        arg_types.push((p.ident.to_string(), &*arg.ty));
+                    }
+                    pat => errors.push(syn::Error::new(
+                        pat.span(),
+                        "coroutine arguments must be plain identifiers like `i: usize` (use `let mut i = i;` in the body if you need `mut`)",
+                    )),
+                },
+            }
+        }
+        if elided || !generics.impl_params.is_empty() || !generics.params.is_empty() {
+            generics.lifetime = Some(LIFETIME.to_string());
+        }
+
+        // `self` is the state machine in `poll`, so we call the receiver
+        // `this` instead
+        let mut uses = SelfUses::default();
+        uses.visit_block(block);
+        errors.extend(uses.errors);
+        let code = if receiver.is_some() && !uses.renames.is_empty() {
+            let mut code = self.src.to_string();
+            for at in uses.renames {
+                code.replace_range(at..at + RECEIVER.len(), RECEIVER);
+            }
+            Cow::Owned(code)
+        } else {
+            Cow::Borrowed(self.src)
+        };
+
+        let close = block.brace_token.span.close().byte_range();
+        let lowered = cfg::lower(&code, self.syntax, block, &args, &arg_types, output, &output_ty);
+        if let Err(e) = &lowered {
+            errors.push(e.clone());
+        }
+        errors.finish()?;
+        let (cfg, result) = lowered?;
+
+        let start = match (attrs.first(), vis) {
+            (Some(attr), _) => attr.span().byte_range().start,
+            (None, Visibility::Inherited) => sig.span().byte_range().start,
+            (None, vis) => vis.span().byte_range().start,
+        };
+        let span = line_start_if_blank_before(self.src, start)..close.end;
+        let vis = match vis {
+            Visibility::Inherited => String::new(),
+            vis => format!("{} ", self.text(vis.span())),
+        };
+
+        // The state machine of a method goes where it isn't indented
+        let indent = &self.src[span.start..start];
+        let orig = self.src[start..close.start].trim_end_matches(' ').lines();
+        let orig: String = orig
+            .map(|line| format!("{}\n", line.strip_prefix(indent).unwrap_or(line)))
+            .collect();
+
+        Ok(CoroutineFn {
+            in_impl: impl_block.map(|_| indent.to_string()),
+            span,
+            insert_at: self.insert_at,
+            orig: match impl_block {
+                Some(_) => orig,
+                None => self.src[start..close.start].to_string(),
+            },
+            orig_start: start,
+            attrs: attrs
+                .iter()
+                .map(|a| self.text(a.span()).to_string())
+                .collect(),
+            vis,
+            name: sig.ident.to_string(),
+            args,
+            receiver,
+            generics,
+            output: output_ty,
+            result,
+            cfg,
+        })
+    }
+}
+
+impl<'ast> Visit<'ast> for Finder<'_> {
+    fn visit_item_fn(&mut self, f: &'ast ItemFn) {
+        if self.is_coroutine(&f.sig.asyncness) {
+            if self.depth > 0 {
+                self.errors.push(syn::Error::new(
+                    f.sig.span(),
+                    "a coroutine must be declared at module level, not inside another function",
+                ));
+            } else {
+                match self.coroutine_fn(&f.attrs, &f.vis, &f.sig, &f.block) {
+                    Ok(coroutine) => self.coroutines.push(coroutine),
+                    Err(e) => self.errors.push(e),
+                }
+            }
+        }
+        visit::visit_item_fn(self, f);
+    }
+
+    fn visit_item_impl(&mut self, i: &'ast ItemImpl) {
+        let mut errors = Errors::default();
+        let (params, predicates) = self.params(&i.generics, &mut errors);
+        self.errors.extend(errors);
+        let outer = self.impl_block.replace(ImplBlock {
+            self_ty: self.text(i.self_ty.span()).to_string(),
+            params,
+            predicates,
+            of_trait: i.trait_.is_some(),
+        });
+        visit::visit_item_impl(self, i);
+        self.impl_block = outer;
+    }
+
+    fn visit_impl_item_fn(&mut self, f: &'ast ImplItemFn) {
+        if self.is_coroutine(&f.sig.asyncness) {
+            if self.depth > 0 {
+                self.errors.push(syn::Error::new(
+                    f.sig.span(),
+                    "a coroutine must be declared in an `impl` block at module level, not inside a function",
+                ));
+            } else if self.impl_block.as_ref().is_some_and(|i| i.of_trait) {
+                self.errors.push(syn::Error::new(
+                    f.sig.span(),
+                    "coroutines in trait impls are not supported, only in `impl Type` blocks",
+                ));
+            } else {
+                match self.coroutine_fn(&f.attrs, &f.vis, &f.sig, &f.block) {
+                    Ok(coroutine) => self.coroutines.push(coroutine),
+                    Err(e) => self.errors.push(e),
+                }
+            }
+        }
+        visit::visit_impl_item_fn(self, f);
+    }
+
+    fn visit_trait_item_fn(&mut self, f: &'ast TraitItemFn) {
+        if self.is_coroutine(&f.sig.asyncness) {
+            self.errors.push(syn::Error::new(
+                f.sig.span(),
+                "coroutines in traits are not supported, only in `impl Type` blocks",
+            ));
+        }
+        visit::visit_trait_item_fn(self, f);
+    }
+
+    fn visit_item_mod(&mut self, m: &'ast ItemMod) {
+        // Coroutines in an inline module are written at the end of that
+        // module so they stay in the same scope
+        let Some((brace, _)) = &m.content else {
+            return;
+        };
+        let outer = self.insert_at;
+        self.insert_at =
+            line_start_if_blank_before(self.src, brace.span.close().byte_range().start);
+        visit::visit_item_mod(self, m);
+        self.insert_at = outer;
+    }
+
+    fn visit_block(&mut self, b: &'ast syn::Block) {
+        self.depth += 1;
+        visit::visit_block(self, b);
+        self.depth -= 1;
+    }
+}
+
+/// Finds what we have to change in a type: `Self`, which means something
+/// else next to the state machine, and lifetimes that aren't named
+struct TypeNames<'a, 'e> {
+    self_ty: Option<&'a str>,
+    /// Where, how many bytes to replace, and what with
+    edits: Vec<(usize, usize, String)>,
+    errors: &'e mut Errors,
+}
+
+impl<'ast> Visit<'ast> for TypeNames<'_, '_> {
+    fn visit_type_reference(&mut self, r: &'ast syn::TypeReference) {
+        if r.lifetime.is_none() {
+            let at = r.and_token.span.byte_range().end;
+            self.edits.push((at, 0, format!("{LIFETIME} ")));
+        }
+        visit::visit_type_reference(self, r);
+    }
+
+    fn visit_lifetime(&mut self, l: &'ast syn::Lifetime) {
+        if l.ident == "_" {
+            let start = l.apostrophe.byte_range().start;
+            let end = l.ident.span().byte_range().end;
+            self.edits.push((start, end - start, LIFETIME.to_string()));
+        }
+    }
+
+    fn visit_path(&mut self, path: &'ast syn::Path) {
+        if let (Some(first), Some(self_ty)) = (path.segments.first(), self.self_ty) {
+            if first.ident == "Self" {
+                let range = first.ident.span().byte_range();
+                self.edits
+                    .push((range.start, range.len(), self_ty.to_string()));
+            }
+        }
+        visit::visit_path(self, path);
+    }
+
+    fn visit_type_impl_trait(&mut self, ty: &'ast syn::TypeImplTrait) {
+        self.errors.push(syn::Error::new(
+            ty.span(),
+            "a coroutine can't take `impl Trait`, use a type parameter instead",
+        ));
+    }
+
+    // The lifetimes left out in `fn(&str)` and `Fn(&str)` belong to them
+    fn visit_type_bare_fn(&mut self, _: &'ast syn::TypeBareFn) {}
+
+    fn visit_parenthesized_generic_arguments(
+        &mut self,
+        _: &'ast syn::ParenthesizedGenericArguments,
+    ) {
+    }
+}
+
+/// Finds `self` in the body of a coroutine so we can rename it, and
+/// what we can't rename: `Self` and variables that are already called `this`
+#[derive(Default)]
+struct SelfUses {
+    renames: Vec<usize>,
+    errors: Errors,
+}
+
+impl SelfUses {
+    fn ident(&mut self, ident: &Ident, path: bool) {
+        if ident == "self" && path {
+            self.renames.push(ident.span().byte_range().start);
+        } else if ident == "Self" {
+            self.errors.push(syn::Error::new(
+                ident.span(),
+                "`Self` would be the state machine after we rewrite the coroutine, name the type instead",
+            ));
+        } else if ident == RECEIVER {
+            self.errors.push(syn::Error::new(
+                ident.span(),
+                format!("`{RECEIVER}` is what we call `self` in the state machine, rename this variable"),
+            ));
+        }
+    }
+
+    fn scan_macro_tokens(&mut self, tokens: TokenStream) {
+        let mut tokens = tokens.into_iter().peekable();
+        let mut prev_dot = false;
+        while let Some(tt) = tokens.next() {
+            match &tt {
+                TokenTree::Ident(ident) if !prev_dot => {
+                    let path =
+                        !matches!(tokens.peek(), Some(TokenTree::Punct(p)) if p.as_char() == ':');
+                    self.ident(ident, path);
+                }
+                TokenTree::Group(g) => self.scan_macro_tokens(g.stream()),
+                _ => (),
+            }
+            prev_dot = matches!(&tt, TokenTree::Punct(p) if p.as_char() == '.');
+        }
+    }
+}
+
+impl<'ast> Visit<'ast> for SelfUses {
+    fn visit_expr_path(&mut self, path: &'ast syn::ExprPath) {
+        if let Some(ident) = path.path.get_ident() {
+            self.ident(ident, true);
+        }
+        visit::visit_expr_path(self, path);
+    }
+
+    fn visit_path(&mut self, path: &'ast syn::Path) {
+        if let Some(first) = path.segments.first() {
+            if first.ident == "Self" {
+                self.ident(&first.ident, false);
+            }
+        }
+        visit::visit_path(self, path);
+    }
+
+    fn visit_pat_ident(&mut self, p: &'ast syn::PatIdent) {
+        if p.ident == RECEIVER {
+            self.ident(&p.ident, false);
+        }
+        visit::visit_pat_ident(self, p);
+    }
+
+    fn visit_macro(&mut self, mac: &'ast Macro) {
+        self.scan_macro_tokens(mac.tokens.clone());
+    }
+
+    // `self` means something else in the items declared inside a coroutine
+    fn visit_item(&mut self, _: &'ast syn::Item) {}
+}
+
+/// Returns the future if `expr` is `fut.wait`
+pub(crate) fn waited_on(expr: &Expr) -> Option<&Expr> {
+    match expr {
+        Expr::Field(field) => match &field.member {
+            Member::Named(ident) if ident == W_KW => Some(&field.base),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Returns the future if `expr` is `fut.wait?`
+pub(crate) fn tried(expr: &Expr) -> Option<&Expr> {
+    match expr {
+        Expr::Try(expr) => waited_on(&expr.expr),
+        _ => None,
+    }
+}
+
+/// Returns the span of the expression if a statement is a tail expression
+/// that produces a value
+pub(crate) fn ends_in_expression(stmt: &Stmt) -> Option<Span> {
+    match stmt {
+        Stmt::Expr(expr, None) => match expr {
+            Expr::If(_)
+            | Expr::Match(_)
+            | Expr::Loop(_)
+            | Expr::While(_)
+            | Expr::ForLoop(_)
+            | Expr::Block(_)
+            | Expr::Unsafe(_)
+            | Expr::Const(_)
+            | Expr::TryBlock(_) => None,
+            expr => Some(expr.span()),
+        },
+        Stmt::Macro(m) if m.semi_token.is_none() => match m.mac.delimiter {
+            MacroDelimiter::Brace(_) => None,
+            _ => Some(m.span()),
+        },
+        _ => None,
+    }
+}
+
+/// A `.wait` in the middle of an expression, like the one in
+/// `println!("{}", fut.wait);`. We wait on it before the rest of the
+/// expression runs and put a variable with its output in its place.
+pub(crate) struct Lift {
+    /// `fut.wait` or `fut.wait?`
+    pub(crate) span: Span,
+    pub(crate) fut: Expr,
+    pub(crate) tried: bool,
+    /// The operands to the left of the wait that run code, in order. They
+    /// have to run before we wait, so we keep their values until we're done.
+    pub(crate) spills: Vec<Expr>,
+}
+
+/// Looks for things we can't handle in the code between two wait points
+pub(crate) struct StepChecker<'a> {
+    pub(crate) errors: Errors,
+    /// The waits we can lift out of the code, in the order they run. Unless
+    /// we're `lifting`, every `.wait` is an error instead.
+    pub(crate) lifts: Vec<Lift>,
+    /// The wait keyword the way the user spells it
+    wait: &'a str,
+    lifting: bool,
+    /// `return` and `?` are fine inside closures and async blocks
+    closures: usize,
+    /// How many branches, loops and blocks deep we are. A `.wait` in there
+    /// might not run, so we can't wait on it before everything else.
+    branches: usize,
+}
+
+impl<'a> StepChecker<'a> {
+    pub(crate) fn new(syntax: &'a Syntax) -> Self {
+        StepChecker {
+            errors: Errors::default(),
+            lifts: vec![],
+            wait: syntax.wait(),
+            lifting: false,
+            closures: 0,
+            branches: 0,
+        }
+    }
+
+    pub(crate) fn lifting(syntax: &'a Syntax) -> Self {
+        StepChecker {
+            lifting: true,
+            ..StepChecker::new(syntax)
+        }
+    }
+
+    fn wait(&mut self, span: Span, fut: &Expr, tried: bool, keyword: Span) {
+        let wait = self.wait;
+        if self.closures > 0 {
+            self.errors.push(syn::Error::new(
+                keyword,
+                format!("`.{wait}` can't be used inside a closure or an async block, only the coroutine itself can wait"),
+            ));
+        } else if !self.lifting || self.branches > 0 {
+            self.errors.push(syn::Error::new(
+                keyword,
+                format!("`.{wait}` can't be used after `&&` or `||`, or in a branch, loop or block inside an expression, since it might not run. Use an `if` statement instead"),
+            ));
+        } else {
+            // The waits inside `fut` are lifted out of it when we lift it
+            let fut = fut.clone();
+            self.lifts.push(Lift { span, fut, tried, spills: vec![] });
+            return;
+        }
+        self.visit_expr(fut);
+    }
+
+    /// Visits operands that run left to right. Since we wait before any of
+    /// them run, the ones that run code before a wait run before it instead.
+    fn operands<'e>(&mut self, exprs: impl IntoIterator<Item = &'e Expr>) {
+        let mut before = vec![];
+        for expr in exprs {
+            let first = self.lifts.len();
+            self.visit_expr(expr);
+            if let Some(lift) = self.lifts.get_mut(first) {
+                lift.spills.splice(0..0, before.drain(..));
+            }
+            if runs_code(expr) {
+                before.push(expr.clone());
+            }
+        }
+    }
+
+    fn branch(&mut self, visit: impl FnOnce(&mut Self)) {
+        self.branches += 1;
+        visit(self);
+        self.branches -= 1;
+    }
+
+    fn scan_macro_tokens(&mut self, tokens: TokenStream) {
+        let mut prev_dot = false;
+        for tt in tokens {
+            match &tt {
+                TokenTree::Ident(ident) if prev_dot && ident == W_KW => {
+                    self.errors.push(syn::Error::new(
+                        ident.span(),
+                        format!("`.{}` can only be used in macros that take expressions separated by commas, like `println!`", self.wait),
+                    ));
+                }
+                TokenTree::Group(g) => self.scan_macro_tokens(g.stream()),
+                _ => (),
+            }
+            prev_dot = matches!(&tt, TokenTree::Punct(p) if p.as_char() == '.');
+        }
+    }
+}
+
+impl<'ast> Visit<'ast> for StepChecker<'_> {
+    fn visit_expr_field(&mut self, field: &'ast syn::ExprField) {
+        match &field.member {
+            Member::Named(ident) if ident == W_KW => {
+                self.wait(field.span(), &field.base, false, ident.span())
+            }
+            _ => visit::visit_expr_field(self, field),
+        }
+    }
+
+    fn visit_expr_return(&mut self, ret: &'ast syn::ExprReturn) {
+        if self.closures == 0 {
+            self.errors.push(syn::Error::new(
+                ret.return_token.span,
+                "`return` is only supported on a statement of its own, like `return x;`",
+            ));
+        }
+        visit::visit_expr_return(self, ret);
+    }
+
+    fn visit_expr_try(&mut self, expr: &'ast syn::ExprTry) {
+        if let Expr::Field(field) = &*expr.expr {
+            if let Member::Named(ident) = &field.member {
+                if ident == W_KW {
+                    return self.wait(expr.span(), &field.base, true, ident.span());
+                }
+            }
+        }
+        if self.closures == 0 {
+            let wait = self.wait;
+            self.errors.push(syn::Error::new(
+                expr.question_token.span,
+                format!("the `?` operator is only supported right after `.{wait}`, like `let txt = fut.{wait}?;`"),
+            ));
+        }
+        visit::visit_expr_try(self, expr);
+    }
+
+    fn visit_expr_binary(&mut self, expr: &'ast syn::ExprBinary) {
+        match expr.op {
+            // The right side only runs if the left side doesn't decide it
+            syn::BinOp::And(_) | syn::BinOp::Or(_) => {
+                self.visit_expr(&expr.left);
+                self.branch(|c| c.visit_expr(&expr.right));
+            }
+            _ => self.operands([&*expr.left, &*expr.right]),
+        }
+    }
+
+    fn visit_expr_call(&mut self, expr: &'ast syn::ExprCall) {
+        self.operands(iter::once(&*expr.func).chain(&expr.args));
+    }
+
+    fn visit_expr_method_call(&mut self, expr: &'ast syn::ExprMethodCall) {
+        self.operands(iter::once(&*expr.receiver).chain(&expr.args));
+    }
+
+    fn visit_expr_index(&mut self, expr: &'ast syn::ExprIndex) {
+        self.operands([&*expr.expr, &*expr.index]);
+    }
+
+    fn visit_expr_tuple(&mut self, expr: &'ast syn::ExprTuple) {
+        self.operands(&expr.elems);
+    }
+
+    fn visit_expr_array(&mut self, expr: &'ast syn::ExprArray) {
+        self.operands(&expr.elems);
+    }
+
+    fn visit_expr_struct(&mut self, expr: &'ast syn::ExprStruct) {
+        let fields = expr.fields.iter().map(|field| &field.expr);
+        self.operands(fields.chain(expr.rest.as_deref()));
+    }
+
+    fn visit_expr_if(&mut self, expr: &'ast syn::ExprIf) {
+        self.visit_expr(&expr.cond);
+        self.branch(|c| {
+            c.visit_block(&expr.then_branch);
+            if let Some((_, els)) = &expr.else_branch {
+                c.visit_expr(els);
+            }
+        });
+    }
+
+    fn visit_expr_match(&mut self, expr: &'ast syn::ExprMatch) {
+        self.visit_expr(&expr.expr);
+        self.branch(|c| {
+            for arm in &expr.arms {
+                c.visit_arm(arm);
+            }
+        });
+    }
+
+    fn visit_expr_for_loop(&mut self, expr: &'ast syn::ExprForLoop) {
+        self.visit_expr(&expr.expr);
+        self.branch(|c| c.visit_block(&expr.body));
+    }
+
+    fn visit_expr_while(&mut self, expr: &'ast syn::ExprWhile) {
+        self.branch(|c| visit::visit_expr_while(c, expr));
+    }
+
+    fn visit_expr_loop(&mut self, expr: &'ast syn::ExprLoop) {
+        self.branch(|c| visit::visit_expr_loop(c, expr));
+    }
+
+    // Variables declared in a block go out of scope at its end, so we
+    // can't wait on anything in there before the block starts either
+    fn visit_block(&mut self, block: &'ast Block) {
+        self.branch(|c| visit::visit_block(c, block));
+    }
+
+    fn visit_expr_closure(&mut self, closure: &'ast syn::ExprClosure) {
+        self.closures += 1;
+        visit::visit_expr_closure(self, closure);
+        self.closures -= 1;
+    }
+
+    fn visit_expr_async(&mut self, block: &'ast syn::ExprAsync) {
+        self.closures += 1;
+        visit::visit_expr_async(self, block);
+        self.closures -= 1;
+    }
+
+    fn visit_macro(&mut self, mac: &'ast Macro) {
+        // Most macros that take expressions, like `println!`, take them
+        // separated by commas. We can't tell what the others do with their
+        // tokens, but we can at least tell that someone tried to wait in there.
+        let parser = Punctuated::<Expr, Token![,]>::parse_terminated;
+        match parser.parse2(mac.tokens.clone()) {
+            Ok(exprs) => self.operands(&exprs),
+            Err(_) => self.scan_macro_tokens(mac.tokens.clone()),
+        }
+    }
+
+    // Functions and other items declared inside a coroutine are ordinary
+    // code, which can't wait. A coroutine in there is reported where we
+    // look for coroutines.
+    fn visit_item(&mut self, item: &'ast syn::Item) {
+        if matches!(item, syn::Item::Fn(f) if f.sig.asyncness.is_some()) {
+            return;
+        }
+        fn scan(tokens: TokenStream, found: &mut Vec<Span>) {
+            let mut prev_dot = false;
+            for tt in tokens {
+                match &tt {
+                    TokenTree::Ident(ident) if prev_dot && ident == W_KW => found.push(ident.span()),
+                    TokenTree::Group(g) => scan(g.stream(), found),
+                    _ => (),
+                }
+                prev_dot = matches!(&tt, TokenTree::Punct(p) if p.as_char() == '.');
+            }
+        }
+        let mut found = vec![];
+        scan(item.to_token_stream(), &mut found);
+        for span in found {
+            self.errors.push(syn::Error::new(
+                span,
+                format!("`.{}` can't be used outside a coroutine, and a function or macro declared inside one is outside of it", self.wait),
+            ));
+        }
+    }
+}
+
+/// Whether working out `expr` runs code that a wait could see the effects
+/// of, or that could see the effects of a wait, like calling a function.
+/// The waits in it don't count, since we wait on those before anything else.
+fn runs_code(expr: &Expr) -> bool {
+    match expr {
+        Expr::Lit(_) | Expr::Path(_) | Expr::Closure(_) => false,
+        Expr::Field(field) => match &field.member {
+            Member::Named(ident) if ident == W_KW => false,
+            _ => runs_code(&field.base),
+        },
+        Expr::Try(expr) => runs_code(&expr.expr),
+        Expr::Reference(expr) => runs_code(&expr.expr),
+        Expr::Paren(expr) => runs_code(&expr.expr),
+        Expr::Group(expr) => runs_code(&expr.expr),
+        Expr::Cast(expr) => runs_code(&expr.expr),
+        Expr::Unary(expr) => runs_code(&expr.expr),
+        Expr::Binary(expr) => runs_code(&expr.left) || runs_code(&expr.right),
+        Expr::Tuple(expr) => expr.elems.iter().any(runs_code),
+        Expr::Array(expr) => expr.elems.iter().any(runs_code),
+        _ => true,
+    }
+}
+
+/// Moves `pos` back to the start of the line if there's only whitespace
+/// in front of it on that line.
+pub(crate) fn line_start_if_blank_before(src: &str, pos: usize) -> usize {
+    let before = &src[..pos];
+    let trimmed = before.trim_end_matches([' ', '\t']);
+    if trimmed.is_empty() || trimmed.ends_with('\n') {
+        trimmed.len()
+    } else {
+        pos
+    }
+}
+
+/// Moves `pos` past the end of the line if there's only whitespace or a
+/// comment after it on that line.
+pub(crate) fn line_end_if_blank_after(src: &str, pos: usize) -> usize {
+    let after = &src[pos..];
+    let mut trimmed = after.trim_start_matches([' ', '\t', '\r']);
+    if trimmed.starts_with("//") {
+        trimmed = trimmed.trim_start_matches(|c| c != '\n');
+    }
+    if trimmed.is_empty() {
+        src.len()
+    } else if trimmed.starts_with('\n') {
+        src.len() - trimmed.len() + 1
+    } else {
+        pos
+    }
+}
+
+/// Every line ends with `\n` in the generated code
+pub(crate) fn lines(s: &str) -> String {
+    s.lines().map(|line| format!("{line}\n")).collect()
+}
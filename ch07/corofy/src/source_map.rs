@@ -0,0 +1,244 @@
+//! Where the code corofy writes comes from.
+//!
+//! Most of a state machine is code the user wrote, pasted into the arms of
+//! a `match`, so when it doesn't compile or panics, rustc points at lines
+//! of `main_corofied.rs` nobody wrote. A source map knows which line of the
+//! source each line we write comes from, and points rustc's diagnostics
+//! and panic messages back there.
+use std::fmt::Write;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::CorofyError;
+
+/// Where a line we write comes from. In JSON it's `[line, shift]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "(usize, isize)", try_from = "(usize, isize)")]
+pub(crate) struct Origin {
+    /// The line in the source, counting from 1
+    pub(crate) line: usize,
+    /// How many columns further right the code is in the source
+    pub(crate) shift: isize,
+}
+
+impl From<Origin> for (usize, isize) {
+    fn from(origin: Origin) -> Self {
+        (origin.line, origin.shift)
+    }
+}
+
+impl TryFrom<(usize, isize)> for Origin {
+    type Error = &'static str;
+
+    fn try_from((line, shift): (usize, isize)) -> Result<Self, Self::Error> {
+        match line {
+            0 => Err("lines count from 1"),
+            line => Ok(Origin { line, shift }),
+        }
+    }
+}
+
+/// Where the lines of some code we wrote come from, counting from 0 at its
+/// first line. Lines that aren't there don't come from the source.
+pub(crate) type Lines = Vec<(usize, Origin)>;
+
+/// The line of the source each line of the code we write comes from.
+/// Lines we make up ourselves, like the `State` enum, don't come from any.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceMap {
+    /// Written so we can tell later versions apart, ignored when we read
+    #[serde(serialize_with = "version", skip_deserializing)]
+    version: (),
+    /// The file we wrote, like `src/main_corofied.rs`
+    pub file: String,
+    /// The file we read, like `original_main.rs`
+    pub source: String,
+    lines: Vec<Option<Origin>>,
+}
+
+impl SourceMap {
+    /// Where `line:column` in the code we wrote is in the source. Lines and
+    /// columns count from 1, like rustc counts them. Returns `None` for
+    /// code we made up.
+    pub fn original(&self, line: usize, column: usize) -> Option<(usize, usize)> {
+        let origin = (*self.lines.get(line.checked_sub(1)?)?)?;
+        let column = (column as isize + origin.shift).max(1) as usize;
+        Some((origin.line, column))
+    }
+
+    /// Points the lines at the code `to` says they come from instead
+    pub(crate) fn map_origins(&mut self, to: impl Fn(Origin) -> Origin) {
+        for origin in self.lines.iter_mut().flatten() {
+            *origin = to(*origin);
+        }
+    }
+
+    /// Points every `file:line:column` in `text` that's in the code we
+    /// wrote at the same code in the source instead. That's where rustc's
+    /// diagnostics (`--> src/main_corofied.rs:57:13`) and panic messages
+    /// (`panicked at src/main_corofied.rs:57:13`) point. Rustc writes the
+    /// path relative to the package, so we only look at the file name.
+    pub fn remap(&self, text: &str) -> String {
+        let name = Path::new(&self.file)
+            .file_name()
+            .map_or(self.file.clone(), |name| {
+                name.to_string_lossy().into_owned()
+            });
+        if name.is_empty() {
+            return text.to_string();
+        }
+
+        let mut out = String::new();
+        let mut rest = text;
+        while let Some(i) = rest.find(&name) {
+            let end = i + name.len();
+            let start = rest[..i]
+                .char_indices()
+                .rev()
+                .find(|(_, c)| c.is_whitespace() || "([<'\"`".contains(*c))
+                .map_or(0, |(j, c)| j + c.len_utf8());
+            let path = &rest[start..end];
+            let whole = path == name
+                || path.ends_with(&format!("/{name}"))
+                || path.ends_with(&format!("\\{name}"));
+            let found = location(&rest[end..])
+                .filter(|_| whole)
+                .and_then(|(line, column, len)| Some((self.original(line, column)?, len)));
+            match found {
+                Some(((line, column), len)) => {
+                    out.push_str(&rest[..start]);
+                    write!(out, "{}:{line}:{column}", self.source).unwrap();
+                    rest = &rest[end + len..];
+                }
+                _ => {
+                    out.push_str(&rest[..end]);
+                    rest = &rest[end..];
+                }
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+
+    /// Writes the map as JSON. `lines` has an entry for each line we
+    /// wrote: `null` if we made it up, or the line in the source and how
+    /// many columns further right the code is there.
+    pub fn to_json(&self) -> String {
+        let mut out = serde_json::to_string(self).unwrap();
+        out.push('\n');
+        out
+    }
+
+    /// Reads a map written by `to_json`
+    pub fn from_json(json: &str) -> Result<Self, CorofyError> {
+        serde_json::from_str(json).map_err(|e| {
+            CorofyError::from(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("not a corofy source map: {e}"),
+            ))
+        })
+    }
+}
+
+fn version<S: Serializer>(_: &(), serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u32(1)
+}
+
+/// Reads `:line:column` from the start of `s`. Returns them and how long
+/// they are.
+fn location(s: &str) -> Option<(usize, usize, usize)> {
+    let number = |s: &str| {
+        let s = s.strip_prefix(':')?;
+        let digits = s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        Some((s[..digits].parse().ok()?, digits + 1))
+    };
+    let (line, a) = number(s)?;
+    let (column, b) = number(&s[a..])?;
+    Some((line, column, a + b))
+}
+
+/// The code we write, and where each of its lines comes from
+pub(crate) struct Output {
+    pub(crate) text: String,
+    /// One for every line of `text`, including the one we're writing
+    lines: Vec<Option<Origin>>,
+}
+
+impl Output {
+    pub(crate) fn new() -> Self {
+        Output {
+            text: String::new(),
+            lines: vec![None],
+        }
+    }
+
+    /// Writes `text`, where the lines in `origins` come from the source.
+    /// They count from 0 at the first line of `text`.
+    pub(crate) fn push(&mut self, text: &str, origins: &[(usize, Origin)]) {
+        let first = self.lines.len() - 1;
+        let column = self.text.len() - self.text.rfind('\n').map_or(0, |i| i + 1);
+        self.text.push_str(text);
+        self.lines.extend(text.matches('\n').map(|_| None));
+        for &(i, mut origin) in origins {
+            let line = &mut self.lines[first + i];
+            // A line that starts with code we copied keeps pointing there
+            if line.is_none() {
+                if i == 0 {
+                    origin.shift -= column as isize;
+                }
+                *line = Some(origin);
+            }
+        }
+    }
+
+    /// Copies `src[range]` as it is
+    pub(crate) fn copy(&mut self, src: &str, range: Range<usize>) {
+        let line = src[..range.start].matches('\n').count() + 1;
+        let line_start = src[..range.start].rfind('\n').map_or(0, |i| i + 1);
+        let column = src[line_start..range.start].chars().count() as isize;
+        let text = &src[range];
+        let origins: Vec<(usize, Origin)> = text
+            .split('\n')
+            .enumerate()
+            .filter(|(_, code)| !code.is_empty())
+            .map(|(i, _)| {
+                let shift = if i == 0 { column } else { 0 };
+                (
+                    i,
+                    Origin {
+                        line: line + i,
+                        shift,
+                    },
+                )
+            })
+            .collect();
+        self.push(text, &origins);
+    }
+
+    /// What we wrote and where its lines come from, to write it again
+    /// somewhere else
+    pub(crate) fn into_parts(self) -> (String, Lines) {
+        let lines = self
+            .lines
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, origin)| Some((i, origin?)))
+            .collect();
+        (self.text, lines)
+    }
+
+    pub(crate) fn finish(mut self) -> (String, SourceMap) {
+        // The file ends with a line break, not with another line
+        if self.text.ends_with('\n') {
+            self.lines.pop();
+        }
+        let map = SourceMap {
+            lines: self.lines,
+            ..SourceMap::default()
+        };
+        (self.text, map)
+    }
+}
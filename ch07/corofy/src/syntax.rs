@@ -0,0 +1,106 @@
+//! The keywords that mark a coroutine and the points where it waits.
+//!
+//! The book writes `coroutine fn` and `fut.wait`, but any two words Rust
+//! doesn't use already work just as well. `async fn` and `fut.await` read
+//! code that already compiles, so what rustc makes of it can be compared
+//! with what we make of it.
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::{FN_KW, W_KW};
+
+/// The keywords we look for in the code we rewrite
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Syntax {
+    coroutine: String,
+    wait: String,
+}
+
+impl Syntax {
+    /// `coroutine` goes in front of `fn` and `wait` after the `.` at a wait
+    /// point. Both have to be identifiers that aren't Rust keywords, unless
+    /// they're `async` and `await`.
+    pub fn new(coroutine: &str, wait: &str) -> Result<Self, String> {
+        if (coroutine, wait) != ("async", "await") {
+            for kw in [coroutine, wait] {
+                if syn::parse_str::<syn::Ident>(kw).is_err() {
+                    return Err(format!("`{kw}` can't be a keyword, it has to be an identifier that isn't a Rust keyword"));
+                }
+            }
+        }
+        if coroutine == wait {
+            return Err(String::from(
+                "the coroutine and wait keywords can't be the same",
+            ));
+        }
+        Ok(Syntax {
+            coroutine: coroutine.to_string(),
+            wait: wait.to_string(),
+        })
+    }
+
+    /// `async fn` and `fut.await`, like in Rust
+    pub fn async_await() -> Self {
+        Syntax {
+            coroutine: String::from("async"),
+            wait: String::from("await"),
+        }
+    }
+
+    pub fn coroutine(&self) -> &str {
+        &self.coroutine
+    }
+
+    pub fn wait(&self) -> &str {
+        &self.wait
+    }
+
+    /// We read the code the way rustc does
+    pub(crate) fn is_async(&self) -> bool {
+        self.coroutine == "async"
+    }
+
+    /// The type a coroutine without a return type resolves to, and the
+    /// value it resolves to when it runs out of code. That's `()` in Rust,
+    /// but in the book it's an empty `String`.
+    pub(crate) fn default_output(&self) -> (&'static str, &'static str) {
+        if self.is_async() {
+            ("()", "()")
+        } else {
+            ("String", "String::new()")
+        }
+    }
+}
+
+impl Default for Syntax {
+    fn default() -> Self {
+        Syntax {
+            coroutine: FN_KW.to_string(),
+            wait: W_KW.to_string(),
+        }
+    }
+}
+
+/// `coroutine,wait`, or `async` for `async,await`
+impl FromStr for Syntax {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "async" {
+            return Ok(Syntax::async_await());
+        }
+        match s.split_once(',') {
+            Some((coroutine, wait)) => Syntax::new(coroutine.trim(), wait.trim()),
+            None => Err(format!(
+                "expected the coroutine and the wait keyword separated by a comma, like `{}`, got `{s}`",
+                Syntax::default()
+            )),
+        }
+    }
+}
+
+impl Display for Syntax {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{}", self.coroutine, self.wait)
+    }
+}
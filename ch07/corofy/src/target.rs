@@ -0,0 +1,97 @@
+//! The `Future` trait the state machines we write implement.
+//!
+//! Every chapter changes the trait a little: chapter 7 polls with no
+//! arguments, chapter 8 passes a `Waker` along, chapter 9 pins `self` and
+//! chapter 10 switches to the `Future` trait in the standard library.
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// Which chapter's `Future` trait to write the state machines for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Target {
+    /// `fn poll(&mut self) -> PollState<Self::Output>`
+    #[default]
+    Ch07,
+    /// `fn poll(&mut self, waker: &Waker) -> PollState<Self::Output>`
+    Ch08,
+    /// `fn poll(self: Pin<&mut Self>, waker: &Waker) -> PollState<Self::Output>`
+    Ch09Pin,
+    /// `std::future::Future`
+    Std,
+}
+
+impl Target {
+    pub const ALL: [Target; 4] = [Target::Ch07, Target::Ch08, Target::Ch09Pin, Target::Std];
+
+    /// `poll` takes `self: Pin<&mut Self>` and the futures we wait on are pinned
+    pub(crate) fn pins(self) -> bool {
+        matches!(self, Target::Ch09Pin | Target::Std)
+    }
+
+    /// The trait the state machines implement. We name the one in `std` in
+    /// full, since code written for it usually doesn't import it.
+    pub(crate) fn future(self) -> &'static str {
+        match self {
+            Target::Std => "std::future::Future",
+            _ => "Future",
+        }
+    }
+
+    /// The arguments `poll` takes after `self`
+    pub(crate) fn poll_args(self) -> &'static str {
+        match self {
+            Target::Ch07 => "",
+            Target::Ch08 | Target::Ch09Pin => ", waker: &Waker",
+            Target::Std => ", cx: &mut std::task::Context<'_>",
+        }
+    }
+
+    /// What we pass on when we poll the futures we wait on
+    pub(crate) fn waker(self) -> &'static str {
+        match self {
+            Target::Ch07 => "",
+            Target::Ch08 | Target::Ch09Pin => "waker",
+            Target::Std => "cx",
+        }
+    }
+
+    /// The type `poll` returns
+    pub(crate) fn poll_state(self) -> &'static str {
+        match self {
+            Target::Std => "std::task::Poll",
+            _ => "PollState",
+        }
+    }
+
+    pub(crate) fn not_ready(self) -> &'static str {
+        match self {
+            Target::Std => "Pending",
+            _ => "NotReady",
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Target::ALL
+            .into_iter()
+            .find(|t| t.to_string() == s)
+            .ok_or_else(|| {
+                format!("unknown target `{s}`, expected one of ch07, ch08, ch09-pin or std")
+            })
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Target::Ch07 => "ch07",
+            Target::Ch08 => "ch08",
+            Target::Ch09Pin => "ch09-pin",
+            Target::Std => "std",
+        };
+        f.write_str(name)
+    }
+}
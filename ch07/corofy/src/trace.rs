@@ -0,0 +1,89 @@
+//! Tracing what a state machine does while it runs.
+//!
+//! A traced coroutine reports every time it's polled, every state it
+//! enters and every time it returns `NotReady`, with the name of the
+//! coroutine, the event and the state:
+//!
+//! - `poll` when `poll` is called, with the state it's in
+//! - `enter` when it moves to another state, with that state
+//! - `not_ready` when `poll` returns `NotReady`, with the state it waits in
+//!
+//! It prints them to stderr, or passes them to a function with the same
+//! arguments, like `fn hook(coroutine: &str, event: &str, state: &str)`.
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// How the state machines we write report what they do
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Trace {
+    /// They don't
+    #[default]
+    Off,
+    /// `eprintln!("{coroutine}: {event} {state}")`
+    Stderr,
+    /// They call the function at this path
+    Hook(String),
+}
+
+impl Trace {
+    /// Reports that `poll` was called in the state `me` is in
+    pub(crate) fn poll(&self, coroutine: &str, me: &str) -> Option<String> {
+        let state = format!("{me}.state.name()");
+        self.call(coroutine, "poll", &state, None)
+    }
+
+    /// Reports `event` in `state`, which we know the name of
+    pub(crate) fn event(&self, coroutine: &str, event: &str, state: &str) -> Option<String> {
+        self.call(coroutine, event, &format!("{state:?}"), Some(state))
+    }
+
+    /// The statement that reports `event`. `state` is the code for the
+    /// name of the state, and `name` the name if we know it already.
+    fn call(
+        &self,
+        coroutine: &str,
+        event: &str,
+        state: &str,
+        name: Option<&str>,
+    ) -> Option<String> {
+        match (self, name) {
+            (Trace::Off, _) => None,
+            (Trace::Stderr, Some(name)) => {
+                Some(format!("eprintln!(\"{coroutine}: {event} {name}\");"))
+            }
+            (Trace::Stderr, None) => Some(format!(
+                "eprintln!(\"{coroutine}: {event} {{}}\", {state});"
+            )),
+            (Trace::Hook(hook), _) => {
+                Some(format!("{hook}(\"{coroutine}\", \"{event}\", {state});"))
+            }
+        }
+    }
+}
+
+/// `stderr`, or the path of the hook function
+impl FromStr for Trace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "stderr" {
+            return Ok(Trace::Stderr);
+        }
+        match syn::parse_str::<syn::Path>(s) {
+            Ok(_) => Ok(Trace::Hook(s.to_string())),
+            Err(_) => Err(format!(
+                "expected `stderr` or the path of a function to trace with, got `{s}`"
+            )),
+        }
+    }
+}
+
+impl Display for Trace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Trace::Off => f.write_str("off"),
+            Trace::Stderr => f.write_str("stderr"),
+            Trace::Hook(hook) => f.write_str(hook),
+        }
+    }
+}
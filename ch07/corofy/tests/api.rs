@@ -0,0 +1,191 @@
+use std::fs;
+
+use corofy::{graphs, rewrite, rewrite_into, Options, SourceMap, Syntax, Target, Trace};
+
+#[test]
+fn rewrites_more_than_once() {
+    let src = fs::read_to_string("./tests/test4/input.txt").unwrap();
+    let mut first = vec![];
+    rewrite_into(&Options::default(), &src, &mut first).unwrap();
+    let mut second = vec![];
+    rewrite_into(&Options::default(), &src, &mut second).unwrap();
+
+    assert!(!first.is_empty());
+    assert_eq!(first, second);
+}
+
+#[test]
+fn writes_the_same_as_it_returns() {
+    let src = fs::read_to_string("./tests/test14/input.txt").unwrap();
+    let options = Options {
+        target: Target::Std,
+        syntax: Syntax::async_await(),
+        trace: Trace::Hook(String::from("count_polls")),
+        source_map: false,
+    };
+    let (out, map) = rewrite(&options, &src).unwrap();
+    let mut written = vec![];
+    let written_map = rewrite_into(&options, &src, &mut written).unwrap();
+
+    assert!(out.contains("count_polls"));
+    assert_eq!(String::from_utf8(written).unwrap(), out);
+    assert_eq!((map, written_map), (None, None));
+
+    let options = Options { source_map: true, ..options };
+    let map = rewrite_into(&options, &src, vec![]).unwrap();
+    assert!(map.is_some_and(|map| map.original(1, 1).is_some()));
+}
+
+#[test]
+fn keeps_crlf_line_endings() {
+    let src = fs::read_to_string("./tests/test4/input.txt").unwrap();
+    let (lf, _) = rewrite(&Options::default(), &src).unwrap();
+    let (crlf, _) = rewrite(&Options::default(), &src.replace('\n', "\r\n")).unwrap();
+
+    assert_eq!(crlf.matches('\n').count(), crlf.matches("\r\n").count());
+    assert_eq!(crlf.replace("\r\n", "\n"), lf);
+}
+
+#[test]
+fn points_at_the_crlf_source() {
+    let src = "fn a() {}\r\n\r\ncoroutine fn b() {\r\n    if g() && f().wait.is_empty() {}\r\n}\r\n";
+    let err = rewrite(&Options::default(), src).unwrap_err();
+    let d = &err.diagnostics()[0];
+    assert_eq!((d.line, d.column), (4, 19));
+    assert_eq!(&src[d.range.clone()], "wait");
+}
+
+#[test]
+fn draws_every_state_and_transition() {
+    let src = fs::read_to_string("./tests/test11/input.txt").unwrap();
+    let graphs = graphs(&Syntax::default(), &src).unwrap();
+    let names: Vec<&str> = graphs.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["parse", "sum", "first_long", "shout"]);
+
+    let (_, sum) = &graphs[1];
+    assert!(sum.contains("Start -> Loop1 [label=\"12: let mut total: usize = 0;\\l[for .. in txts]\\l\"];"), "{sum}");
+    assert!(sum.contains("Loop1 -> Resolved [label=\"[Some(txt)]\\l[if txt.is_empty()]\\l15: return Err(String::from(\\\"empty\\\"))\\l\"];"), "{sum}");
+    assert!(sum.contains("Wait1 -> Resolved [label=\"Ready(Err(e))\\lreturn Err(From::from(e))\\l\"];"), "{sum}");
+    assert!(sum.contains("Loop1 -> Wait1 [label=\"[Some(txt)]\\l[else]\\l17: let n: usize = parse(txt).wait?;\\l\"];"), "{sum}");
+    assert!(sum.contains("Wait1 -> Wait1 [label=\"NotReady\", style=dashed];"), "{sum}");
+}
+
+#[test]
+fn maps_lines_back_to_the_coroutine() {
+    let src = fs::read_to_string("./tests/test11/input.txt").unwrap();
+    let (out, map) = rewrite(&Options { source_map: true, ..Options::default() }, &src).unwrap();
+    let mut map = map.unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    // The first line with `code` that isn't in the commented out coroutine
+    let find = |code: &str| {
+        let line = lines.iter().position(|l| l.contains(code) && !l.trim_start().starts_with("//"));
+        line.unwrap() + 1
+    };
+    let column = |line: usize, code: &str| lines[line - 1].find(code).unwrap() + 1;
+
+    // Code the user wrote, conditions, waits and returns all point at the coroutine
+    let total = find("total += n;");
+    assert_eq!(map.original(total, column(total, "total")), Some((18, 9)));
+    let cond = find("if txt.is_empty()");
+    assert_eq!(map.original(cond, column(cond, "txt")), Some((14, 12)));
+    let wait = find("Box::new(parse(txt))");
+    assert_eq!(map.original(wait, column(wait, "parse")), Some((17, 24)));
+    let ret = find("Ready(Err(String::from(\"empty\")))");
+    assert_eq!(map.original(ret, column(ret, "Err")), Some((15, 20)));
+    // Code we didn't touch moved, and code we made up comes from nowhere
+    assert_eq!(map.original(find("fn main()"), 1), Some((54, 1)));
+    assert_eq!(map.original(find("enum State1"), 1), None);
+
+    map.file = String::from("src/main_corofied.rs");
+    map.source = String::from("original_main.rs");
+    let rustc = format!(
+        "error[E0277]: cannot add-assign\n   --> src/main_corofied.rs:{total}:{}\nthread 'main' panicked at src/main_corofied.rs:{}:1:\n",
+        column(total, "+="),
+        find("enum State1"),
+    );
+    let remapped = map.remap(&rustc);
+    assert!(remapped.contains("   --> original_main.rs:18:15\n"), "{remapped}");
+    assert!(remapped.contains(&format!("panicked at src/main_corofied.rs:{}:1:", find("enum State1"))), "{remapped}");
+
+    assert_eq!(SourceMap::from_json(&map.to_json()).unwrap(), map);
+}
+
+#[test]
+fn reads_back_the_source_maps_it_writes() {
+    let src = fs::read_to_string("./tests/test11/input.txt").unwrap();
+    let options = Options { source_map: true, ..Options::default() };
+    let (_, map) = rewrite(&options, &src).unwrap();
+    let mut map = map.unwrap();
+    map.file = String::from("C:\\code\\\"main\"\tcorofied\u{1}.rs");
+    map.source = String::from("åäö/\nmain.rs");
+    let json = map.to_json();
+    assert!(json.starts_with(r#"{"version":1,"file":"C:\\code\\\"main\"\tcorofied\u0001.rs","#), "{json}");
+    assert_eq!(SourceMap::from_json(&json).unwrap(), map);
+
+    let json = r#"{"version": 1, "file": "a\u00e5\/b", "source": "c", "lines": [null, [3, -2]]}"#;
+    let map = SourceMap::from_json(json).unwrap();
+    assert_eq!(map.file, "aå/b");
+    assert_eq!(map.original(1, 1), None);
+    assert_eq!(map.original(2, 5), Some((3, 3)));
+
+    for json in [
+        "",
+        "[]",
+        r#"{"file": "a", "source": "b"}"#,
+        r#"{"file": "a", "source": "b", "lines": [[1]]}"#,
+        r#"{"file": "a", "source": "b", "lines": [[0, 0]]}"#,
+        r#"{"file": "a", "source": "b", "lines": ["1"]}"#,
+        r#"{"file": "a\q", "source": "b", "lines": []}"#,
+        r#"{"file": "a", "source": "b", "lines": []} {}"#,
+    ] {
+        let err = SourceMap::from_json(json).unwrap_err();
+        assert!(err.to_string().contains("not a corofy source map"), "{json}: {err}");
+    }
+}
+
+#[test]
+fn uses_the_keywords_it_is_given() {
+    assert_eq!("coro,wait".parse(), Syntax::new("coro", "wait"));
+    assert_eq!("async".parse(), Ok(Syntax::async_await()));
+    assert!(Syntax::new("fn", "wait").is_err());
+    assert!(Syntax::new("async", "wait").is_err());
+    assert!(Syntax::new("coro", "coro").is_err());
+
+    let syntax = Syntax::new("coro", "wait").unwrap();
+    let err = rewrite(&Options { syntax, ..Options::default() }, "fn a() {}\n").unwrap_err();
+    assert_eq!(err.to_string(), "No `coro` function found.");
+
+    let src = "async fn a() {\n    let x = || f().await;\n}\n";
+    let options = Options { target: Target::Std, syntax: Syntax::async_await(), ..Options::default() };
+    let err = rewrite(&options, src).unwrap_err();
+    assert!(err.to_string().contains("`.await` can't be used inside a closure"), "{err}");
+    assert_eq!(&src[err.diagnostics()[0].range.clone()], "await");
+}
+
+#[test]
+fn keeps_fields_called_wait_when_that_is_not_the_keyword() {
+    let modes = [
+        (Target::Ch07, Syntax::new("coro", "later").unwrap(), "later"),
+        (Target::Std, Syntax::async_await(), "await"),
+    ];
+    for (target, syntax, wait) in modes {
+        let src = format!(
+            "struct C {{ wait: u64 }}\n{} fn a(c: C) {{\n    let ms: u64 = c.wait;\n    let txt = f().{wait};\n    println!(\"{{ms}} {{txt}} {{}}\", c.wait);\n}}\n",
+            syntax.coroutine()
+        );
+        let (got, _) = rewrite(&Options { target, syntax, ..Options::default() }, &src).unwrap();
+        assert!(got.contains("Wait1("), "{got}");
+        assert!(!got.contains("Wait2"), "{got}");
+        assert!(!got.contains("Box::new(c)"), "{got}");
+        assert!(got.contains("\n                    let ms: u64 = c.wait;\n"), "{got}");
+    }
+}
+
+#[test]
+fn leaves_variables_called_coroutine_alone() {
+    let src = "fn helper(coroutine: bool, a: bool) -> u8 {\n    if a && coroutine { 1 } else { 2 }\n}\n\nfn mask(coroutine: u8) -> u8 {\n    coroutine | 2\n}\n\ncoroutine fn a() {\n    let h = coroutine move { f().wait; };\n    g(coroutine { f().wait; }).wait;\n}\n";
+    let (out, _) = rewrite(&Options::default(), src).unwrap();
+    assert!(out.contains("    if a && coroutine { 1 } else { 2 }\n"), "{out}");
+    assert!(out.contains("    coroutine | 2\n"), "{out}");
+    assert!(!out.contains("coroutine move {\n"), "{out}");
+}
@@ -0,0 +1,69 @@
+//! Compiles the programs in `tests/async` as they are, with Rust's own
+//! `async fn`s, and corofied for the `std` target, and checks that both
+//! print the same. That catches state machines that compile but don't do
+//! what the `async fn` does.
+use std::{fs, path::Path, process::Command};
+
+use corofy::{rewrite, Options, Syntax, Target};
+
+#[test]
+fn resolves_to_a_pinned_variable() {
+    compare("pinned_result");
+}
+
+#[test]
+fn keeps_shadowed_variables_apart() {
+    compare("shadowing");
+}
+
+#[test]
+fn infers_the_types_of_lifted_lets() {
+    compare("lifted_let");
+}
+
+#[test]
+fn takes_loop_items_from_suffixes_and_casts() {
+    compare("suffixed_loops");
+}
+
+#[test]
+fn names_the_std_future_in_full() {
+    compare("unimported_future");
+}
+
+fn compare(name: &str) {
+    let src = fs::read_to_string(format!("./tests/async/{name}.rs")).unwrap();
+    let options = Options { target: Target::Std, syntax: Syntax::async_await(), ..Options::default() };
+    let (corofied, _) = rewrite(&options, &src).unwrap();
+    let expected = build_and_run(&format!("{name}-async"), &src);
+    let got = build_and_run(&format!("{name}-corofied"), &corofied);
+    assert_eq!(got, expected, "`{name}` printed something else than its `async fn`s");
+}
+
+/// Builds a crate called `name` with `main` as `main.rs`, runs it and
+/// returns what it printed
+fn build_and_run(name: &str, main: &str) -> String {
+    let krate = Path::new(env!("CARGO_TARGET_TMPDIR")).join("async").join(name);
+    let _ = fs::remove_dir_all(&krate);
+    fs::create_dir_all(krate.join("src")).unwrap();
+    fs::write(
+        krate.join("Cargo.toml"),
+        format!("[package]\nname = \"{name}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[workspace]\n"),
+    )
+    .unwrap();
+    fs::write(krate.join("src/main.rs"), main).unwrap();
+
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("async-target");
+    let build = Command::new(env!("CARGO"))
+        .args(["build", "--quiet", "--manifest-path"])
+        .arg(krate.join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .output()
+        .unwrap();
+    assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+
+    let out = Command::new(target_dir.join("debug").join(name)).output().unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    String::from_utf8(out.stdout).unwrap()
+}
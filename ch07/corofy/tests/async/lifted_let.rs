@@ -0,0 +1,48 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// Resolves to `t` the second time it's polled
+struct Later<T>(Option<T>, bool);
+
+fn later<T: Unpin>(t: T) -> Later<T> {
+    Later(Some(t), false)
+}
+
+impl<T: Unpin> Future for Later<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<T> {
+        if self.1 {
+            Poll::Ready(self.0.take().unwrap())
+        } else {
+            self.1 = true;
+            Poll::Pending
+        }
+    }
+}
+
+/// Keeps variables declared by statements we lift waits out of, without
+/// annotating their types
+async fn lifted() -> String {
+    let s = later(String::from("a")).await.to_string();
+    let n = later(String::from("bc")).await.len();
+    let _: () = later(()).await;
+    format!("{s}{n}")
+}
+
+fn block_on<F: Future>(f: F) -> F::Output {
+    let mut f = std::pin::pin!(f);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(v) = f.as_mut().poll(&mut cx) {
+            return v;
+        }
+    }
+}
+
+fn main() {
+    println!("{}", block_on(lifted()));
+}
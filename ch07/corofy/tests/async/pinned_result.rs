@@ -0,0 +1,60 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// Resolves to `t` the second time it's polled
+struct Later<T>(Option<T>, bool);
+
+fn later<T: Unpin>(t: T) -> Later<T> {
+    Later(Some(t), false)
+}
+
+impl<T: Unpin> Future for Later<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<T> {
+        if self.1 {
+            Poll::Ready(self.0.take().unwrap())
+        } else {
+            self.1 = true;
+            Poll::Pending
+        }
+    }
+}
+
+/// Resolves to what's borrowed across the wait
+async fn written() -> String {
+    let mut buffer = String::new();
+    let writer = &mut buffer;
+    let _: () = later(()).await;
+    writer.push('a');
+    buffer
+}
+
+/// Returns it early, or resolves to one of its methods
+async fn early(stop: bool) -> String {
+    let mut buffer = String::from("b");
+    let writer = &mut buffer;
+    let _: () = later(()).await;
+    writer.push('c');
+    if stop {
+        return buffer;
+    }
+    buffer.to_uppercase()
+}
+
+fn block_on<F: Future>(f: F) -> F::Output {
+    let mut f = std::pin::pin!(f);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(v) = f.as_mut().poll(&mut cx) {
+            return v;
+        }
+    }
+}
+
+fn main() {
+    println!("{} {} {}", block_on(written()), block_on(early(true)), block_on(early(false)));
+}
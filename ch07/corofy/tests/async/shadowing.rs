@@ -0,0 +1,61 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// Resolves to `t` the second time it's polled
+struct Later<T>(Option<T>, bool);
+
+fn later<T: Unpin>(t: T) -> Later<T> {
+    Later(Some(t), false)
+}
+
+impl<T: Unpin> Future for Later<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<T> {
+        if self.1 {
+            Poll::Ready(self.0.take().unwrap())
+        } else {
+            self.1 = true;
+            Poll::Pending
+        }
+    }
+}
+
+/// Shadows a variable it keeps in the same block, with another type
+async fn same_block() -> String {
+    let x: u8 = 1;
+    let a: String = later(String::from("a")).await;
+    let x: u16 = x as u16 + 256;
+    let b: String = later(String::from("b")).await;
+    format!("{a}{b}{x}")
+}
+
+/// Shadows one it doesn't need after the block
+async fn nested() -> String {
+    let x: u8 = 1;
+    print!("{x} ");
+    {
+        let x: u16 = 2;
+        let a: String = later(String::from("a")).await;
+        print!("{a}{x} ");
+    }
+    let b: String = later(String::from("b")).await;
+    b
+}
+
+fn block_on<F: Future>(f: F) -> F::Output {
+    let mut f = std::pin::pin!(f);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(v) = f.as_mut().poll(&mut cx) {
+            return v;
+        }
+    }
+}
+
+fn main() {
+    println!("{} {}", block_on(same_block()), block_on(nested()));
+}
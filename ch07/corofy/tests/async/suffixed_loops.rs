@@ -0,0 +1,64 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// Resolves to `t` the second time it's polled
+struct Later<T>(Option<T>, bool);
+
+fn later<T: Unpin>(t: T) -> Later<T> {
+    Later(Some(t), false)
+}
+
+impl<T: Unpin> Future for Later<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<T> {
+        if self.1 {
+            Poll::Ready(self.0.take().unwrap())
+        } else {
+            self.1 = true;
+            Poll::Pending
+        }
+    }
+}
+
+/// Keeps variables declared by statements we lift waits out of, without
+async fn sq(i: usize) -> usize {
+    later(i * i).await
+}
+
+/// Loops whose items only have a type because of a suffix or a cast
+async fn sums() -> String {
+    let mut t = 0usize;
+    for i in 0..4usize {
+        let v: usize = sq(i).await;
+        t += v + i;
+    }
+    let mut bytes = 0u8;
+    for b in [1, 2u8, 3] {
+        let b: u8 = later(b).await;
+        bytes += b;
+    }
+    let mut big = 0u64;
+    for n in vec![2 as u64, 3] {
+        let n: u64 = later(n).await;
+        big += n;
+    }
+    format!("{t} {bytes} {big}")
+}
+
+fn block_on<F: Future>(f: F) -> F::Output {
+    let mut f = std::pin::pin!(f);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(v) = f.as_mut().poll(&mut cx) {
+            return v;
+        }
+    }
+}
+
+fn main() {
+    println!("{}", block_on(sums()));
+}
@@ -0,0 +1,45 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// Resolves to `t` the second time it's polled
+struct Later<T>(Option<T>, bool);
+
+fn later<T: Unpin>(t: T) -> Later<T> {
+    Later(Some(t), false)
+}
+
+impl<T: Unpin> std::future::Future for Later<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<T> {
+        if self.1 {
+            Poll::Ready(self.0.take().unwrap())
+        } else {
+            self.1 = true;
+            Poll::Pending
+        }
+    }
+}
+
+/// Never imports `Future`, so what we write has to name it in full
+async fn greet(name: String) -> String {
+    let txt = later(format!("hello {name}")).await;
+    let excl = later(String::from("!")).await;
+    format!("{txt}{excl}")
+}
+
+fn block_on<F: std::future::Future>(f: F) -> F::Output {
+    let mut f = std::pin::pin!(f);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(v) = f.as_mut().poll(&mut cx) {
+            return v;
+        }
+    }
+}
+
+fn main() {
+    println!("{}", block_on(greet(String::from("world"))));
+}
@@ -0,0 +1,174 @@
+use std::{fs, env::temp_dir, io::Write, process::{Command, Stdio}};
+
+const COROFY: &str = env!("CARGO_BIN_EXE_corofy");
+
+#[test]
+fn checks_and_writes_directories() {
+    let dir = temp_dir().join("corofy_cli_check");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("example/src")).unwrap();
+    fs::copy("./tests/test4/input.txt", dir.join("example/original_main.rs")).unwrap();
+    let dest = dir.join("example/src/main_corofied.rs");
+    fs::write(&dest, "stale").unwrap();
+
+    let check = Command::new(COROFY).arg("--check").arg(&dir).output().unwrap();
+    assert!(!check.status.success());
+    let stderr = String::from_utf8_lossy(&check.stderr);
+    assert!(stderr.contains("main_corofied.rs is out of date"), "{stderr}");
+
+    let diff = Command::new(COROFY).arg("--diff").arg(&dir).output().unwrap();
+    assert!(!diff.status.success());
+    let stdout = String::from_utf8_lossy(&diff.stdout);
+    assert!(stdout.contains("-stale"), "{stdout}");
+
+    let write = Command::new(COROFY).arg(&dir).status().unwrap();
+    assert!(write.success());
+    let check = Command::new(COROFY).arg("--check").arg(&dir).status().unwrap();
+    assert!(check.success());
+}
+
+#[test]
+fn skips_directories_without_a_destination_when_checking() {
+    let dir = temp_dir().join("corofy_cli_check_missing");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("example/src")).unwrap();
+    fs::copy("./tests/test4/input.txt", dir.join("example/original_main.rs")).unwrap();
+
+    let check = Command::new(COROFY).arg("--check").arg(&dir).output().unwrap();
+    assert!(check.status.success());
+    let stderr = String::from_utf8_lossy(&check.stderr);
+    assert!(stderr.contains("nothing to check against"), "{stderr}");
+    assert!(stderr.contains("main_corofied.rs doesn't exist"), "{stderr}");
+    assert!(!stderr.contains("no original_main.rs"), "{stderr}");
+}
+
+#[test]
+fn reads_stdin_and_writes_stdout() {
+    let src = fs::read_to_string("./tests/test4/input.txt").unwrap();
+    let mut child = Command::new(COROFY)
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(src.as_bytes()).unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success());
+
+    let got = String::from_utf8(out.stdout).unwrap();
+    let expected = fs::read_to_string("./tests/test4/expected.txt").unwrap();
+    for (i, (a, b)) in got.lines().zip(expected.lines()).enumerate() {
+        assert_eq!(a.trim(), b.trim(), "Failed in line {}", i+1);
+    }
+}
+
+#[test]
+fn rejects_in_place_for_stdin() {
+    let out = Command::new(COROFY).args(["--in-place", "-"]).output().unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("`--in-place` needs a single source file"), "{stderr}");
+}
+
+#[test]
+fn emits_a_graph_per_coroutine() {
+    let dir = temp_dir().join("corofy_cli_dot");
+    let _ = fs::remove_dir_all(&dir);
+    let status = Command::new(COROFY)
+        .args(["--emit", "dot", "./tests/test4/input.txt"])
+        .arg(&dir)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let request = fs::read_to_string(dir.join("input_request.dot")).unwrap();
+    assert!(request.starts_with("digraph \"request\" {"), "{request}");
+    assert!(request.contains("Start -> Wait1"), "{request}");
+    assert!(request.contains("Wait2 -> Resolved"), "{request}");
+    assert!(dir.join("input_inner.dot").exists());
+}
+
+#[test]
+fn writes_a_source_map_and_remaps_rustc_output() {
+    let dir = temp_dir().join("corofy_cli_map");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let dest = dir.join("main_corofied.rs");
+    let status = Command::new(COROFY)
+        .args(["--source-map", "./tests/test11/input.txt"])
+        .arg(&dest)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let out = fs::read_to_string(&dest).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    let line = lines.iter().position(|l| l.trim() == "total += n;").unwrap() + 1;
+    let column = lines[line - 1].find("total").unwrap() + 1;
+    let mut child = Command::new(COROFY)
+        .arg("--remap")
+        .arg(dir.join("main_corofied.rs.map"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let panic = format!("thread 'main' panicked at src/main_corofied.rs:{line}:{column}:\n");
+    child.stdin.take().unwrap().write_all(panic.as_bytes()).unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success());
+
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert_eq!(stdout, "thread 'main' panicked at ./tests/test11/input.txt:18:9:\n");
+}
+
+#[test]
+fn reads_other_keywords() {
+    let corofy = |args: &[&str], src: &str| {
+        let mut child = Command::new(COROFY)
+            .args(args)
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(src.as_bytes()).unwrap();
+        child.wait_with_output().unwrap()
+    };
+
+    let src = "async fn a() {\n    let txt = f().await;\n    println!(\"{txt}\")\n}\n";
+    let out = corofy(&["--async", "--target", "std"], src);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("fn a() -> impl std::future::Future<Output=()>"), "{stdout}");
+    assert!(stdout.contains("break std::task::Poll::Ready(println!(\"{txt}\"));"), "{stdout}");
+
+    let out = corofy(&["--keywords=coro,wait"], "coro fn a() {\n    f().wait;\n}\n");
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let out = corofy(&["--keywords", "coro"], "");
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("separated by a comma"), "{stderr}");
+}
+
+#[test]
+fn traces_with_a_hook() {
+    let out = Command::new(COROFY)
+        .args(["--trace=log::hook", "./tests/test4/input.txt", "-"])
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("log::hook(\"request\", \"poll\", self.state.name());"), "{stdout}");
+    assert!(stdout.contains("log::hook(\"request\", \"not_ready\", \"Wait1\");"), "{stdout}");
+    assert!(stdout.contains("log::hook(\"request\", \"enter\", \"Resolved\");"), "{stdout}");
+
+    let out = Command::new(COROFY)
+        .args(["--trace=not a path", "./tests/test4/input.txt", "-"])
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("expected `stderr` or the path of a function"), "{stderr}");
+}
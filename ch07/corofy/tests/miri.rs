@@ -0,0 +1,42 @@
+//! Runs what corofy writes for coroutines that borrow across wait points
+//! under Miri, which catches the pointers into the stack we use after
+//! they're invalidated. Miri needs a nightly toolchain with the `miri`
+//! component, which we ask rustup's `cargo` for, so the test only runs
+//! when it's asked for with `cargo test --test miri -- --ignored`.
+use std::{fs, path::Path, process::Command};
+
+use corofy::{rewrite, Options, Syntax, Target};
+
+#[test]
+#[ignore = "needs `cargo +nightly miri`, run it with `--ignored`"]
+fn borrows_across_wait_points_are_sound() {
+    let miri = Command::new("cargo").args(["+nightly", "miri", "--version"]).output();
+    assert!(
+        miri.is_ok_and(|out| out.status.success()),
+        "`cargo +nightly miri` isn't installed, install it with `rustup component add --toolchain nightly miri`"
+    );
+
+    let krate = Path::new(env!("CARGO_TARGET_TMPDIR")).join("miri");
+    let _ = fs::remove_dir_all(&krate);
+    fs::create_dir_all(krate.join("src")).unwrap();
+    fs::write(
+        krate.join("Cargo.toml"),
+        "[package]\nname = \"borrows\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[workspace]\n",
+    )
+    .unwrap();
+    let src = fs::read_to_string("./tests/miri/borrows.rs").unwrap();
+    let (out, _) = rewrite(&Options { target: Target::Std, syntax: Syntax::async_await(), ..Options::default() }, &src).unwrap();
+    fs::write(krate.join("src/main.rs"), out).unwrap();
+
+    for flags in ["", "-Zmiri-tree-borrows"] {
+        let run = Command::new("cargo")
+            .args(["+nightly", "miri", "run", "--quiet", "--manifest-path"])
+            .arg(krate.join("Cargo.toml"))
+            .env("MIRIFLAGS", flags)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&run.stderr);
+        assert!(run.status.success(), "Miri with `{flags}`:\n{stderr}");
+        assert_eq!(String::from_utf8_lossy(&run.stdout), "3 7\n");
+    }
+}
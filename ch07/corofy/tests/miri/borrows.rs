@@ -0,0 +1,63 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// Resolves to `t` the second time it's polled
+struct Later<T>(Option<T>, bool);
+
+fn later<T: Unpin>(t: T) -> Later<T> {
+    Later(Some(t), false)
+}
+
+impl<T: Unpin> Future for Later<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<T> {
+        if self.1 {
+            Poll::Ready(self.0.take().unwrap())
+        } else {
+            self.1 = true;
+            Poll::Pending
+        }
+    }
+}
+
+/// Uses a borrow and what it borrows in the same state
+async fn both() -> usize {
+    let mut buffer = String::new();
+    let writer = &mut buffer;
+    let _: () = later(()).await;
+    writer.push('a');
+    let _: () = later(()).await;
+    writer.push('b');
+    buffer.push('c');
+    buffer.len()
+}
+
+/// Reads what's borrowed while the borrow lives on to the next state
+async fn shared() -> usize {
+    let mut text = String::from("ab");
+    let view = &text;
+    let _: () = later(()).await;
+    let n: usize = text.len();
+    let _: () = later(()).await;
+    let m: usize = view.len();
+    text.push('c');
+    n + m + text.len()
+}
+
+fn block_on<F: Future>(f: F) -> F::Output {
+    let mut f = std::pin::pin!(f);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(v) = f.as_mut().poll(&mut cx) {
+            return v;
+        }
+    }
+}
+
+fn main() {
+    println!("{} {}", block_on(both()), block_on(shared()));
+}
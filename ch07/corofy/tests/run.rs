@@ -0,0 +1,335 @@
+//! Compiles what corofy writes for the examples in the book and runs it
+//! against a stand-in for `delayserver`, so code that compiles but doesn't
+//! do what the `coroutine` version says is caught too. The book's programs
+//! have to do the same as the state machines the book checks in next to
+//! them, and the ones we wrote for these tests print what `tests/run` says.
+//!
+//! The stand-in answers ten times faster than it's asked to, so the
+//! examples run in a fraction of the time they take in the book.
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use corofy::{rewrite, Options, Target, Trace};
+
+const SPEEDUP: u64 = 10;
+
+struct Example {
+    /// The example's directory, relative to the root of the repository
+    dir: &'static str,
+    /// The program we run through corofy, relative to `dir`. It's compiled
+    /// with the rest of the example's `src`.
+    src: &'static str,
+    target: Target,
+    /// How long the `coroutine` version takes with the stand-in, which is
+    /// a tenth of the sum of the delays it waits for one after another
+    elapsed: Duration,
+    /// Several threads print at once, so we can only compare what they
+    /// print, not in which order
+    ordered: bool,
+    trace: Trace,
+}
+
+#[test]
+fn runs_ch07_b_async_await() {
+    run(Example {
+        dir: "ch07/b-async-await",
+        src: "original_main.rs",
+        target: Target::Ch07,
+        elapsed: Duration::from_millis(1000),
+        ordered: true,
+        trace: Trace::Off,
+    });
+}
+
+#[test]
+fn runs_ch07_c_async_await() {
+    run(Example {
+        dir: "ch07/c-async-await",
+        src: "original_main.rs",
+        target: Target::Ch07,
+        elapsed: Duration::from_millis(400),
+        ordered: true,
+        trace: Trace::Off,
+    });
+}
+
+#[test]
+fn runs_a_loop_over_a_variable_in_a_loop() {
+    run(Example {
+        dir: "ch07/c-async-await",
+        src: "../corofy/tests/run/nested_for.rs",
+        target: Target::Ch07,
+        elapsed: Duration::from_millis(400),
+        ordered: true,
+        trace: Trace::Off,
+    });
+}
+
+#[test]
+fn runs_operands_before_the_waits_to_their_right() {
+    run(Example {
+        dir: "ch07/c-async-await",
+        src: "../corofy/tests/run/operand_order.rs",
+        target: Target::Ch07,
+        elapsed: Duration::from_millis(200),
+        ordered: true,
+        trace: Trace::Off,
+    });
+}
+
+#[test]
+fn runs_ch08_c_reactor_executor() {
+    run(Example {
+        dir: "ch08/c-reactor-executor",
+        src: "main_orig.rs",
+        target: Target::Ch08,
+        elapsed: Duration::from_millis(400),
+        ordered: true,
+        trace: Trace::Off,
+    });
+}
+
+#[test]
+fn runs_ch08_d_multiple_threads() {
+    run(Example {
+        dir: "ch08/d-multiple-threads",
+        src: "main_orig.rs",
+        target: Target::Ch08,
+        elapsed: Duration::from_millis(400),
+        ordered: false,
+        trace: Trace::Off,
+    });
+}
+
+#[test]
+fn runs_ch07_combinators() {
+    run(Example {
+        dir: "ch07/c-async-await",
+        src: "combinators.rs",
+        target: Target::Ch07,
+        elapsed: Duration::from_millis(900),
+        ordered: true,
+        trace: Trace::Off,
+    });
+}
+
+#[test]
+fn runs_ch08_combinators() {
+    run(Example {
+        dir: "ch08/c-reactor-executor",
+        src: "combinators.rs",
+        target: Target::Ch08,
+        elapsed: Duration::from_millis(900),
+        ordered: true,
+        trace: Trace::Off,
+    });
+}
+
+#[test]
+fn runs_ch08_mixed_with_std() {
+    run(Example {
+        dir: "ch08/c-reactor-executor",
+        src: "mixed.rs",
+        target: Target::Ch08,
+        elapsed: Duration::from_millis(500),
+        ordered: true,
+        trace: Trace::Off,
+    });
+}
+
+#[test]
+fn traces_ch08_c_reactor_executor() {
+    let trace = run(Example {
+        dir: "ch08/c-reactor-executor",
+        src: "main_orig.rs",
+        target: Target::Ch08,
+        elapsed: Duration::from_millis(400),
+        ordered: true,
+        trace: Trace::Stderr,
+    });
+    let count = |line: &str| trace.lines().filter(|l| *l == line).count();
+    assert_eq!(count("async_main: poll Start"), 1, "{trace}");
+    assert_eq!(count("async_main: enter Resolved"), 1, "{trace}");
+    assert_eq!(count("request: poll Start"), 5, "{trace}");
+    assert_eq!(count("request: enter Resolved"), 5, "{trace}");
+    assert_eq!(count("request: enter Wait1"), 5, "{trace}");
+    // Every time a request isn't ready it's polled again, until it is. How
+    // often that happens depends on how fast the server answers.
+    let not_ready = count("request: not_ready Wait1");
+    assert_eq!(count("request: poll Wait1"), not_ready, "{trace}");
+}
+
+/// Returns what the example printed to stderr
+fn run(example: Example) -> String {
+    // The book's program is named after its example, any other one after
+    // its file too
+    let book = matches!(example.src, "original_main.rs" | "main_orig.rs");
+    let mut name = example.dir.replace('/', "-");
+    if !book {
+        let stem = Path::new(example.src).file_stem().unwrap().to_string_lossy();
+        name = format!("{name}-{stem}");
+    }
+    let mut krate = name.clone();
+    if example.trace != Trace::Off {
+        krate.push_str("-traced");
+    }
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("../..");
+    let dir = root.join(example.dir);
+
+    let src = fs::read_to_string(dir.join(example.src)).unwrap();
+    let options = Options { target: example.target, trace: example.trace, ..Options::default() };
+    let (main, _) = rewrite(&options, &src).unwrap();
+    let server = DelayServer::start();
+    let (out, elapsed) = build_and_run(&krate, &dir, &main, &server);
+
+    // Waiting for less than that means we skipped a wait, and waiting twice
+    // as long means we waited one after another for what should run at once
+    assert!(
+        elapsed >= example.elapsed && elapsed < example.elapsed * 2,
+        "`{}` took {elapsed:?}, expected {:?}",
+        example.dir,
+        example.elapsed
+    );
+
+    // The book's programs come with the state machine the book runs, which
+    // has to ask for and print the same things. The others are ours.
+    let expected = if book {
+        let reference = DelayServer::start();
+        let main = fs::read_to_string(dir.join("src/main_corofied.rs")).unwrap();
+        let (out, _) = build_and_run(&format!("{krate}-book"), &dir, &main, &reference);
+        assert_eq!(server.requests(), reference.requests(), "`{}` asked for something else", example.dir);
+        String::from_utf8(out.stdout).unwrap()
+    } else {
+        fs::read_to_string(format!("./tests/run/{name}.txt")).unwrap()
+    };
+    let got = String::from_utf8(out.stdout).unwrap();
+    let mut got = output_lines(&got);
+    let mut expected = output_lines(&expected);
+    if !example.ordered {
+        got.sort();
+        expected.sort();
+    }
+    assert_eq!(got, expected, "`{}` printed something else", example.dir);
+    String::from_utf8(out.stderr).unwrap()
+}
+
+/// Builds a copy of the example in `dir` with `main` as `main.rs`, talking
+/// to `server` instead of the one on port 8080, and runs it
+fn build_and_run(name: &str, dir: &Path, main: &str, server: &DelayServer) -> (Output, Duration) {
+    let krate = Path::new(env!("CARGO_TARGET_TMPDIR")).join("run").join(name);
+    let _ = fs::remove_dir_all(&krate);
+    copy_dir(&dir.join("src"), &krate.join("src"));
+    let _ = fs::remove_file(krate.join("src/main_corofied.rs"));
+    let manifest = fs::read_to_string(dir.join("Cargo.toml")).unwrap();
+    let package = manifest.lines().find(|line| line.starts_with("name =")).unwrap();
+    let manifest = manifest.replacen(package, &format!("name = \"{name}\""), 1);
+    fs::write(krate.join("Cargo.toml"), format!("{manifest}\n[workspace]\n")).unwrap();
+    fs::write(krate.join("src/main.rs"), main).unwrap();
+    let http = fs::read_to_string(krate.join("src/http.rs")).unwrap();
+    fs::write(krate.join("src/http.rs"), http.replace("127.0.0.1:8080", &server.addr.to_string())).unwrap();
+
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("run-target");
+    let build = Command::new(env!("CARGO"))
+        .args(["build", "--quiet", "--manifest-path"])
+        .arg(krate.join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .output()
+        .unwrap();
+    assert!(build.status.success(), "{}", String::from_utf8_lossy(&build.stderr));
+
+    let started = Instant::now();
+    let out = Command::new(target_dir.join("debug").join(name)).output().unwrap();
+    let elapsed = started.elapsed();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    (out, elapsed)
+}
+
+/// The lines a program printed, without the time it took and how often the
+/// executors went to sleep, which change from run to run
+fn output_lines(out: &str) -> Vec<&str> {
+    out.lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.starts_with("ELAPSED TIME") && !line.ends_with("Sleep until notified."))
+        .collect()
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let path: PathBuf = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &path);
+        } else {
+            fs::copy(entry.path(), path).unwrap();
+        }
+    }
+}
+
+/// A server that answers `GET /{delay}/{message}` with `message` after
+/// `delay` milliseconds (divided by `SPEEDUP`), just like `delayserver`
+/// does, and remembers what it was asked for
+struct DelayServer {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl DelayServer {
+    fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let log = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let log = log.clone();
+                thread::spawn(move || respond(stream, &log));
+            }
+        });
+        DelayServer { addr, requests }
+    }
+
+    /// The paths we were asked for, sorted since requests that run at once
+    /// can come in any order
+    fn requests(&self) -> Vec<String> {
+        let mut requests = self.requests.lock().unwrap().clone();
+        requests.sort();
+        requests
+    }
+}
+
+fn respond(mut stream: TcpStream, log: &Mutex<Vec<String>>) {
+    let mut request = vec![];
+    let mut buf = [0u8; 1024];
+    while !request.ends_with(b"\r\n\r\n") {
+        let n = stream.read(&mut buf).unwrap();
+        if n == 0 {
+            return;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let path = request.split(' ').nth(1).unwrap_or_default();
+    log.lock().unwrap().push(path.to_string());
+    let mut parts = path.trim_start_matches('/').splitn(2, '/');
+    let delay: u64 = parts.next().unwrap_or_default().parse().unwrap_or_default();
+    let message = parts.next().unwrap_or_default();
+
+    thread::sleep(Duration::from_millis(delay / SPEEDUP));
+    let response = format!(
+        "HTTP/1.1 200 OK\r\ncontent-length: {}\r\ncontent-type: text/plain; charset=utf-8\r\n\r\n{message}",
+        message.len()
+    );
+    let _ = stream.write_all(response.as_bytes());
+}
@@ -0,0 +1,33 @@
+mod http;
+mod future;
+
+use future::*;
+use crate::http::Http;
+
+/// `n` is only used to start the inner loop, which happens again after
+/// every wait in it
+coroutine fn async_main() {
+    let n: usize = 2;
+    let mut round: usize = 0;
+    'outer: loop {
+        round += 1;
+        for j in 0..n {
+            let txt = Http::get(&format!("/1000/Round{round}Item{j}")).wait;
+            println!("{}", txt.lines().last().unwrap_or_default());
+        }
+        if round == 2 {
+            break 'outer;
+        }
+    }
+}
+
+fn main() {
+    let mut future = async_main();
+
+    loop {
+        match future.poll() {
+            PollState::NotReady => (),
+            PollState::Ready(_) => break,
+        }
+    }
+}
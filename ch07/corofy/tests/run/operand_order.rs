@@ -0,0 +1,34 @@
+mod http;
+mod future;
+
+use future::*;
+use crate::http::Http;
+
+/// Prints `what` and returns how long it is
+fn say(what: &str) -> usize {
+    println!("{what}");
+    what.len()
+}
+
+/// `say("Before")` runs before the request and `say("After")` after it, just
+/// like they would if `.wait` was an ordinary function call
+coroutine fn async_main() {
+    let txt = format!(
+        "{} {} {}",
+        say("Before") as usize,
+        Http::get("/2000/During").wait.lines().last().unwrap_or_default(),
+        say("After"),
+    );
+    println!("{txt}");
+}
+
+fn main() {
+    let mut future = async_main();
+
+    loop {
+        match future.poll() {
+            PollState::NotReady => (),
+            PollState::Ready(_) => break,
+        }
+    }
+}
@@ -0,0 +1,21 @@
+use std::{fs, env::temp_dir};
+
+use corofy::{rewrite_into, Options, Target};
+#[test]
+fn produces_expected_output_12() {
+    let src = fs::read_to_string("./tests/test12/input.txt").unwrap();
+    let dest_path = temp_dir().join("test12.txt");
+    let dest = fs::File::create(&dest_path).unwrap();
+
+    if let Err(e) =  rewrite_into(&Options { target: Target::Ch07, ..Options::default() }, &src, dest) {
+        panic!("ERROR: {e}");
+    }
+
+    let expected = fs::read_to_string("./tests/test12/expected.txt").unwrap();
+    let got = fs::read_to_string(dest_path).unwrap();
+
+    assert_eq!(got.lines().count(), expected.lines().count());
+    for (i, (a, b)) in got.lines().zip(expected.lines()).enumerate() {
+        assert_eq!(a.trim(), b.trim(), "Failed in line {}", i+1);
+    }
+}
@@ -0,0 +1,18 @@
+use std::fs;
+
+use corofy::{rewrite, Options, Syntax, Target};
+#[test]
+fn produces_expected_output_13() {
+    let src = fs::read_to_string("./tests/test13/input.txt").unwrap();
+    let got = match rewrite(&Options { target: Target::Std, syntax: Syntax::async_await(), ..Options::default() }, &src) {
+        Ok((got, _)) => got,
+        Err(e) => panic!("ERROR: {e}"),
+    };
+
+    let expected = fs::read_to_string("./tests/test13/expected.txt").unwrap();
+
+    assert_eq!(got.lines().count(), expected.lines().count());
+    for (i, (a, b)) in got.lines().zip(expected.lines()).enumerate() {
+        assert_eq!(a.trim(), b.trim(), "Failed in line {}", i+1);
+    }
+}
@@ -0,0 +1,197 @@
+use std::{fs, env::temp_dir};
+
+use corofy::{rewrite_into, Options, Target};
+
+fn rewrite_err(name: &str, src: &str) -> String {
+    let dest = fs::File::create(temp_dir().join(format!("{name}.txt"))).unwrap();
+    match rewrite_into(&Options::default(), src, dest) {
+        Ok(_) => panic!("`{name}` should have been rejected"),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[test]
+fn rejects_wait_that_might_not_run() {
+    let err = rewrite_err(
+        "wait_that_might_not_run",
+        "coroutine fn a() {\n    while done() || Http::get(\"/\").wait.is_empty() {\n        Http::get(\"/\").wait;\n    }\n    let n = match x { 0 => f().wait.len(), _ => 0 };\n}\n",
+    );
+    assert!(err.contains("`.wait` can't be used after `&&` or `||`"), "{err}");
+    assert!(err.contains("line 2, column 36"), "{err}");
+    assert!(err.contains("line 5, column 32"), "{err}");
+}
+
+#[test]
+fn rejects_operands_before_a_wait_we_cant_keep() {
+    let err = rewrite_err(
+        "operands_before_a_wait",
+        "coroutine fn a() {\n    println!(\"{} {}\", start_timer(), f().wait);\n}\n",
+    );
+    assert!(err.contains("can't tell the type of this, which we need to keep it until the wait"), "{err}");
+    assert!(err.contains("line 2, column 23"), "{err}");
+}
+
+#[test]
+fn rejects_loops_we_cant_keep() {
+    let err = rewrite_err(
+        "loops",
+        "coroutine fn a() {\n    for x in items() {\n        f(x).wait;\n    }\n    while let Some(x) = next() {\n        f().wait;\n        println!(\"{x}\");\n    }\n}\n",
+    );
+    assert!(err.contains("can't tell the type of the items in this loop"), "{err}");
+    assert!(err.contains("can't tell the type of `x`"), "{err}");
+    assert!(err.contains("line 5, column 20"), "{err}");
+}
+
+#[test]
+fn rejects_wait_inside_closures_and_macros() {
+    let err = rewrite_err(
+        "wait_inside_closures_and_macros",
+        "coroutine fn a() {\n    let f = || Http::get(\"/\").wait;\n    let v = vec![Http::get(\"/\").wait; 2];\n}\n",
+    );
+    assert!(err.contains("`.wait` can't be used inside a closure"), "{err}");
+    assert!(err.contains("only be used in macros that take expressions"), "{err}");
+    assert!(err.contains("line 3, column 33"), "{err}");
+}
+
+#[test]
+fn rejects_unsupported_signatures() {
+    let err = rewrite_err(
+        "signatures",
+        "coroutine fn a(t: impl Display) {}\ncoroutine fn b(mut i: usize) {}\ncoroutine fn c() -> impl Display { 1 }\n",
+    );
+    assert!(err.contains("can't take `impl Trait`"), "{err}");
+    assert!(err.contains("must be plain identifiers"), "{err}");
+    assert!(err.contains("can't return `impl Trait`"), "{err}");
+}
+
+#[test]
+fn rejects_missing_result() {
+    let err = rewrite_err("missing_result", "coroutine fn a() -> usize {\n    f().wait;\n}\n");
+    assert!(err.contains("must end in an expression"), "{err}");
+}
+
+#[test]
+fn rejects_methods_we_cant_rewrite() {
+    let err = rewrite_err(
+        "methods",
+        "struct A;\nimpl A {\n    coroutine fn a(mut self) {}\n    coroutine fn b(&self) -> usize {\n        Self::C\n    }\n}\nimpl Tr for A {\n    coroutine fn c(&self) {}\n}\ncoroutine fn d(&self) {}\n",
+    );
+    assert!(err.contains("can't take `mut self`"), "{err}");
+    assert!(err.contains("`Self` would be the state machine"), "{err}");
+    assert!(err.contains("line 5, column 9"), "{err}");
+    assert!(err.contains("coroutines in trait impls are not supported"), "{err}");
+    assert!(err.contains("only a coroutine in an `impl` block can take `self`"), "{err}");
+}
+
+#[test]
+fn rejects_return_and_question_mark() {
+    let err = rewrite_err(
+        "return",
+        "coroutine fn a() {\n    let x = f()?;\n    let y = match x { 0 => return, y => y };\n    g().wait?;\n}\ncoroutine fn b() -> usize {\n    return;\n}\n",
+    );
+    assert!(err.contains("the `?` operator is only supported right after `.wait`"), "{err}");
+    assert!(err.contains("`return` is only supported on a statement of its own"), "{err}");
+    assert!(err.contains("`.wait?` can only be used in a coroutine that returns a `Result`"), "{err}");
+    assert!(err.contains("`return` needs a value"), "{err}");
+}
+
+#[test]
+fn rejects_invalid_syntax() {
+    let err = rewrite_err("syntax", "coroutine fn a() {\n    let x = \"{;\n}\n");
+    assert!(err.contains("line 2"), "{err}");
+}
+
+#[test]
+fn ignores_keyword_in_comments_and_strings() {
+    let err = rewrite_err(
+        "comments",
+        "// coroutine fn a() {}\nfn main() { let s = \"coroutine fn b() { x.wait; }\"; }\n",
+    );
+    assert_eq!(err, "No `coroutine` function found.");
+}
+
+#[test]
+fn rejects_borrows_without_pin() {
+    let err = rewrite_err(
+        "borrows_without_pin",
+        "coroutine fn a() {\n    let mut buffer = String::new();\n    let writer = &mut buffer;\n    f().wait;\n    writer.push('a');\n}\n",
+    );
+    assert!(err.contains("`writer` is a reference into the coroutine's own stack"), "{err}");
+    assert!(err.contains("line 3, column 9"), "{err}");
+}
+
+#[test]
+fn reports_every_error_with_a_snippet() {
+    let dest = fs::File::create(temp_dir().join("every_error.txt")).unwrap();
+    let src = "coroutine fn a() {\n    let f = || Http::get(\"/\").wait;\n}\n\ncoroutine fn b() {\n    if ready() && Http::get(\"/\").wait.is_empty() {}\n}\n";
+    let err = rewrite_into(&Options::default(), src, dest).unwrap_err().with_file("src/main.rs");
+    let diagnostics = err.diagnostics();
+    assert_eq!(diagnostics.len(), 2, "{err}");
+    assert_eq!((diagnostics[0].line, diagnostics[1].line), (2, 6), "{err}");
+
+    let err = err.to_string();
+    assert!(err.contains(" --> src/main.rs:6:"), "{err}");
+    assert!(err.contains("6 |     if ready() && Http::get(\"/\").wait.is_empty() {}\n  |"), "{err}");
+    assert!(err.contains("^^^^"), "{err}");
+}
+
+#[test]
+fn rejects_blocks_we_cant_move_out() {
+    let err = rewrite_err(
+        "blocks",
+        "fn main() {\n    let x = f();\n    spawn(coroutine { g().wait; x });\n    spawn(coroutine move |n| g(n).wait);\n}\nimpl A {\n    fn a(&self) {\n        spawn(coroutine { g().wait; self.b() });\n    }\n}\n",
+    );
+    assert!(err.contains("can't tell the type of `x`, which this block takes"), "{err}");
+    assert!(err.contains("line 3, column 33"), "{err}");
+    assert!(err.contains("the arguments of a coroutine closure need a type"), "{err}");
+    assert!(err.contains("a `coroutine` block can't use `self`"), "{err}");
+    assert!(!err.contains("can't be used here"), "{err}");
+
+    let err = rewrite_err("block_in_macro", "fn main() {\n    m!(x => coroutine { g().wait; });\n}\n");
+    assert!(err.contains("a `coroutine` block or closure can't be used here"), "{err}");
+}
+
+#[test]
+fn rejects_using_a_pinned_result_by_value() {
+    let dest = fs::File::create(temp_dir().join("pinned_result.txt")).unwrap();
+    let src = "coroutine fn a() -> Option<String> {\n    let mut buffer = String::new();\n    let writer = &mut buffer;\n    f().wait;\n    writer.push('a');\n    Some(buffer)\n}\n";
+    let err = rewrite_into(&Options { target: Target::Std, ..Options::default() }, src, dest).unwrap_err().to_string();
+    assert!(err.contains("`buffer` is borrowed across a wait point, so it's a reference"), "{err}");
+    assert!(err.contains("line 6, column 10"), "{err}");
+}
+
+#[test]
+fn rejects_shadowing_a_kept_variable_in_a_block() {
+    let err = rewrite_err(
+        "shadowing",
+        "coroutine fn a() -> String {\n    let x: u8 = 1;\n    {\n        let x: u16 = 2;\n        f().wait;\n        println!(\"{x}\");\n    }\n    f().wait;\n    x.to_string()\n}\ncoroutine fn b() -> u8 {\n    let x: u8 = 1;\n    if c() {\n        let x: u8 = 2;\n        if d() {\n            return x;\n        }\n    }\n    x\n}\n",
+    );
+    assert!(err.contains("this `x` hides another `x` that's kept between wait points or used after this block"), "{err}");
+    assert!(err.contains("line 4, column 13"), "{err}");
+    assert!(err.contains("line 14, column 13"), "{err}");
+}
+
+#[test]
+fn rejects_wait_in_items_inside_a_coroutine() {
+    let err = rewrite_err(
+        "wait_in_items",
+        "coroutine fn a() {\n    fn inner() -> String {\n        Http::get(\"/\").wait\n    }\n    macro_rules! m {\n        () => { Http::get(\"/\").wait };\n    }\n    Http::get(\"/\").wait;\n}\ncoroutine unsafe fn b() {\n    Http::get(\"/\").wait;\n}\n",
+    );
+    assert!(err.contains("`.wait` can't be used outside a coroutine, and a function or macro declared inside one is outside of it"), "{err}");
+    assert!(err.contains("line 3, column 24"), "{err}");
+    assert!(err.contains("line 6, column 32"), "{err}");
+    assert!(err.contains("a coroutine can't be `unsafe`"), "{err}");
+    assert!(err.contains("line 10, column 11"), "{err}");
+}
+
+#[test]
+fn rejects_loops_over_unsuffixed_integers() {
+    let err = rewrite_err(
+        "unsuffixed_integers",
+        "coroutine fn a() {\n    let mut t = 0usize;\n    for i in 0..4 {\n        let v: usize = sq(i).wait;\n        t += v + i;\n    }\n    for i in [1, 2] {\n        f(i).wait;\n    }\n    for i in vec![1, 2] {\n        f(i).wait;\n    }\n}\n",
+    );
+    assert!(err.contains("like `0..4usize`"), "{err}");
+    assert!(err.contains("line 3, column 14"), "{err}");
+    assert!(err.contains("line 7, column 14"), "{err}");
+    assert!(err.contains("line 10, column 14"), "{err}");
+}
@@ -0,0 +1,36 @@
+use std::time::Instant;
+
+mod http;
+mod future;
+
+use future::*;
+use crate::http::Http;
+
+#[corofy_macro::coroutine]
+fn request(i: usize) {
+    let path = format!("/{}/HelloWorld{i}", i * 1000);
+    let txt = Http::get(&path).wait;
+    println!("{txt}");
+}
+
+#[corofy_macro::coroutine]
+fn async_main() {
+    println!("Program starting");
+    let mut futures = vec![];
+
+    for i in 0..5 {
+        futures.push(request(i));
+    }
+
+    future::join_all(futures).wait;
+}
+
+
+fn main() {
+    let start = Instant::now();
+    let mut future = async_main();
+
+    while let PollState::NotReady = future.poll() {}
+
+    println!("\nELAPSED TIME: {}", start.elapsed().as_secs_f32());
+}
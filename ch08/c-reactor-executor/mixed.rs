@@ -0,0 +1,38 @@
+mod future;
+mod http;
+mod runtime;
+use future::*;
+use runtime::Waker;
+use crate::http::Http;
+
+fn main() {
+    // Our executor runs an `async fn` that awaits our futures
+    let mut executor = runtime::init();
+    executor.block_on(FromStd::new(async_main()));
+}
+
+async fn async_main() -> String {
+    println!("Program starting");
+    let txt = request(1).into_std().await;
+    println!("async: {txt}");
+
+    let txt = relay(2).into_std().await;
+    println!("async: {txt}");
+
+    let both = join(request(2), FromStd::new(async { request(1).into_std().await })).into_std().await;
+    println!("async: {} {}", both.0, both.1);
+    String::new()
+}
+
+/// Resolves to the message the server sends back, `HelloWorld{i}`
+coroutine fn request(i: usize) -> String {
+    let path = format!("/{}/HelloWorld{i}", i * 1000);
+    let message: String = Http::get(&path).map(|txt| txt.lines().last().unwrap_or_default().to_string()).wait;
+    message
+}
+
+/// The same as `request`, by way of an `async` block that a coroutine waits on
+coroutine fn relay(i: usize) -> String {
+    let message: String = FromStd::new(async move { request(i).into_std().await }).wait;
+    message
+}
@@ -1,10 +1,8 @@
 mod future;
 mod http;
 mod runtime;
-
 use future::{Future, PollState};
 use runtime::Waker;
-
 use crate::http::Http;
 
 fn main() {
@@ -16,13 +14,11 @@ fn main() {
 
 
 
-
-
 // =================================
 // We rewrite this:
 // =================================
     
-// coro fn request(i: usize) {
+// coroutine fn request(i: usize) {
 //     let path = format!("/{}/HelloWorld{i}", i * 1000);
 //     let txt = Http::get(&path).wait;
 //     println!("{txt}");
@@ -58,7 +54,7 @@ impl Coroutine0 {
 impl Future for Coroutine0 {
     type Output = String;
 
-    fn poll(&mut self) -> PollState<Self::Output> {
+    fn poll(&mut self, waker: &Waker) -> PollState<Self::Output> {
         loop {
         match self.state {
                 State0::Start(i) => {
@@ -71,7 +67,7 @@ impl Future for Coroutine0 {
                 }
 
                 State0::Wait1(ref mut f1) => {
-                    match f1.poll() {
+                    match f1.poll(waker) {
                         PollState::Ready(txt) => {
                             // ---- Code you actually wrote ----
                             println!("{txt}");
@@ -96,7 +92,7 @@ impl Future for Coroutine0 {
 // We rewrite this:
 // =================================
     
-// coro fn async_main() {
+// coroutine fn async_main() {
 //     println!("Program starting");
 // 
 //     for i in 0..5 {
@@ -133,7 +129,7 @@ impl Coroutine1 {
 impl Future for Coroutine1 {
     type Output = String;
 
-    fn poll(&mut self) -> PollState<Self::Output> {
+    fn poll(&mut self, waker: &Waker) -> PollState<Self::Output> {
         loop {
         match self.state {
                 State1::Start => {
@@ -47,11 +47,11 @@ impl Executor {
     }
 
     fn get_waker(&self, id: usize) -> Waker {
-        Waker {
+        Waker(Wake::Task {
             id,
             thread: thread::current(),
             ready_queue: CURRENT_EXEC.with(|q| q.ready_queue.clone()),
-        }
+        })
     }
 
     fn insert_task(&self, id: usize, task: Task) {
@@ -97,18 +97,47 @@ impl Executor {
 }
 
 #[derive(Clone)]
-pub struct Waker {
-    thread: Thread,
-    id: usize,
-    ready_queue: Arc<Mutex<Vec<usize>>>,
+pub struct Waker(Wake);
+
+#[derive(Clone)]
+enum Wake {
+    /// Puts a task of our executor back in its ready queue
+    Task {
+        thread: Thread,
+        id: usize,
+        ready_queue: Arc<Mutex<Vec<usize>>>,
+    },
+    /// Wakes a future of ours that runs on an executor for
+    /// `std::future::Future`, see `future::IntoStd`
+    Std(std::task::Waker),
 }
 
 impl Waker {
     pub fn wake(&self) {
-        self.ready_queue
-            .lock()
-            .map(|mut q| q.push(self.id))
-            .unwrap();
-        self.thread.unpark();
+        match &self.0 {
+            Wake::Task {
+                thread,
+                id,
+                ready_queue,
+            } => {
+                ready_queue.lock().map(|mut q| q.push(*id)).unwrap();
+                thread.unpark();
+            }
+            Wake::Std(waker) => waker.wake_by_ref(),
+        }
+    }
+
+    /// The `std::task::Waker` this one wraps, if it wraps one
+    pub fn as_std(&self) -> Option<&std::task::Waker> {
+        match &self.0 {
+            Wake::Std(waker) => Some(waker),
+            Wake::Task { .. } => None,
+        }
+    }
+}
+
+impl From<std::task::Waker> for Waker {
+    fn from(waker: std::task::Waker) -> Self {
+        Waker(Wake::Std(waker))
     }
 }
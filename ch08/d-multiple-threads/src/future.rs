@@ -1,3 +1,11 @@
+// Not every program uses the adapters for `std::future::Future`
+#![allow(dead_code)]
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll, RawWaker, RawWakerVTable},
+};
+
 // NEW
 use std::{thread::Thread, sync::Arc};
 
@@ -54,4 +62,191 @@ pub fn join_all<F: Future>(futures: Vec<F>) -> JoinAll<F> {
                 PollState::NotReady
             }
         }
-    }
\ No newline at end of file
+    }
+
+/// Lets a future of ours run on an executor for `std::future::Future`, or be
+/// awaited in an `async fn`. The `std::task::Waker` it gets goes to the
+/// future wrapped in our `Waker`, so the reactor can wake it.
+pub struct IntoStd<F>(F);
+
+// Our futures don't need to stay where they are while they're polled, so
+// pinning an `IntoStd` doesn't pin the future in it
+impl<F> Unpin for IntoStd<F> {}
+
+impl<F: Future> IntoStd<F> {
+    pub fn new(future: F) -> Self {
+        IntoStd(future)
+    }
+}
+
+impl<F: Future> std::future::Future for IntoStd<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let waker = Waker::from(cx.waker().clone());
+        match self.get_mut().0.poll(&waker) {
+            PollState::Ready(value) => Poll::Ready(value),
+            PollState::NotReady => Poll::Pending,
+        }
+    }
+}
+
+/// Lets a `std::future::Future`, like an `async` block, run on our executor,
+/// or be waited on in a `coroutine`. The future gets a `std::task::Waker`
+/// that wakes our `Waker`.
+pub struct FromStd<F>(Pin<Box<F>>);
+
+impl<F: std::future::Future> FromStd<F> {
+    pub fn new(future: F) -> Self {
+        FromStd(Box::pin(future))
+    }
+}
+
+impl<F: std::future::Future> Future for FromStd<F> {
+    type Output = F::Output;
+
+    fn poll(&mut self, waker: &Waker) -> PollState<Self::Output> {
+        let waker = std_waker(waker);
+        let mut cx = Context::from_waker(&waker);
+        match self.0.as_mut().poll(&mut cx) {
+            Poll::Ready(value) => PollState::Ready(value),
+            Poll::Pending => PollState::NotReady,
+        }
+    }
+}
+
+/// A `std::task::Waker` that wakes `waker`. One that wraps a
+/// `std::task::Waker` already gives us that back.
+fn std_waker(waker: &Waker) -> std::task::Waker {
+    match waker.as_std() {
+        Some(waker) => waker.clone(),
+        None => raw_waker(waker.clone()),
+    }
+}
+
+/// A `std::task::Waker` that owns `waker` and calls it through `VTABLE`
+fn raw_waker(waker: Waker) -> std::task::Waker {
+    let data = Arc::into_raw(Arc::new(waker)) as *const ();
+    // SAFETY: `data` is an `Arc<Waker>` we own a count of, which is what the
+    // functions in `VTABLE` expect. `Waker` is `Send` and `Sync`.
+    unsafe { std::task::Waker::from_raw(RawWaker::new(data, &VTABLE)) }
+}
+
+// The `RawWaker` functions for a `std::task::Waker` made from our `Waker`.
+// Their `data` is a pointer we got from `Arc::into_raw(Arc<Waker>)`.
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone_raw, wake_raw, wake_by_ref_raw, drop_raw);
+
+unsafe fn clone_raw(data: *const ()) -> RawWaker {
+    Arc::increment_strong_count(data as *const Waker);
+    RawWaker::new(data, &VTABLE)
+}
+
+unsafe fn wake_raw(data: *const ()) {
+    let waker = Arc::from_raw(data as *const Waker);
+    waker.wake();
+}
+
+unsafe fn wake_by_ref_raw(data: *const ()) {
+    (*(data as *const Waker)).wake();
+}
+
+unsafe fn drop_raw(data: *const ()) {
+    std::mem::drop(Arc::from_raw(data as *const Waker));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::ManuallyDrop;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A `std::task::Waker` that counts how often it's woken
+    #[derive(Default)]
+    struct Counter(AtomicUsize);
+
+    impl std::task::Wake for Counter {
+        fn wake(self: Arc<Self>) {
+            self.wake_by_ref();
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// How many `std::task::Waker`s share the `Arc<Waker>` behind `waker`
+    fn wakers(waker: &std::task::Waker) -> usize {
+        // SAFETY: `waker` came from `raw_waker`, and we don't drop the count it owns
+        let arc = ManuallyDrop::new(unsafe { Arc::from_raw(waker.data() as *const Waker) });
+        Arc::strong_count(&arc)
+    }
+
+    #[test]
+    fn wakes_through_the_vtable() {
+        let counter = Arc::new(Counter::default());
+        let woken = || counter.0.load(Ordering::SeqCst);
+        let waker = raw_waker(Waker::from(std::task::Waker::from(counter.clone())));
+        assert_eq!(wakers(&waker), 1);
+
+        let clone = waker.clone();
+        assert_eq!(wakers(&waker), 2);
+        clone.wake_by_ref();
+        assert_eq!((woken(), wakers(&waker)), (1, 2));
+        // Waking by value uses up the clone
+        clone.wake();
+        assert_eq!((woken(), wakers(&waker)), (2, 1));
+        drop(waker.clone());
+        assert_eq!(wakers(&waker), 1);
+
+        // Dropping the last one drops our `Waker` and the one it wraps
+        assert_eq!(Arc::strong_count(&counter), 2);
+        drop(waker);
+        assert_eq!(Arc::strong_count(&counter), 1);
+        assert_eq!(woken(), 2);
+    }
+
+    #[test]
+    fn gives_back_the_std_waker_it_wraps() {
+        let counter = Arc::new(Counter::default());
+        let waker = std::task::Waker::from(counter.clone());
+        let unwrapped = std_waker(&Waker::from(waker.clone()));
+        assert_eq!(unwrapped.data(), waker.data());
+        unwrapped.wake();
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn adapts_both_ways() {
+        let counter = Arc::new(Counter::default());
+        let waker = std::task::Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        // One of ours in an `async` block in one of ours in a `std` future
+        let inner = FromStd::new(async { IntoStd::new(pending_once(7)).await * 2 });
+        let mut future = std::pin::pin!(IntoStd::new(inner));
+        let mut poll = || std::future::Future::poll(future.as_mut(), &mut cx);
+        assert_eq!(poll(), Poll::Pending);
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        assert_eq!(poll(), Poll::Ready(14));
+    }
+
+    /// Isn't ready the first time it's polled, and wakes its waker right away
+    fn pending_once(value: usize) -> PendingOnce {
+        PendingOnce(Some(value), false)
+    }
+
+    struct PendingOnce(Option<usize>, bool);
+
+    impl Future for PendingOnce {
+        type Output = usize;
+
+        fn poll(&mut self, waker: &Waker) -> PollState<Self::Output> {
+            if std::mem::replace(&mut self.1, true) {
+                PollState::Ready(self.0.take().unwrap())
+            } else {
+                waker.wake();
+                PollState::NotReady
+            }
+        }
+    }
+}
@@ -0,0 +1,6 @@
+//! The `Future` trait, runtime and `Http::get` of
+//! `ch08/c-reactor-executor`, along with its adapters for
+//! `std::future::Future`
+pub mod future;
+pub mod http;
+pub mod runtime;
@@ -0,0 +1,337 @@
+// Not every program uses every combinator
+#![allow(dead_code)]
+
+use std::{
+    marker::PhantomData,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, RawWaker, RawWakerVTable},
+};
+
+// NEW
+use crate::ch08::runtime::Waker;
+// END NEW
+
+
+pub trait Future {
+    type Output;
+    ///////////////////////// NEW
+    fn poll(&mut self, waker: &Waker) -> PollState<Self::Output>;
+}
+
+pub enum PollState<T> {
+    Ready(T),
+    NotReady,
+}
+
+/// A future that resolves to `value` the first time it's polled
+pub fn ready<T>(value: T) -> Ready<T> {
+    Ready(Some(value))
+}
+
+pub struct Ready<T>(Option<T>);
+
+impl<T> Future for Ready<T> {
+    type Output = T;
+
+    fn poll(&mut self, _waker: &Waker) -> PollState<Self::Output> {
+        PollState::Ready(self.0.take().expect("`Ready` polled after it resolved"))
+    }
+}
+
+/// A future that never resolves
+pub fn pending<T>() -> Pending<T> {
+    Pending(PhantomData)
+}
+
+pub struct Pending<T>(PhantomData<T>);
+
+impl<T> Future for Pending<T> {
+    type Output = T;
+
+    fn poll(&mut self, _waker: &Waker) -> PollState<Self::Output> {
+        PollState::NotReady
+    }
+}
+
+/// A boxed future, like the ones in a `Vec` of different coroutines, is a
+/// future too
+impl<F: Future + ?Sized> Future for Box<F> {
+    type Output = F::Output;
+
+    fn poll(&mut self, waker: &Waker) -> PollState<Self::Output> {
+        (**self).poll(waker)
+    }
+}
+
+/// The combinators every future gets, like `request(i).map(|txt| txt.len())`
+pub trait FutureExt: Future + Sized {
+    /// Resolves to what `f` makes of the output
+    fn map<U, F: FnOnce(Self::Output) -> U>(self, f: F) -> Map<Self, F> {
+        Map { fut: self, f: Some(f) }
+    }
+
+    /// Resolves to what the future `f` makes of the output resolves to
+    fn then<B: Future, F: FnOnce(Self::Output) -> B>(self, f: F) -> Then<Self, B, F> {
+        Then(ThenState::First(self, Some(f)))
+    }
+
+    /// A `std::future::Future` that polls this one
+    fn into_std(self) -> IntoStd<Self> {
+        IntoStd::new(self)
+    }
+}
+
+impl<F: Future> FutureExt for F {}
+
+pub struct Map<Fut, F> {
+    fut: Fut,
+    f: Option<F>,
+}
+
+impl<U, Fut: Future, F: FnOnce(Fut::Output) -> U> Future for Map<Fut, F> {
+    type Output = U;
+
+    fn poll(&mut self, waker: &Waker) -> PollState<Self::Output> {
+        match self.fut.poll(waker) {
+            PollState::Ready(value) => {
+                let f = self.f.take().expect("`Map` polled after it resolved");
+                PollState::Ready(f(value))
+            }
+            PollState::NotReady => PollState::NotReady,
+        }
+    }
+}
+
+pub struct Then<A, B, F>(ThenState<A, B, F>);
+
+enum ThenState<A, B, F> {
+    First(A, Option<F>),
+    Second(B),
+}
+
+impl<A: Future, B: Future, F: FnOnce(A::Output) -> B> Future for Then<A, B, F> {
+    type Output = B::Output;
+
+    fn poll(&mut self, waker: &Waker) -> PollState<Self::Output> {
+        loop {
+            match &mut self.0 {
+                ThenState::First(a, f) => match a.poll(waker) {
+                    PollState::Ready(value) => {
+                        let f = f.take().expect("`Then` polled after it resolved");
+                        // The second future starts right away
+                        self.0 = ThenState::Second(f(value));
+                    }
+                    PollState::NotReady => return PollState::NotReady,
+                },
+                ThenState::Second(b) => return b.poll(waker),
+            }
+        }
+    }
+}
+
+/// A future we poll until it resolves, and keep the output of until the
+/// others are done too
+enum MaybeDone<F: Future> {
+    Pending(F),
+    Done(F::Output),
+    Taken,
+}
+
+impl<F: Future> MaybeDone<F> {
+    /// Polls the future if it's still pending, and returns if it's done
+    fn poll(&mut self, waker: &Waker) -> bool {
+        if let MaybeDone::Pending(fut) = self {
+            match fut.poll(waker) {
+                PollState::Ready(value) => *self = MaybeDone::Done(value),
+                PollState::NotReady => return false,
+            }
+        }
+        true
+    }
+
+    fn take(&mut self) -> F::Output {
+        match std::mem::replace(self, MaybeDone::Taken) {
+            MaybeDone::Done(value) => value,
+            _ => panic!("polled a join after it resolved"),
+        }
+    }
+}
+
+/// Polls `a` and `b` together and resolves to both outputs
+pub fn join<A: Future, B: Future>(a: A, b: B) -> Join<A, B> {
+    Join {
+        a: MaybeDone::Pending(a),
+        b: MaybeDone::Pending(b),
+    }
+}
+
+pub struct Join<A: Future, B: Future> {
+    a: MaybeDone<A>,
+    b: MaybeDone<B>,
+}
+
+impl<A: Future, B: Future> Future for Join<A, B> {
+    type Output = (A::Output, B::Output);
+
+    fn poll(&mut self, waker: &Waker) -> PollState<Self::Output> {
+        // Both get polled, even if the first isn't ready
+        let a = self.a.poll(waker);
+        let b = self.b.poll(waker);
+        if a && b {
+            PollState::Ready((self.a.take(), self.b.take()))
+        } else {
+            PollState::NotReady
+        }
+    }
+}
+
+/// One of two things, like which future of a `select` was first
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+/// Polls `a` and `b` together and resolves to the output of the first one
+/// that's ready, along with the other one, which can still be polled
+pub fn select<A: Future, B: Future>(a: A, b: B) -> Select<A, B> {
+    Select(Some((a, b)))
+}
+
+pub struct Select<A, B>(Option<(A, B)>);
+
+impl<A: Future, B: Future> Future for Select<A, B> {
+    type Output = Either<(A::Output, B), (B::Output, A)>;
+
+    fn poll(&mut self, waker: &Waker) -> PollState<Self::Output> {
+        let (a, b) = self.0.as_mut().expect("`Select` polled after it resolved");
+        if let PollState::Ready(value) = a.poll(waker) {
+            let (_, b) = self.0.take().unwrap();
+            return PollState::Ready(Either::Left((value, b)));
+        }
+        if let PollState::Ready(value) = b.poll(waker) {
+            let (a, _) = self.0.take().unwrap();
+            return PollState::Ready(Either::Right((value, a)));
+        }
+        PollState::NotReady
+    }
+}
+
+/// Polls all the futures together and resolves to their outputs, in the
+/// order they were in
+pub fn join_all<F: Future>(futures: Vec<F>) -> JoinAll<F> {
+    let futures = futures.into_iter().map(MaybeDone::Pending).collect();
+    JoinAll { futures }
+}
+
+pub struct JoinAll<F: Future> {
+    futures: Vec<MaybeDone<F>>,
+}
+
+impl<F: Future> Future for JoinAll<F> {
+    type Output = Vec<F::Output>;
+
+    fn poll(&mut self, waker: &Waker) -> PollState<Self::Output> {
+        let mut finished = true;
+        for fut in self.futures.iter_mut() {
+            finished &= fut.poll(waker);
+        }
+
+        if finished {
+            PollState::Ready(self.futures.iter_mut().map(MaybeDone::take).collect())
+        } else {
+            PollState::NotReady
+        }
+    }
+}
+
+/// Lets a future of ours run on an executor for `std::future::Future`, or be
+/// awaited in an `async fn`. The `std::task::Waker` it gets goes to the
+/// future wrapped in our `Waker`, so the reactor can wake it.
+pub struct IntoStd<F>(F);
+
+// Our futures don't need to stay where they are while they're polled, so
+// pinning an `IntoStd` doesn't pin the future in it
+impl<F> Unpin for IntoStd<F> {}
+
+impl<F: Future> IntoStd<F> {
+    pub fn new(future: F) -> Self {
+        IntoStd(future)
+    }
+}
+
+impl<F: Future> std::future::Future for IntoStd<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let waker = Waker::from(cx.waker().clone());
+        match self.get_mut().0.poll(&waker) {
+            PollState::Ready(value) => Poll::Ready(value),
+            PollState::NotReady => Poll::Pending,
+        }
+    }
+}
+
+/// Lets a `std::future::Future`, like an `async` block, run on our executor,
+/// or be waited on in a `coroutine`. The future gets a `std::task::Waker`
+/// that wakes our `Waker`.
+pub struct FromStd<F>(Pin<Box<F>>);
+
+impl<F: std::future::Future> FromStd<F> {
+    pub fn new(future: F) -> Self {
+        FromStd(Box::pin(future))
+    }
+}
+
+impl<F: std::future::Future> Future for FromStd<F> {
+    type Output = F::Output;
+
+    fn poll(&mut self, waker: &Waker) -> PollState<Self::Output> {
+        let waker = std_waker(waker);
+        let mut cx = Context::from_waker(&waker);
+        match self.0.as_mut().poll(&mut cx) {
+            Poll::Ready(value) => PollState::Ready(value),
+            Poll::Pending => PollState::NotReady,
+        }
+    }
+}
+
+/// A `std::task::Waker` that wakes `waker`. One that wraps a
+/// `std::task::Waker` already gives us that back.
+fn std_waker(waker: &Waker) -> std::task::Waker {
+    match waker.as_std() {
+        Some(waker) => waker.clone(),
+        None => raw_waker(waker.clone()),
+    }
+}
+
+/// A `std::task::Waker` that owns `waker` and calls it through `VTABLE`
+fn raw_waker(waker: Waker) -> std::task::Waker {
+    let data = Arc::into_raw(Arc::new(waker)) as *const ();
+    // SAFETY: `data` is an `Arc<Waker>` we own a count of, which is what the
+    // functions in `VTABLE` expect. `Waker` is `Send` and `Sync`.
+    unsafe { std::task::Waker::from_raw(RawWaker::new(data, &VTABLE)) }
+}
+
+// The `RawWaker` functions for a `std::task::Waker` made from our `Waker`.
+// Their `data` is a pointer we got from `Arc::into_raw(Arc<Waker>)`.
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone_raw, wake_raw, wake_by_ref_raw, drop_raw);
+
+unsafe fn clone_raw(data: *const ()) -> RawWaker {
+    Arc::increment_strong_count(data as *const Waker);
+    RawWaker::new(data, &VTABLE)
+}
+
+unsafe fn wake_raw(data: *const ()) {
+    let waker = Arc::from_raw(data as *const Waker);
+    waker.wake();
+}
+
+unsafe fn wake_by_ref_raw(data: *const ()) {
+    (*(data as *const Waker)).wake();
+}
+
+unsafe fn drop_raw(data: *const ()) {
+    std::mem::drop(Arc::from_raw(data as *const Waker));
+}
+
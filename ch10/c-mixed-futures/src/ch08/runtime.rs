@@ -0,0 +1,10 @@
+pub use executor::{Executor, Waker};
+pub use reactor::reactor;
+
+mod executor;
+mod reactor;
+
+pub fn init() -> Executor {
+    reactor::start();
+    Executor::new()
+}
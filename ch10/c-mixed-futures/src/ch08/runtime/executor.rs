@@ -0,0 +1,143 @@
+use crate::ch08::future::{Future, PollState};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread::{self, Thread},
+};
+
+type Task = Box<dyn Future<Output = String>>;
+
+thread_local! {
+    static CURRENT_EXEC: ExecutorCore = ExecutorCore::default();
+}
+
+#[derive(Default)]
+struct ExecutorCore {
+    tasks: RefCell<HashMap<usize, Task>>,
+    ready_queue: Arc<Mutex<Vec<usize>>>,
+    next_id: Cell<usize>,
+}
+
+pub fn spawn<F>(future: F)
+where
+    F: Future<Output = String> + 'static,
+{
+    CURRENT_EXEC.with(|e| {
+        let id = e.next_id.get();
+        e.tasks.borrow_mut().insert(id, Box::new(future));
+        e.ready_queue.lock().map(|mut q| q.push(id)).unwrap();
+        e.next_id.set(id + 1);
+    });
+}
+
+pub struct Executor;
+
+impl Executor {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    fn pop_ready(&self) -> Option<usize> {
+        CURRENT_EXEC.with(|q| q.ready_queue.lock().map(|mut q| q.pop()).unwrap())
+    }
+
+    fn get_future(&self, id: usize) -> Option<Task> {
+        CURRENT_EXEC.with(|q| q.tasks.borrow_mut().remove(&id))
+    }
+
+    fn get_waker(&self, id: usize) -> Waker {
+        Waker(Wake::Task {
+            id,
+            thread: thread::current(),
+            ready_queue: CURRENT_EXEC.with(|q| q.ready_queue.clone()),
+        })
+    }
+
+    fn insert_task(&self, id: usize, task: Task) {
+        CURRENT_EXEC.with(|q| q.tasks.borrow_mut().insert(id, task));
+    }
+
+    fn task_count(&self) -> usize {
+        CURRENT_EXEC.with(|q| q.tasks.borrow().len())
+    }
+
+    pub fn block_on<F>(&mut self, future: F)
+    where
+        F: Future<Output = String> + 'static,
+    {
+        spawn(future);
+        loop {
+            while let Some(id) = self.pop_ready() {
+                let mut future = match self.get_future(id) {
+                    Some(f) => f,
+                    // guard against false wakeups
+                    None => continue,
+                };
+                let waker = self.get_waker(id);
+
+                match future.poll(&waker) {
+                    PollState::NotReady => self.insert_task(id, future),
+                    PollState::Ready(_) => continue,
+                }
+            }
+
+            let task_count = self.task_count();
+            let name = thread::current().name().unwrap_or_default().to_string();
+
+            if task_count > 0 {
+                println!("{name}: {task_count} pending tasks. Sleep until notified.");
+                thread::park();
+            } else {
+                println!("{name}: All tasks are finished");
+                break;
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Waker(Wake);
+
+#[derive(Clone)]
+enum Wake {
+    /// Puts a task of our executor back in its ready queue
+    Task {
+        thread: Thread,
+        id: usize,
+        ready_queue: Arc<Mutex<Vec<usize>>>,
+    },
+    /// Wakes a future of ours that runs on an executor for
+    /// `std::future::Future`, see `future::IntoStd`
+    Std(std::task::Waker),
+}
+
+impl Waker {
+    pub fn wake(&self) {
+        match &self.0 {
+            Wake::Task {
+                thread,
+                id,
+                ready_queue,
+            } => {
+                ready_queue.lock().map(|mut q| q.push(*id)).unwrap();
+                thread.unpark();
+            }
+            Wake::Std(waker) => waker.wake_by_ref(),
+        }
+    }
+
+    /// The `std::task::Waker` this one wraps, if it wraps one
+    pub fn as_std(&self) -> Option<&std::task::Waker> {
+        match &self.0 {
+            Wake::Std(waker) => Some(waker),
+            Wake::Task { .. } => None,
+        }
+    }
+}
+
+impl From<std::task::Waker> for Waker {
+    fn from(waker: std::task::Waker) -> Self {
+        Waker(Wake::Std(waker))
+    }
+}
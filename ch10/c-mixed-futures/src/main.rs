@@ -0,0 +1,29 @@
+mod ch08;
+mod http;
+mod runtime;
+use crate::ch08::future::{FromStd, FutureExt};
+use crate::http::Http;
+
+fn main() {
+    // Each runtime has a reactor of its own, and both have to run for
+    // their leaf futures to be woken
+    let mut executor = runtime::init();
+    let mut ch08_executor = ch08::runtime::init();
+
+    // Our executor runs an `async fn` that awaits a future from chapter 8
+    executor.block_on(async_main());
+
+    // and the executor from chapter 8 runs the same `async fn`
+    ch08_executor.block_on(FromStd::new(async {
+        async_main().await;
+        String::new()
+    }));
+}
+
+async fn async_main() {
+    println!("Program starting");
+    let txt = ch08::http::Http::get("/600/HelloChapter8").into_std().await;
+    println!("{txt}");
+    let txt = Http::get("/400/HelloChapter10").await;
+    println!("{txt}");
+}
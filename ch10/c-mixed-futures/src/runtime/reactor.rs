@@ -0,0 +1,82 @@
+use mio::{net::TcpStream, Events, Interest, Poll, Registry, Token};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    thread, task::{Context, Waker},
+};
+
+
+type Wakers = Arc<Mutex<HashMap<usize, Waker>>>;
+
+static REACTOR: OnceLock<Reactor> = OnceLock::new();
+
+pub fn reactor() -> &'static Reactor {
+    REACTOR.get().expect("Called outside an runtime context")
+}
+
+pub struct Reactor {
+    wakers: Wakers,
+    registry: Registry,
+    next_id: AtomicUsize,
+}
+
+impl Reactor {
+    pub fn register(&self, stream: &mut TcpStream, interest: Interest, id: usize) {
+        self.registry.register(stream, Token(id), interest).unwrap();
+    }
+
+    pub fn set_waker(&self, cx: &Context, id: usize) {
+        let _ = self
+            .wakers
+            .lock()
+            .map(|mut w| w.insert(id, cx.waker().clone()).is_none())
+            .unwrap();
+    }
+
+    pub fn deregister(&self, stream: &mut TcpStream, id: usize) {
+        self.wakers.lock().map(|mut w| w.remove(&id)).unwrap();
+        self.registry.deregister(stream).unwrap();
+    }
+
+    pub fn next_id(&self) -> usize {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+fn event_loop(mut poll: Poll, wakers: Wakers) {
+    let mut events = Events::with_capacity(100);
+    loop {
+        poll.poll(&mut events, None).unwrap();
+        for e in events.iter() {
+            // Optimization for Windows since we get unneeded wakeups
+            // if !e.is_readable() && e.is_read_closed() {
+            //     continue;
+            // }
+            let Token(id) = e.token();
+            let wakers = wakers.lock().unwrap();
+
+            if let Some(waker) = wakers.get(&id) {
+                waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+pub fn start() {
+    use thread::spawn;
+    let wakers = Arc::new(Mutex::new(HashMap::new()));
+    let poll = Poll::new().unwrap();
+    let registry = poll.registry().try_clone().unwrap();
+    let next_id = AtomicUsize::new(1);
+    let reactor = Reactor {
+        wakers: wakers.clone(),
+        registry,
+        next_id,
+    };
+
+    REACTOR.set(reactor).ok().expect("Reactor already running");
+    spawn(move || event_loop(poll, wakers));
+}
@@ -0,0 +1,77 @@
+//! A global allocator that keeps track of how many bytes are allocated so
+//! we can measure how much memory each task uses.
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    sync::atomic::{AtomicIsize, Ordering},
+};
+
+pub struct Counting;
+
+static LIVE: AtomicIsize = AtomicIsize::new(0);
+static PEAK: AtomicIsize = AtomicIsize::new(0);
+
+thread_local! {
+    // The stand-in server runs in the same process, but we don't want to
+    // count its allocations.
+    static UNTRACKED: Cell<bool> = const { Cell::new(false) };
+}
+
+fn tracked() -> bool {
+    UNTRACKED.try_with(|u| !u.get()).unwrap_or(false)
+}
+
+fn add(bytes: isize) {
+    if tracked() {
+        let live = LIVE.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        PEAK.fetch_max(live, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            add(layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            add(layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        add(-(layout.size() as isize));
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            add(new_size as isize - layout.size() as isize);
+        }
+        new
+    }
+}
+
+/// Stops counting allocations made on the current thread.
+pub fn untrack_current_thread() {
+    UNTRACKED.with(|u| u.set(true));
+}
+
+/// Resets the high-water mark and returns the number of bytes allocated
+/// right now, so we can compare it with `peak` later.
+pub fn reset_peak() -> usize {
+    let live = LIVE.load(Ordering::Relaxed);
+    PEAK.store(live, Ordering::Relaxed);
+    live.max(0) as usize
+}
+
+pub fn peak() -> usize {
+    PEAK.load(Ordering::Relaxed).max(0) as usize
+}
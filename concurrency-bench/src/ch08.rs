@@ -0,0 +1,72 @@
+//! The reactor/executor from `ch08/c-reactor-executor` using our own
+//! `Future` trait. The runtime is copied as is, apart from the places
+//! marked with `changed`.
+use std::time::{Duration, Instant};
+
+use crate::{alloc, Requests};
+use future::{Future, PollState};
+use http::Http;
+use runtime::Waker;
+
+mod future;
+mod http;
+mod runtime;
+
+/// `block_on` needs a future to run. We spawn all the tasks ourselves so
+/// this one is just ready right away.
+struct Done;
+
+impl Future for Done {
+    type Output = String;
+
+    fn poll(&mut self, _waker: &Waker) -> PollState<Self::Output> {
+        PollState::Ready(String::new())
+    }
+}
+
+/// Wakes itself and returns `NotReady` until it has been polled `remaining` times.
+struct Yielder {
+    remaining: usize,
+}
+
+impl Future for Yielder {
+    type Output = String;
+
+    fn poll(&mut self, waker: &Waker) -> PollState<Self::Output> {
+        if self.remaining == 0 {
+            return PollState::Ready(String::new());
+        }
+        self.remaining -= 1;
+        waker.wake();
+        PollState::NotReady
+    }
+}
+
+pub fn requests(tasks: usize, delay: u64) -> Requests {
+    let mut executor = runtime::init();
+    let base = alloc::reset_peak();
+    let start = Instant::now();
+
+    for i in 0..tasks {
+        runtime::spawn(Http::get(&format!("/{delay}/ch08-{i}")));
+    }
+    let spawn = start.elapsed();
+
+    executor.block_on(Done);
+
+    Requests {
+        spawn,
+        elapsed: start.elapsed(),
+        memory: alloc::peak() - base,
+    }
+}
+
+pub fn ping_pong(rounds: usize) -> Duration {
+    let mut executor = runtime::init();
+    runtime::spawn(Yielder { remaining: rounds });
+    runtime::spawn(Yielder { remaining: rounds });
+
+    let start = Instant::now();
+    executor.block_on(Done);
+    start.elapsed() / (rounds as u32 * 2)
+}
@@ -0,0 +1,71 @@
+//! The runtime from `ch10/a-rust-futures` that uses `std::future::Future`
+//! and real `async/await`. The runtime is copied as is, apart from the
+//! places marked with `changed`.
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use crate::{alloc, Requests};
+use http::Http;
+
+mod http;
+mod runtime;
+
+/// Wakes itself and returns `Pending` the first time it's polled.
+struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+async fn yielder(rounds: usize) {
+    for _ in 0..rounds {
+        YieldNow { yielded: false }.await;
+    }
+}
+
+pub fn requests(tasks: usize, delay: u64) -> Requests {
+    let mut executor = runtime::init();
+    let base = alloc::reset_peak();
+    let start = Instant::now();
+
+    for i in 0..tasks {
+        let path = format!("/{delay}/ch10-{i}");
+        runtime::spawn(async move {
+            Http::get(&path).await;
+        });
+    }
+    let spawn = start.elapsed();
+
+    executor.block_on(async {});
+
+    Requests {
+        spawn,
+        elapsed: start.elapsed(),
+        memory: alloc::peak() - base,
+    }
+}
+
+pub fn ping_pong(rounds: usize) -> Duration {
+    let mut executor = runtime::init();
+    runtime::spawn(yielder(rounds));
+    runtime::spawn(yielder(rounds));
+
+    let start = Instant::now();
+    executor.block_on(async {});
+    start.elapsed() / (rounds as u32 * 2)
+}
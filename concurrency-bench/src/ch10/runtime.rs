@@ -0,0 +1,10 @@
+pub use executor::{spawn, Executor};
+pub use reactor::reactor;
+
+mod executor;
+mod reactor;
+
+pub fn init() -> Executor {
+    reactor::start();
+    Executor::new()
+}
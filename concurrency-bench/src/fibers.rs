@@ -0,0 +1,72 @@
+//! The fibers from `ch05/f-fibers-runtime`. Our fibers don't have a reactor,
+//! so a fiber waiting for a response simply yields until the socket has
+//! data. That means the runtime never sleeps while requests are in flight.
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+use f_fibers_runtime::{yield_thread, FifoScheduler, Runtime};
+
+use crate::{alloc, get_req, server, Requests, STACK_SIZE};
+
+fn get(path: &str) -> String {
+    let mut stream = TcpStream::connect(server::addr()).unwrap();
+    stream.write_all(get_req(path).as_bytes()).unwrap();
+    stream.set_nonblocking(true).unwrap();
+
+    let mut response = vec![];
+    let mut buf = [0u8; 1024];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => response.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => yield_thread(),
+            Err(e) => panic!("{e:?}"),
+        }
+    }
+    String::from_utf8_lossy(&response).to_string()
+}
+
+pub fn requests(tasks: usize, delay: u64) -> Requests {
+    let base = alloc::reset_peak();
+    // The stacks are allocated up front. We count them as memory, but
+    // not as part of the time it takes to spawn.
+    let mut runtime = Runtime::with_config(FifoScheduler::default(), tasks + 1, STACK_SIZE);
+    runtime.init();
+    let start = Instant::now();
+
+    for i in 0..tasks {
+        let path = format!("/{delay}/fiber{i}");
+        Runtime::spawn(move || {
+            get(&path);
+        });
+    }
+    let spawn = start.elapsed();
+
+    runtime.run();
+
+    Requests {
+        spawn,
+        elapsed: start.elapsed(),
+        memory: alloc::peak() - base,
+    }
+}
+
+/// Two fibers that do nothing but yield to each other.
+pub fn ping_pong(rounds: usize) -> Duration {
+    let mut runtime = Runtime::with_config(FifoScheduler::default(), 3, STACK_SIZE);
+    runtime.init();
+    for _ in 0..2 {
+        Runtime::spawn(move || {
+            for _ in 0..rounds {
+                yield_thread();
+            }
+        });
+    }
+
+    let start = Instant::now();
+    runtime.run();
+    start.elapsed() / (rounds as u32 * 2)
+}
@@ -0,0 +1,84 @@
+use std::{env, time::Duration};
+
+mod alloc;
+mod ch08;
+mod ch10;
+mod fibers;
+mod server;
+mod threads;
+
+#[global_allocator]
+static ALLOCATOR: alloc::Counting = alloc::Counting;
+
+/// The stack size we give both OS threads and fibers
+const STACK_SIZE: usize = 64 * 1024;
+
+/// The result of running the requests workload on one of the models.
+pub struct Requests {
+    /// Time it took to spawn all the tasks
+    spawn: Duration,
+    /// Time from we started spawning until all tasks were finished
+    elapsed: Duration,
+    /// Peak number of bytes allocated while the tasks were running
+    memory: usize,
+}
+
+fn get_req(path: &str) -> String {
+    format!(
+        "GET {path} HTTP/1.1\r\n\
+             Host: localhost\r\n\
+             Connection: close\r\n\
+             \r\n"
+    )
+}
+
+type Model = (
+    &'static str,
+    fn(usize, u64) -> Requests,
+    fn(usize) -> Duration,
+);
+
+const MODELS: [Model; 4] = [
+    ("OS threads", threads::requests, threads::ping_pong),
+    ("fibers", fibers::requests, fibers::ping_pong),
+    ("ch08 futures", ch08::requests, ch08::ping_pong),
+    ("ch10 futures", ch10::requests, ch10::ping_pong),
+];
+
+fn arg(n: usize, default: usize) -> usize {
+    env::args()
+        .nth(n)
+        .and_then(|a| a.parse().ok())
+        .unwrap_or(default)
+}
+
+fn main() {
+    let tasks = arg(1, 500);
+    let delay = arg(2, 200) as u64;
+    let rounds = arg(3, 100_000);
+
+    server::start();
+
+    println!("\n{tasks} concurrent requests with a {delay} ms delay\n");
+    println!(
+        "{:<14} {:>12} {:>12} {:>12} {:>14}",
+        "MODEL", "SPAWN/TASK", "MEM/TASK", "TOTAL", "THROUGHPUT"
+    );
+    for (name, requests, _) in MODELS {
+        let r = requests(tasks, delay);
+        println!(
+            "{:<14} {:>12} {:>12} {:>12} {:>14}",
+            name,
+            format!("{:.2?}", r.spawn / tasks as u32),
+            format!("{:.1} KiB", r.memory as f64 / tasks as f64 / 1024.0),
+            format!("{:.2?}", r.elapsed),
+            format!("{:.0} req/s", tasks as f64 / r.elapsed.as_secs_f64()),
+        );
+    }
+
+    println!("\nPing-pong between two tasks, {rounds} rounds\n");
+    println!("{:<14} {:>12}", "MODEL", "SWITCH/POLL");
+    for (name, _, ping_pong) in MODELS {
+        println!("{:<14} {:>12}", name, format!("{:.2?}", ping_pong(rounds)));
+    }
+}
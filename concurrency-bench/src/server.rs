@@ -0,0 +1,140 @@
+//! An in-process stand-in for `delayserver`. It answers
+//! `GET /[delay in ms]/[message]` by waiting for the given delay and echoing
+//! the message back, just like the real one, but it runs on a single thread
+//! using mio so it doesn't add threads of its own to the measurements.
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    io::{ErrorKind, Read, Write},
+    net::SocketAddr,
+    sync::OnceLock,
+    thread,
+    time::{Duration, Instant},
+};
+
+use mio::{
+    net::{TcpListener, TcpStream},
+    Events, Interest, Poll, Token,
+};
+
+use crate::alloc;
+
+static ADDR: OnceLock<SocketAddr> = OnceLock::new();
+
+const LISTENER: Token = Token(0);
+
+/// Starts the server the first time it's called and returns its address.
+pub fn start() -> SocketAddr {
+    *ADDR.get_or_init(|| {
+        let mut listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let poll = Poll::new().unwrap();
+        poll.registry()
+            .register(&mut listener, LISTENER, Interest::READABLE)
+            .unwrap();
+        thread::spawn(move || {
+            alloc::untrack_current_thread();
+            serve(poll, listener);
+        });
+        addr
+    })
+}
+
+pub fn addr() -> SocketAddr {
+    *ADDR.get().expect("server not started")
+}
+
+struct Connection {
+    stream: TcpStream,
+    request: Vec<u8>,
+}
+
+fn serve(mut poll: Poll, listener: TcpListener) {
+    let mut events = Events::with_capacity(1024);
+    let mut connections: HashMap<usize, Connection> = HashMap::new();
+    let mut waiting: HashMap<usize, (TcpStream, String)> = HashMap::new();
+    let mut timers: BinaryHeap<Reverse<(Instant, usize)>> = BinaryHeap::new();
+    let mut next_id = 1;
+
+    loop {
+        let timeout = timers
+            .peek()
+            .map(|Reverse((at, _))| at.saturating_duration_since(Instant::now()));
+        poll.poll(&mut events, timeout).unwrap();
+
+        for event in events.iter() {
+            if event.token() == LISTENER {
+                loop {
+                    match listener.accept() {
+                        Ok((mut stream, _)) => {
+                            let id = next_id;
+                            next_id += 1;
+                            poll.registry()
+                                .register(&mut stream, Token(id), Interest::READABLE)
+                                .unwrap();
+                            let request = vec![];
+                            connections.insert(id, Connection { stream, request });
+                        }
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                        Err(e) => panic!("{e:?}"),
+                    }
+                }
+                continue;
+            }
+
+            let Token(id) = event.token();
+            let Some(conn) = connections.get_mut(&id) else {
+                continue;
+            };
+
+            let mut buf = [0u8; 1024];
+            loop {
+                match conn.stream.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => conn.request.extend_from_slice(&buf[..n]),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => panic!("{e:?}"),
+                }
+            }
+
+            if !conn.request.windows(4).any(|w| w == b"\r\n\r\n") {
+                continue;
+            }
+
+            let mut conn = connections.remove(&id).unwrap();
+            poll.registry().deregister(&mut conn.stream).unwrap();
+            let (delay, message) = parse(&conn.request);
+            timers.push(Reverse((Instant::now() + delay, id)));
+            waiting.insert(id, (conn.stream, message));
+        }
+
+        while let Some(Reverse((at, id))) = timers.peek().copied() {
+            if at > Instant::now() {
+                break;
+            }
+            timers.pop();
+            let (mut stream, message) = waiting.remove(&id).unwrap();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\n\
+                 content-length: {}\r\n\
+                 connection: close\r\n\
+                 content-type: text/plain; charset=utf-8\r\n\
+                 \r\n\
+                 {message}",
+                message.len()
+            );
+            // The response is small enough to fit in the socket's send buffer
+            let _ = stream.write_all(response.as_bytes());
+        }
+    }
+}
+
+/// Gets the delay and message from `GET /[delay]/[message] HTTP/1.1`
+fn parse(request: &[u8]) -> (Duration, String) {
+    let request = String::from_utf8_lossy(request);
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+    let mut parts = path.trim_start_matches('/').splitn(2, '/');
+    let delay = parts.next().and_then(|d| d.parse().ok()).unwrap_or(0);
+    let message = parts.next().unwrap_or_default().to_string();
+    (Duration::from_millis(delay), message)
+}
@@ -0,0 +1,69 @@
+//! OS threads, like in `ch02/a-os-threads`. Every task gets its own thread
+//! and simply blocks while it waits for the response.
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    sync::mpsc::sync_channel,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{alloc, get_req, server, Requests, STACK_SIZE};
+
+fn get(path: &str) -> String {
+    let mut stream = TcpStream::connect(server::addr()).unwrap();
+    stream.write_all(get_req(path).as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+pub fn requests(tasks: usize, delay: u64) -> Requests {
+    let mut handles = Vec::with_capacity(tasks);
+    let base = alloc::reset_peak();
+    let start = Instant::now();
+
+    for i in 0..tasks {
+        let path = format!("/{delay}/thread{i}");
+        let handle = thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn(move || get(&path))
+            .unwrap();
+        handles.push(handle);
+    }
+    let spawn = start.elapsed();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Requests {
+        spawn,
+        elapsed: start.elapsed(),
+        // The stack is mapped by the OS and never shows up as an allocation
+        memory: alloc::peak() - base + tasks * STACK_SIZE,
+    }
+}
+
+/// Two threads passing the baton back and forth through a pair of
+/// rendezvous channels. Every hand-off is a switch between two OS threads.
+pub fn ping_pong(rounds: usize) -> Duration {
+    let (to_pong, from_ping) = sync_channel::<()>(0);
+    let (to_ping, from_pong) = sync_channel::<()>(0);
+
+    let pong = thread::spawn(move || {
+        for _ in 0..rounds {
+            from_ping.recv().unwrap();
+            to_ping.send(()).unwrap();
+        }
+    });
+
+    let start = Instant::now();
+    for _ in 0..rounds {
+        to_pong.send(()).unwrap();
+        from_pong.recv().unwrap();
+    }
+    let elapsed = start.elapsed();
+    pong.join().unwrap();
+    elapsed / (rounds as u32 * 2)
+}